@@ -0,0 +1,173 @@
+use std::{fmt, net::SocketAddr};
+
+use rusqlite::{params, types::{ToSql, ToSqlOutput}};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio_rusqlite::Connection;
+
+use crate::{auth::Caller, user::User};
+
+/// What kind of identity performed an audited action
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActorKind {
+    /// Someone without a session yet, like a failed login
+    Anonymous,
+    /// A logged in user
+    User,
+    /// An API token
+    Token,
+    /// An API token owned by a service account
+    Service,
+}
+
+impl ActorKind {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Anonymous => "anonymous",
+            Self::User => "user",
+            Self::Token => "token",
+            Self::Service => "service",
+        }
+    }
+}
+
+impl fmt::Display for ActorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl ToSql for ActorKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+/// A security relevant action to record in the audit table.
+/// Built up with the helper methods and then saved with `record`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    /// dotted action name like `login.success` or `user.create`
+    pub action: String,
+    pub actor_kind: ActorKind,
+    pub actor_id: Option<i64>,
+    pub actor_name: Option<String>,
+    /// what the action was done to, like a user id or service name
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub details: Value,
+}
+
+impl AuditEvent {
+    pub fn new(action: &str) -> Self {
+        Self {
+            action: action.to_string(),
+            actor_kind: ActorKind::Anonymous,
+            actor_id: None,
+            actor_name: None,
+            target: None,
+            ip: None,
+            details: Value::Null,
+        }
+    }
+
+    /// Action done by a logged in user
+    pub fn user(mut self, user: &User) -> Self {
+        self.actor_kind = ActorKind::User;
+        self.actor_id = Some(user.id);
+        self.actor_name = Some(user.name.clone());
+        self
+    }
+
+    /// Action done with an API token. The actor id is the token and the name its owner.
+    /// Tokens of service accounts are recorded as `service` so automation stands out from people.
+    pub fn caller(mut self, caller: &Caller) -> Self {
+        self.actor_kind = if caller.service_account { ActorKind::Service } else { ActorKind::Token };
+        self.actor_id = Some(caller.token_id);
+        self.actor_name = Some(caller.user_name.clone());
+        self
+    }
+
+    /// Action done by someone who isn't logged in, identified by the name they gave
+    pub fn anonymous(mut self, name: &str) -> Self {
+        self.actor_kind = ActorKind::Anonymous;
+        self.actor_id = None;
+        self.actor_name = Some(name.to_string());
+        self
+    }
+
+    /// Who did the action as `<kind>:<name>`, like `user:alice` or `service:ci`.
+    /// The status history of services names its sources the same way.
+    pub fn actor(&self) -> String {
+        format!("{}:{}", self.actor_kind, self.actor_name.as_deref().unwrap_or_default())
+    }
+
+    pub fn target(mut self, target: impl ToString) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn ip(mut self, addr: &SocketAddr) -> Self {
+        self.ip = Some(addr.ip().to_string());
+        self
+    }
+
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+/// Save an audit event. Failing to record shouldn't fail the request so errors are only logged.
+pub async fn record(conn: &Connection, event: AuditEvent) {
+    tracing::info!(
+        "audit: {} by {} {:?} ({:?}) on {:?} from {:?}",
+        event.action, event.actor_kind, event.actor_name, event.actor_id, event.target, event.ip
+    );
+    let query = conn
+        .call(move |conn| {
+            // details are stored as a json string, NULL if there are none
+            let details = (!event.details.is_null()).then(|| event.details.to_string());
+            conn.execute(
+                "INSERT INTO audit (action, actor_kind, actor_id, actor_name, target, ip, details) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![event.action, event.actor_kind, event.actor_id, event.actor_name, event.target, event.ip, details],
+            )
+        })
+        .await;
+    if let Err(err) = query {
+        tracing::error!("Audit insert db err: {:?}", err);
+    }
+}
+
+/// Audit event as stored in the db
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub ts: i64,
+    pub action: String,
+    pub actor_kind: String,
+    pub actor_id: Option<i64>,
+    pub actor_name: Option<String>,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub details: Option<Value>,
+}
+
+impl AuditEntry {
+    /// Map a row from `SELECT * FROM audit`
+    pub fn map(row: &rusqlite::Row<'_>) -> Result<Self, rusqlite::Error> {
+        let details: Option<String> = row.get("details")?;
+        Ok(Self {
+            id: row.get("id")?,
+            ts: row.get("ts")?,
+            action: row.get("action")?,
+            actor_kind: row.get("actor_kind")?,
+            actor_id: row.get("actor_id")?,
+            actor_name: row.get("actor_name")?,
+            target: row.get("target")?,
+            ip: row.get("ip")?,
+            details: details.and_then(|details| serde_json::from_str(&details).ok()),
+        })
+    }
+}
@@ -1,118 +1,97 @@
-use async_trait::async_trait;
 use axum::{
-    extract::State,
+    extract::{ConnectInfo, State},
     http::{self, Request, StatusCode},
     middleware::Next,
     response::Response,
     Json,
 };
 use axum_login::{
-    axum_sessions::async_session::{SessionStore, Session, self}, 
+    axum_sessions::SessionHandle,
     RusqliteStore
 };
 use rusqlite::params;
 use serde::{Deserialize, Serialize};
 use tokio_rusqlite::Connection;
-use tracing::log::info;
 use anyhow::Result;
+use std::net::SocketAddr;
 
-use crate::user::{User, UserMapper};
+use crate::{
+    audit::{self, AuditEvent},
+    token::{self, Scope},
+    user::{Role, User, UserMapper},
+};
 
 /// AuthContect extractor used with axum routes
-pub type AuthContext = axum_login::extractors::AuthContext<i64, User, RusqliteStore<User, UserMapper >>;
-
-/// Store session information in Sqlite db using rusqlite
-#[derive(Debug, Clone)]
-pub struct SqliteSessionStore {
-    conn: Connection,
-}
-
-impl SqliteSessionStore {
-    /// Create a new session store from a tokio_rusqilte connection
-    pub fn new(conn: Connection) -> Self {
-        Self { conn }
+pub type AuthContext = axum_login::extractors::AuthContext<i64, User, RusqliteStore<User, UserMapper, Role>, Role>;
+
+/// Session key `axum_login` uses to store the id of the logged in user
+pub const SESSION_USER_ID_KEY: &str = "_user_id";
+/// Session key for the IP the session was last used from
+pub const SESSION_IP_KEY: &str = "ip";
+/// Session key for the user agent the session was last used with
+pub const SESSION_USER_AGENT_KEY: &str = "user_agent";
+
+/// Middleware that records the client IP and user agent in the session
+/// so users can recognize their sessions when they list them.
+/// Only sessions with a logged in user are touched so anonymous requests don't store a session.
+pub async fn track_session<B>(req: Request<B>, next: Next<B>) -> Response {
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let user_agent = req
+        .headers()
+        .get(http::header::USER_AGENT)
+        .and_then(|header| header.to_str().ok())
+        .map(str::to_string);
+
+    let handle = req.extensions().get::<SessionHandle>().cloned();
+    // Run the handler first so the request that logs a user in is tracked too
+    let response = next.run(req).await;
+
+    if let Some(handle) = handle {
+        let mut session = handle.write().await;
+        // Only touch the session when there is a user and something changed
+        if session.get_raw(SESSION_USER_ID_KEY).is_some() {
+            if ip.is_some() && session.get::<String>(SESSION_IP_KEY) != ip {
+                session.insert(SESSION_IP_KEY, ip).ok();
+            }
+            if user_agent.is_some() && session.get::<String>(SESSION_USER_AGENT_KEY) != user_agent {
+                session.insert(SESSION_USER_AGENT_KEY, user_agent).ok();
+            }
+        }
     }
+    response
 }
 
-#[async_trait]
-impl SessionStore for SqliteSessionStore{
-    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
-        // Get the session id
-        let id = Session::id_from_cookie_value(&cookie_value)?;
-        info!("loading session id: {}", id);
-        // Get session from database
-        let session = self.conn
-            .call(move |conn| {
-                // Sql query
-                let mut stmt = conn.prepare("SELECT session FROM sessions WHERE id = :id")?;
-                // submit the query and get all the sessions
-                let sessions = stmt
-                    .query_map(&[(":id", &id)], |row| {
-                        // sessions are stored as message pack binaries so we get the bin type
-                        let data: Vec<u8> = row.get(0)?;
-                        // use serde to convert the binary back to a valid Session
-                        Ok(rmp_serde::from_slice(&data).unwrap())
-                    })?
-                    .collect::<std::result::Result<Vec<Session>, rusqlite::Error>>()?;
-                Ok::<_, rusqlite::Error>(sessions)
-            })
-            .await?;
-        
-        if session.len() != 0 {
-            // If more than 0 then return the session (Should only be 1 valid)
-            tracing::debug!("Session loaded: {:?}", session[0]);
-            Ok(Some(session[0].clone()))
-        } else {
-            // If 0 then we have no valid sessions
-            Ok(None)
-        }  
-    }
-
-    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
-        info!("storing session by id `{}`", session.id());
-        let copy = session.clone();
-        // insert session into database
-        self.conn
-            .call(move |conn| { 
-                // sessions table takes id as string and session as a BLOB
-                conn.execute(
-                    "INSERT INTO sessions(id, session) VALUES (?1, ?2) ON CONFLICT(id) DO UPDATE SET session=excluded.session",
-                    params![copy.id(), rmp_serde::to_vec(&copy).unwrap()],
-                )
-            })
-            .await
-            .map_err(|err| {
-                tracing::debug!("Session insert err: {:?}", err); 
-                err
-            })?;
-
-        Ok(session.into_cookie_value())
-    }
+/// Identity of whoever called a token authenticated route.
+/// `token_auth` inserts it as a request extension so handlers can use `Extension<Caller>`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Caller {
+    pub token_id: i64,
+    pub token_name: String,
+    pub user_id: i64,
+    pub user_name: String,
+    /// The owner is a service account and not a person
+    pub service_account: bool,
+    /// Scopes of the token that the owner's current role still allows
+    pub scopes: Vec<Scope>,
+}
 
-    async fn destroy_session(&self, session: Session) -> async_session::Result {
-        info!("destroying session by id `{}`", session.id());
-        // delete session with associated id
-        self.conn
-            .call(move |conn| { 
-                conn.execute(
-                    "DELETE FROM sessions WHERE id = ?1",
-                    params![session.id()],
-                )
-            })
-            .await?;
-        Ok(())
+impl Caller {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
     }
 
-    async fn clear_store(&self) -> async_session::Result {
-        info!("clearing memory store");
-        // clear all sessions
-        self.conn
-            .call(|conn| { 
-                // sessions table takes id as string and session as a BLOB
-                conn.execute("DELETE FROM sessions", [])
-            })
-            .await?;
-        Ok(())
+    /// Reject the request with 403 unless the token has the scope
+    #[allow(clippy::missing_errors_doc)]
+    pub fn require(&self, scope: Scope) -> Result<(), (StatusCode, Json<JsonError>)> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            tracing::debug!("Token {} is missing scope {}", self.token_id, scope);
+            Err((StatusCode::FORBIDDEN, Json(JsonError::new(format!("Missing Scope: {}", scope)))))
+        }
     }
 }
 
@@ -120,7 +99,7 @@ impl SessionStore for SqliteSessionStore{
 #[allow(clippy::missing_errors_doc)]
 pub async fn token_auth<B: Send + Sync>(
     State(conn): State<Connection>,
-    req: Request<B>,
+    mut req: Request<B>,
     next: Next<B>,
 ) -> Result<Response, (StatusCode, Json<JsonError>)>{
     // Auth header should be in the form of `Bearer xxxxxxxx....`
@@ -146,28 +125,61 @@ pub async fn token_auth<B: Send + Sync>(
         return Err((StatusCode::UNAUTHORIZED, Json(JsonError::unauthorized())));
     };
 
-    tracing::debug!("Reveived Token: {}", token);
+    tracing::debug!("Received Token: {}...", token::prefix(&token));
 
-    // Check that token exists in the DB
-    let tokens = conn
+    // Tokens are stored hashed so look up the hash of what we got
+    let hash = token::hash(&token);
+    // Check that token exists in the DB, hasn't expired and its owner isn't disabled
+    let callers = conn
         .call(move |conn| { 
             // Sql query
-            let mut stmt = conn.prepare("Select id FROM tokens WHERE id = :id")?;
+            let mut stmt = conn.prepare(
+                "SELECT api_tokens.id, api_tokens.name, users.id, users.name, users.role, api_tokens.scopes, users.service_account
+                 FROM api_tokens JOIN users ON users.id = api_tokens.user_id
+                 WHERE hash = :hash AND users.disabled = 0 AND (expires_at IS NULL OR expires_at > unixepoch())",
+            )?;
             // submit the query and get the token
-            // There should be just one row because the token is the primary key
-            let tokens = stmt
-                .query_map(&[(":id", &token)], |row| {
-                    let data: String  = row.get(0)?;
-                    Ok(data)
+            // There should be just one row because the hash is unique
+            let callers = stmt
+                .query_map(&[(":hash", &hash)], |row| {
+                    let role: Role = row.get(4)?;
+                    let scopes: String = row.get(5)?;
+                    Ok(Caller {
+                        token_id: row.get(0)?,
+                        token_name: row.get(1)?,
+                        user_id: row.get(2)?,
+                        user_name: row.get(3)?,
+                        service_account: row.get(6)?,
+                        // The owner might have lost their role since the token was minted
+                        scopes: token::scopes_from_string(&scopes)
+                            .into_iter()
+                            .filter(|scope| scope.min_role() <= role)
+                            .collect(),
+                    })
                 })?
-                .collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
-            Ok::<_, rusqlite::Error>(tokens)
+                .collect::<std::result::Result<Vec<Caller>, rusqlite::Error>>()?;
+            // Keep track of when each token was last used
+            if let Some(caller) = callers.first() {
+                conn.execute("UPDATE api_tokens SET last_used_at = unixepoch() WHERE id = ?1", params![caller.token_id])?;
+            }
+            Ok::<_, rusqlite::Error>(callers)
         })
         .await.map_err(|_err| (StatusCode::UNAUTHORIZED, Json(JsonError::unauthorized())))?;
 
-    // There should be one row because the token is the primary key
-    if tokens.len() != 0 {
+    // There should be one row because the hash is unique
+    if let Some(caller) = callers.into_iter().next() {
         // We know that it is a valid token so we can pass on the request 
+        // along with who made it
+        tracing::debug!("Token {} used by {}", caller.token_id, caller.user_name);
+        let mut event = AuditEvent::new("token.use")
+            .caller(&caller)
+            .target(req.uri().path())
+            .details(serde_json::json!({"method": req.method().as_str(), "token": caller.token_name}));
+        if let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+            event = event.ip(addr);
+        }
+        audit::record(&conn, event).await;
+        req.extensions_mut().insert(caller);
         Ok(next.run(req).await)
     } else {
         // Token not found so reject the request
@@ -176,6 +188,18 @@ pub async fn token_auth<B: Send + Sync>(
     }
 }
 
+/// Current unix timestamp in seconds
+pub fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| i64::try_from(time.as_secs()).unwrap_or(i64::MAX))
+}
+
+/// Compare two secrets without returning early so timing doesn't leak how much matched
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 /// Return error as Json for API requests
 #[derive(Serialize, Deserialize)]
 pub struct JsonError {
@@ -0,0 +1,165 @@
+use std::{env, str::FromStr, time::Duration};
+
+use argon2::Params;
+use axum_login::axum_sessions::SameSite;
+
+use crate::{heartbeat::HeartbeatConfig, lockout::LockoutPolicy, oidc::OidcConfig, passkey::PasskeyConfig, password::PasswordPolicy, prober::ProberConfig, session_store::StoreKind, token};
+
+/// Runtime settings for the backend. Values are read from environmental
+/// variables at startup and fall back to defaults when unset.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub lockout: LockoutPolicy,
+    pub session: SessionConfig,
+    /// Argon2 cost parameters for new password hashes. Stored hashes that are weaker get upgraded at login.
+    pub argon2: Params,
+    /// Rules for new passwords
+    pub password: PasswordPolicy,
+    /// Single sign on settings. `None` when no provider is configured.
+    pub oidc: Option<OidcConfig>,
+    /// Relying party settings for passkey login
+    pub passkey: PasskeyConfig,
+    /// Background health checks of services
+    pub prober: ProberConfig,
+    /// Service heartbeats over NATS
+    pub heartbeat: HeartbeatConfig,
+    /// Token for scripts from `API_TOKEN`. It belongs to the first admin so it is added once one exists.
+    pub api_token: Option<String>,
+}
+
+/// How long sessions live, how often expired ones are removed and how the session cookie is set
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Where sessions are kept
+    pub store: StoreKind,
+    /// Key value bucket used by the NATS store
+    pub nats_bucket: String,
+    /// Lifetime of a session on the server. The cookie itself is gone when the browser closes.
+    pub ttl: Duration,
+    /// Lifetime of a session and its cookie when the user picks "remember me" at login
+    pub remember_ttl: Duration,
+    /// Reset the lifetime every time the session is used
+    pub sliding: bool,
+    /// How often expired sessions are deleted from the store
+    pub cleanup_interval: Duration,
+    pub cookie_name: String,
+    /// Only send the cookie over https. Should be on anywhere that isn't local development.
+    pub cookie_secure: bool,
+    pub cookie_same_site: SameSite,
+    /// Leave unset to only send the cookie to the exact host that set it
+    pub cookie_domain: Option<String>,
+    pub cookie_path: String,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            store: StoreKind::default(),
+            nats_bucket: "cyberdeck_sessions".to_string(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            remember_ttl: Duration::from_secs(30 * 24 * 60 * 60),
+            sliding: true,
+            cleanup_interval: Duration::from_secs(10 * 60),
+            cookie_name: crate::SESSION_COOKIE_NAME.to_string(),
+            cookie_secure: false,
+            cookie_same_site: SameSite::Lax,
+            cookie_domain: None,
+            cookie_path: "/".to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Build the config from environmental variables
+    pub fn from_env() -> Self {
+        let lockout = LockoutPolicy::default();
+        let session = SessionConfig::default();
+        let argon2 = Params::new(
+            env_or("ARGON2_M_COST", Params::DEFAULT_M_COST),
+            env_or("ARGON2_T_COST", Params::DEFAULT_T_COST),
+            env_or("ARGON2_P_COST", Params::DEFAULT_P_COST),
+            None,
+        )
+        .unwrap_or_else(|err| {
+            tracing::warn!("Invalid Argon2 parameters ({}). Using defaults.", err);
+            Params::default()
+        });
+        Self {
+            lockout: LockoutPolicy {
+                max_failures: env_or("LOGIN_MAX_FAILURES", lockout.max_failures),
+                max_failures_per_ip: env_or("LOGIN_MAX_FAILURES_PER_IP", lockout.max_failures_per_ip),
+                lockout: Duration::from_secs(env_or("LOGIN_LOCKOUT_SECS", lockout.lockout.as_secs())),
+                ..lockout
+            },
+            session: SessionConfig {
+                store: env_or("SESSION_STORE", session.store),
+                nats_bucket: env_or("SESSION_NATS_BUCKET", session.nats_bucket),
+                ttl: Duration::from_secs(env_or("SESSION_TTL_SECS", session.ttl.as_secs())),
+                remember_ttl: Duration::from_secs(env_or("SESSION_REMEMBER_TTL_SECS", session.remember_ttl.as_secs())),
+                sliding: env_or("SESSION_SLIDING", session.sliding),
+                cleanup_interval: env_interval_or("SESSION_CLEANUP_SECS", session.cleanup_interval),
+                cookie_name: env_or("SESSION_COOKIE_NAME", session.cookie_name),
+                cookie_secure: env_or("SESSION_COOKIE_SECURE", session.cookie_secure),
+                cookie_same_site: env::var("SESSION_COOKIE_SAME_SITE")
+                    .ok()
+                    .and_then(|value| parse_same_site(&value))
+                    .unwrap_or(session.cookie_same_site),
+                cookie_domain: env::var("SESSION_COOKIE_DOMAIN").ok().or(session.cookie_domain),
+                cookie_path: env_or("SESSION_COOKIE_PATH", session.cookie_path),
+            },
+            argon2,
+            password: PasswordPolicy::from_env(),
+            oidc: OidcConfig::from_env(),
+            passkey: PasskeyConfig::from_env(),
+            prober: ProberConfig::from_env(),
+            heartbeat: HeartbeatConfig::from_env(),
+            api_token: api_token_from_env(),
+        }
+    }
+}
+
+/// Read `API_TOKEN`. Short tokens are refused since they are easy to guess and too short to show only part of.
+fn api_token_from_env() -> Option<String> {
+    let api_token = env::var("API_TOKEN").ok()?;
+    if !token::is_long_enough(&api_token) {
+        tracing::warn!("API_TOKEN was not added because it is too short. Use at least {} characters.", token::TOKEN_LENGTH);
+        return None;
+    }
+    Some(api_token)
+}
+
+/// Parse a SameSite policy. `SameSite` has no `FromStr` so `env_or` can't be used for it.
+fn parse_same_site(value: &str) -> Option<SameSite> {
+    match value.to_ascii_lowercase().as_str() {
+        "strict" => Some(SameSite::Strict),
+        "lax" => Some(SameSite::Lax),
+        "none" => Some(SameSite::None),
+        _ => {
+            tracing::warn!("Could not parse SESSION_COOKIE_SAME_SITE. Using default.");
+            None
+        },
+    }
+}
+
+/// Read and parse an environmental variable or use the default if it is missing or invalid
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            tracing::warn!("Could not parse {}. Using default.", key);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Read how often a background task runs in seconds. Zero is refused like a value that doesn't
+/// parse since a timer can't tick every 0 seconds.
+pub fn env_interval_or(key: &str, default: Duration) -> Duration {
+    match env_or(key, default.as_secs()) {
+        0 => {
+            tracing::warn!("{} can not be 0. Using default.", key);
+            default
+        },
+        secs => Duration::from_secs(secs),
+    }
+}
@@ -0,0 +1,59 @@
+use axum::{
+    http::{Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_login::axum_sessions::SessionHandle;
+use rand::Rng;
+
+use crate::auth::{constant_time_eq, JsonError};
+
+/// Header the frontend sends the token back in
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Session key holding the token for the session
+const SESSION_CSRF_KEY: &str = "csrf_token";
+
+/// Get the CSRF token of a session, making one the first time it is asked for.
+/// The token lives as long as the session so it survives logging in.
+pub async fn session_token(session: &SessionHandle) -> anyhow::Result<String> {
+    if let Some(token) = session.read().await.get::<String>(SESSION_CSRF_KEY) {
+        return Ok(token);
+    }
+    let mut session = session.write().await;
+    // Someone else may have made one while we waited for the lock
+    if let Some(token) = session.get::<String>(SESSION_CSRF_KEY) {
+        return Ok(token);
+    }
+    let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+    session.insert(SESSION_CSRF_KEY, &token)?;
+    Ok(token)
+}
+
+/// Middleware for cookie authenticated routes. Anything that isn't a safe method has to
+/// send the session's CSRF token in the `X-CSRF-Token` header. Another site can make the
+/// browser send our cookie but it can't read the token, so forged requests get a 403.
+/// Token authenticated routes don't use cookies and don't go through this.
+pub async fn csrf_protect<B>(req: Request<B>, next: Next<B>) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+
+    let sent = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .map(str::to_string);
+    let expected = match req.extensions().get::<SessionHandle>() {
+        Some(session) => session.read().await.get::<String>(SESSION_CSRF_KEY),
+        None => None,
+    };
+
+    match (sent, expected) {
+        (Some(sent), Some(expected)) if constant_time_eq(sent.as_bytes(), expected.as_bytes()) => next.run(req).await,
+        _ => {
+            tracing::warn!("Rejected {} {} without a valid CSRF token", req.method(), req.uri().path());
+            (StatusCode::FORBIDDEN, Json(JsonError::new("Invalid CSRF Token".into()))).into_response()
+        },
+    }
+}
@@ -0,0 +1,52 @@
+/// Give a fieldless enum a lowercase name that is used for display, parsing and storing it in the
+/// db as TEXT. Implements `as_str`, `Display`, `FromStr` and rusqlite's `ToSql` and `FromSql`.
+/// `what` names the enum in the error for unknown names.
+///
+/// ```ignore
+/// db_enum!(Role, "role", Viewer => "viewer", Operator => "operator", Admin => "admin");
+/// ```
+macro_rules! db_enum {
+    ($name:ident, $what:literal, $($variant:ident => $text:literal),+ $(,)?) => {
+        impl $name {
+            pub const fn as_str(self) -> &'static str {
+                match self {
+                    $(Self::$variant => $text,)+
+                }
+            }
+        }
+
+        impl ::std::fmt::Display for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl ::std::str::FromStr for $name {
+            type Err = ::anyhow::Error;
+
+            fn from_str(s: &str) -> ::anyhow::Result<Self> {
+                match s {
+                    $($text => Ok(Self::$variant),)+
+                    _ => ::anyhow::bail!("Unknown {}: {}", $what, s),
+                }
+            }
+        }
+
+        impl ::rusqlite::types::ToSql for $name {
+            fn to_sql(&self) -> ::rusqlite::Result<::rusqlite::types::ToSqlOutput<'_>> {
+                Ok(::rusqlite::types::ToSqlOutput::from(self.as_str()))
+            }
+        }
+
+        impl ::rusqlite::types::FromSql for $name {
+            fn column_result(value: ::rusqlite::types::ValueRef<'_>) -> ::rusqlite::types::FromSqlResult<Self> {
+                value
+                    .as_str()?
+                    .parse()
+                    .map_err(|err: ::anyhow::Error| ::rusqlite::types::FromSqlError::Other(err.into()))
+            }
+        }
+    };
+}
+
+pub(crate) use db_enum;
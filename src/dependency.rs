@@ -0,0 +1,265 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+use anyhow::Result;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tokio_rusqlite::Connection;
+
+use crate::{
+    db_enum::db_enum,
+    services::{Service, ServiceStatus},
+};
+
+/// How much a service needs one of its dependencies
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyKind {
+    /// The service stops working without it
+    #[default]
+    Hard,
+    /// The service keeps running with less functionality
+    Soft,
+}
+
+db_enum!(DependencyKind, "dependency kind", Hard => "hard", Soft => "soft");
+
+/// An edge of the graph: `service` depends on `depends_on`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Dependency {
+    pub service: String,
+    pub depends_on: String,
+    pub kind: DependencyKind,
+}
+
+/// Every service and how they depend on each other
+#[derive(Debug, Clone, Serialize)]
+pub struct Graph {
+    pub nodes: Vec<Service>,
+    pub edges: Vec<Dependency>,
+}
+
+/// A service affected by another one going down
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Impact {
+    pub service: String,
+    /// `down` when every dependency on the way is hard, `degraded` otherwise
+    pub impact: ServiceStatus,
+    /// Number of dependencies between the failed service and this one, 1 for direct dependents
+    pub depth: u32,
+    /// Dependency this service is affected through
+    pub via: String,
+}
+
+/// What happened when adding a dependency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Saved,
+    /// One of the services isn't registered
+    NotFound,
+    /// The dependency would close a loop
+    Cycle,
+}
+
+/// Whether adding `service` depends on `depends_on` would close a loop,
+/// which is when `depends_on` already needs `service` directly or through others.
+pub fn creates_cycle(edges: &[Dependency], service: &str, depends_on: &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = vec![depends_on];
+    while let Some(current) = stack.pop() {
+        if current == service {
+            return true;
+        }
+        if seen.insert(current) {
+            stack.extend(edges.iter().filter(|edge| edge.service == current).map(|edge| edge.depends_on.as_str()));
+        }
+    }
+    false
+}
+
+/// Walk from `name` to the services that depend on it, following edges `follow` accepts.
+/// Returns each service reached with its distance and the dependency it was reached through.
+fn dependents(edges: &[Dependency], name: &str, follow: impl Fn(DependencyKind) -> bool) -> BTreeMap<String, (u32, String)> {
+    let mut reached = BTreeMap::new();
+    let mut queue = VecDeque::from([(name.to_string(), 0)]);
+    while let Some((current, depth)) = queue.pop_front() {
+        for edge in edges.iter().filter(|edge| edge.depends_on == current && follow(edge.kind)) {
+            if edge.service != name && !reached.contains_key(&edge.service) {
+                reached.insert(edge.service.clone(), (depth + 1, current.clone()));
+                queue.push_back((edge.service.clone(), depth + 1));
+            }
+        }
+    }
+    reached
+}
+
+/// Services affected if `name` goes down, closest first. A service that only reaches `name`
+/// through hard dependencies goes down with it, one with a soft dependency on the way is degraded.
+pub fn impact(edges: &[Dependency], name: &str) -> Vec<Impact> {
+    let down = dependents(edges, name, |kind| kind == DependencyKind::Hard);
+    let mut affected: Vec<Impact> = dependents(edges, name, |_| true)
+        .into_iter()
+        .map(|(service, (depth, via))| match down.get(&service) {
+            Some((depth, via)) => Impact { service, impact: ServiceStatus::Down, depth: *depth, via: via.clone() },
+            None => Impact { service, impact: ServiceStatus::Degraded, depth, via },
+        })
+        .collect();
+    affected.sort_by(|a, b| a.depth.cmp(&b.depth).then_with(|| a.service.cmp(&b.service)));
+    affected
+}
+
+/// Every dependency in the db
+fn load_edges(conn: &rusqlite::Connection) -> Result<Vec<Dependency>, rusqlite::Error> {
+    let mut stmt = conn.prepare("SELECT service, depends_on, kind FROM service_dependencies ORDER BY service, depends_on")?;
+    let edges = stmt
+        .query_map([], |row| {
+            Ok(Dependency {
+                service: row.get(0)?,
+                depends_on: row.get(1)?,
+                kind: row.get(2)?,
+            })
+        })?
+        .collect::<std::result::Result<Vec<Dependency>, rusqlite::Error>>()?;
+    Ok(edges)
+}
+
+/// Every service with the dependencies between them
+pub async fn graph(conn: &Connection) -> Result<Graph, rusqlite::Error> {
+    conn.call(|conn| {
+        let mut stmt = conn.prepare("SELECT name, server, status FROM services ORDER BY name")?;
+        let nodes = stmt
+            .query_map([], |row| {
+                Ok(Service {
+                    name: row.get(0)?,
+                    server: row.get(1)?,
+                    status: row.get(2)?,
+                })
+            })?
+            .collect::<std::result::Result<Vec<Service>, rusqlite::Error>>()?;
+        Ok::<_, rusqlite::Error>(Graph { nodes, edges: load_edges(conn)? })
+    })
+    .await
+}
+
+/// Services affected if `name` goes down or `None` if there is no such service
+pub async fn impact_of(conn: &Connection, name: String) -> Result<Option<Vec<Impact>>, rusqlite::Error> {
+    conn.call(move |conn| {
+        let found = conn
+            .query_row("SELECT 1 FROM services WHERE name = ?1", params![name], |_| Ok(()))
+            .optional()?;
+        if found.is_none() {
+            return Ok::<_, rusqlite::Error>(None);
+        }
+        Ok(Some(impact(&load_edges(conn)?, &name)))
+    })
+    .await
+}
+
+/// Add a dependency or change its kind. Both services have to exist and the graph has to stay
+/// free of loops, otherwise nothing is saved.
+pub fn save(conn: &mut rusqlite::Connection, service: &str, depends_on: &str, kind: DependencyKind) -> Result<Outcome, rusqlite::Error> {
+    let tx = conn.transaction()?;
+    let found: u32 = tx.query_row(
+        "SELECT COUNT(*) FROM services WHERE name IN (?1, ?2)",
+        params![service, depends_on],
+        |row| row.get(0),
+    )?;
+    if found != 2 {
+        return Ok(Outcome::NotFound);
+    }
+    if creates_cycle(&load_edges(&tx)?, service, depends_on) {
+        return Ok(Outcome::Cycle);
+    }
+    tx.execute(
+        "INSERT INTO service_dependencies (service, depends_on, kind) VALUES (?1, ?2, ?3)
+         ON CONFLICT(service, depends_on) DO UPDATE SET kind=excluded.kind",
+        params![service, depends_on, kind],
+    )?;
+    tx.commit()?;
+    Ok(Outcome::Saved)
+}
+
+/// Remove a dependency. Returns how many were deleted.
+pub async fn remove(conn: &Connection, service: String, depends_on: String) -> Result<usize, rusqlite::Error> {
+    conn.call(move |conn| {
+        conn.execute(
+            "DELETE FROM service_dependencies WHERE service = ?1 AND depends_on = ?2",
+            params![service, depends_on],
+        )
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::MIGRATIONS;
+
+    fn edge(service: &str, depends_on: &str, kind: DependencyKind) -> Dependency {
+        Dependency { service: service.to_string(), depends_on: depends_on.to_string(), kind }
+    }
+
+    #[test]
+    fn impact_follows_dependents() {
+        // web -> api -> db, api -soft-> cache, worker -> db, report -soft-> api
+        let edges = vec![
+            edge("web", "api", DependencyKind::Hard),
+            edge("api", "db", DependencyKind::Hard),
+            edge("api", "cache", DependencyKind::Soft),
+            edge("worker", "db", DependencyKind::Hard),
+            edge("report", "api", DependencyKind::Soft),
+        ];
+        let affected = impact(&edges, "db");
+        let summary: Vec<(&str, ServiceStatus, u32)> = affected.iter().map(|i| (i.service.as_str(), i.impact, i.depth)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("api", ServiceStatus::Down, 1),
+                ("worker", ServiceStatus::Down, 1),
+                ("report", ServiceStatus::Degraded, 2),
+                ("web", ServiceStatus::Down, 2),
+            ]
+        );
+        assert_eq!(affected[3].via, "api");
+
+        // Losing the cache only degrades everything above it
+        let affected = impact(&edges, "cache");
+        assert!(affected.iter().all(|i| i.impact == ServiceStatus::Degraded));
+        assert_eq!(affected.len(), 3);
+        assert!(impact(&edges, "web").is_empty());
+    }
+
+    #[test]
+    fn cycles_are_spotted() {
+        let edges = vec![edge("web", "api", DependencyKind::Hard), edge("api", "db", DependencyKind::Soft)];
+        assert!(creates_cycle(&edges, "db", "web"));
+        assert!(creates_cycle(&edges, "api", "api"));
+        assert!(!creates_cycle(&edges, "web", "db"));
+        assert!(!creates_cycle(&edges, "worker", "db"));
+    }
+
+    #[tokio::test]
+    async fn dependencies_are_saved_without_loops() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        let outcomes = conn
+            .call(|conn| {
+                conn.execute("INSERT INTO services (name, server) VALUES ('web', 'Main'), ('api', 'Main'), ('db', 'Data')", [])?;
+                let saved = save(conn, "web", "api", DependencyKind::Hard)?;
+                save(conn, "api", "db", DependencyKind::Hard)?;
+                let changed = save(conn, "api", "db", DependencyKind::Soft)?;
+                let cycle = save(conn, "db", "web", DependencyKind::Soft)?;
+                let missing = save(conn, "web", "nope", DependencyKind::Hard)?;
+                Ok::<_, rusqlite::Error>((saved, changed, cycle, missing))
+            })
+            .await
+            .unwrap();
+        assert_eq!(outcomes, (Outcome::Saved, Outcome::Saved, Outcome::Cycle, Outcome::NotFound));
+
+        let graph = graph(&conn).await.unwrap();
+        assert_eq!(graph.nodes.len(), 3);
+        assert_eq!(graph.edges, vec![edge("api", "db", DependencyKind::Soft), edge("web", "api", DependencyKind::Hard)]);
+        assert_eq!(impact_of(&conn, "db".into()).await.unwrap().unwrap().len(), 2);
+        assert_eq!(impact_of(&conn, "nope".into()).await.unwrap(), None);
+        assert_eq!(remove(&conn, "web".into(), "api".into()).await.unwrap(), 1);
+    }
+}
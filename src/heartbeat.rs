@@ -0,0 +1,436 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use rusqlite::{params, OptionalExtension};
+use serde::Deserialize;
+use tokio::time::MissedTickBehavior;
+use tokio_rusqlite::Connection;
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::unix_now,
+    config::{env_interval_or, env_or},
+    services::{self, ServiceStatus},
+};
+
+/// Source recorded in the status history for changes made from heartbeats
+const SOURCE: &str = "heartbeat";
+/// Longest interval a service can announce
+const MAX_INTERVAL_SECS: u32 = 60 * 60;
+
+/// Settings for service heartbeats over NATS
+#[derive(Debug, Clone)]
+pub struct HeartbeatConfig {
+    pub enabled: bool,
+    /// Services publish on `<prefix>.<service name>`
+    pub subject_prefix: String,
+    /// Add services that aren't in the registry yet when they send a heartbeat.
+    /// Anyone who can publish to NATS could fill the registry so this is off by default.
+    pub auto_register: bool,
+    /// Interval assumed for heartbeats that don't say how often they are sent
+    pub default_interval: Duration,
+    /// Missed heartbeats before a service is marked degraded
+    pub stale_after: u32,
+    /// Missed heartbeats before a service is marked down
+    pub down_after: u32,
+    /// How often services are checked for missed heartbeats
+    pub reap_interval: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            subject_prefix: "cyberdeck.heartbeat".to_string(),
+            auto_register: false,
+            default_interval: Duration::from_secs(30),
+            stale_after: 2,
+            down_after: 5,
+            reap_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    /// Read the settings from environmental variables
+    pub fn from_env() -> Self {
+        let config = Self::default();
+        Self {
+            enabled: env_or("HEARTBEAT_ENABLED", config.enabled),
+            subject_prefix: env_or("HEARTBEAT_SUBJECT_PREFIX", config.subject_prefix),
+            auto_register: env_or("HEARTBEAT_AUTO_REGISTER", config.auto_register),
+            default_interval: Duration::from_secs(env_or("HEARTBEAT_INTERVAL_SECS", config.default_interval.as_secs())),
+            stale_after: env_or("HEARTBEAT_STALE_AFTER", config.stale_after),
+            down_after: env_or("HEARTBEAT_DOWN_AFTER", config.down_after),
+            reap_interval: env_interval_or("HEARTBEAT_REAP_SECS", config.reap_interval),
+        }
+    }
+}
+
+/// Body of a heartbeat message. Every field is optional so an empty message works too.
+#[derive(Debug, Default, Deserialize)]
+pub struct Heartbeat {
+    /// Server the service runs on. Only used when the service gets registered.
+    pub server: Option<String>,
+    /// Status the service reports for itself. Defaults to up.
+    pub status: Option<ServiceStatus>,
+    /// How often the service sends heartbeats
+    pub interval_secs: Option<u32>,
+    /// Kept as the reason when the status changes
+    pub message: Option<String>,
+}
+
+impl Heartbeat {
+    /// Check what a service sent. It can say it is up, degraded or down. Maintenance is set by
+    /// people and unknown is what the registry says before anyone reported.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(server) = &self.server {
+            services::validate_name("Server", server)?;
+        }
+        match self.status {
+            Some(status @ (ServiceStatus::Maintenance | ServiceStatus::Unknown)) => Err(format!("Status {} Can Not Be Reported", status)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What happened with a heartbeat
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Recorded,
+    /// The service was new and got added to the registry
+    Registered,
+    /// The service isn't registered and auto registration is off
+    Unknown,
+    /// The service is registered but heartbeats aren't turned on for it, so its status is left alone
+    Untracked,
+}
+
+/// Interval to expect heartbeats at when `requested` or the default otherwise.
+/// Either one is kept between 1 second and an hour.
+pub fn interval(config: &HeartbeatConfig, requested: Option<u32>) -> u32 {
+    let secs = requested.unwrap_or_else(|| u32::try_from(config.default_interval.as_secs()).unwrap_or(MAX_INTERVAL_SECS));
+    secs.clamp(1, MAX_INTERVAL_SECS)
+}
+
+/// Status a service should get after going `now - last_seen` seconds without a heartbeat
+pub fn missed_status(config: &HeartbeatConfig, last_seen: i64, interval_secs: u32, now: i64) -> Option<(ServiceStatus, String)> {
+    let missed = (now - last_seen) / i64::from(interval_secs.max(1));
+    if missed >= i64::from(config.down_after) {
+        Some((ServiceStatus::Down, format!("No heartbeat for {}s", now - last_seen)))
+    } else if missed >= i64::from(config.stale_after) {
+        Some((ServiceStatus::Degraded, format!("Heartbeat stale, last one {}s ago", now - last_seen)))
+    } else {
+        None
+    }
+}
+
+/// Store a heartbeat and update the status of the service, registering it first if needed.
+/// Only services that send heartbeats get their status from them, a service in the registry
+/// has to have heartbeats turned on with `enable` first. Services in maintenance keep their status.
+pub fn apply(
+    conn: &mut rusqlite::Connection,
+    config: &HeartbeatConfig,
+    name: &str,
+    heartbeat: &Heartbeat,
+    now: i64,
+) -> Result<Outcome, rusqlite::Error> {
+    let interval = interval(config, heartbeat.interval_secs);
+    let status = heartbeat.status.unwrap_or(ServiceStatus::Up);
+    let reason = heartbeat.message.as_deref().unwrap_or("heartbeat received");
+
+    let tx = conn.transaction()?;
+    let current: Option<(ServiceStatus, bool)> = tx
+        .query_row(
+            "SELECT status, EXISTS (SELECT 1 FROM service_heartbeats WHERE service = services.name) FROM services WHERE name = ?1",
+            params![name],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    // The status heartbeats gave the service, None if they didn't touch it
+    let (outcome, set) = match current {
+        Some((_, false)) => return Ok(Outcome::Untracked),
+        Some((ServiceStatus::Maintenance, true)) => (Outcome::Recorded, None),
+        Some((_, true)) => {
+            services::change_status(&tx, name, status, SOURCE, Some(reason))?;
+            (Outcome::Recorded, Some(status))
+        },
+        None if config.auto_register => {
+            let server = heartbeat.server.as_deref().unwrap_or("Unknown");
+            tx.execute("INSERT INTO services (name, server, status) VALUES (?1, ?2, ?3)", params![name, server, status])?;
+            services::record_transition(&tx, name, None, status, SOURCE, Some(reason))?;
+            (Outcome::Registered, Some(status))
+        },
+        None => return Ok(Outcome::Unknown),
+    };
+    tx.execute(
+        "INSERT INTO service_heartbeats (service, last_seen_at, interval_secs, status) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(service) DO UPDATE SET last_seen_at=excluded.last_seen_at, interval_secs=excluded.interval_secs,
+            status=COALESCE(excluded.status, status)",
+        params![name, now, interval, set],
+    )?;
+    tx.commit()?;
+    Ok(outcome)
+}
+
+/// Mark services whose heartbeats stopped. Returns how many changed status.
+/// Services with an enabled health check are left to the prober. A service whose status was
+/// changed by someone else since heartbeats last set it is left alone until heartbeats come back,
+/// so an admin or the prober aren't overruled on every round.
+pub fn reap(conn: &mut rusqlite::Connection, config: &HeartbeatConfig, now: i64) -> Result<usize, rusqlite::Error> {
+    let tx = conn.transaction()?;
+    let beats = {
+        let mut stmt = tx.prepare(
+            "SELECT service_heartbeats.service, last_seen_at, interval_secs FROM service_heartbeats
+             JOIN services ON services.name = service_heartbeats.service
+             WHERE services.status != ?1 AND (service_heartbeats.status IS NULL OR service_heartbeats.status = services.status)
+                AND NOT EXISTS (SELECT 1 FROM service_checks WHERE service_checks.service = service_heartbeats.service AND enabled = 1)",
+        )?;
+        let rows = stmt
+            .query_map(params![ServiceStatus::Maintenance], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, u32>(2)?)))?
+            .collect::<std::result::Result<Vec<(String, i64, u32)>, rusqlite::Error>>()?;
+        rows
+    };
+    let mut changed = 0;
+    for (name, last_seen, interval) in beats {
+        if let Some((status, reason)) = missed_status(config, last_seen, interval, now) {
+            if services::change_status(&tx, &name, status, SOURCE, Some(&reason))? != Some(status) {
+                changed += 1;
+            }
+            tx.execute("UPDATE service_heartbeats SET status = ?1 WHERE service = ?2", params![status, name])?;
+        }
+    }
+    tx.commit()?;
+    Ok(changed)
+}
+
+/// Start expecting heartbeats from a registered service every `interval_secs`. It counts as just
+/// seen so it has a few intervals to send the first one. Returns false if the service doesn't exist.
+pub async fn enable(conn: &Connection, service: String, interval_secs: u32, now: i64) -> Result<bool, rusqlite::Error> {
+    conn.call(move |conn| {
+        let saved = conn.execute(
+            "INSERT INTO service_heartbeats (service, last_seen_at, interval_secs)
+             SELECT ?1, ?2, ?3 WHERE EXISTS (SELECT 1 FROM services WHERE name = ?1)
+             ON CONFLICT(service) DO UPDATE SET interval_secs=excluded.interval_secs",
+            params![service, now, interval_secs],
+        )?;
+        Ok::<_, rusqlite::Error>(saved == 1)
+    })
+    .await
+}
+
+/// Stop taking heartbeats from a service. Its status is left as it was. Returns how many were removed.
+pub async fn disable(conn: &Connection, service: String) -> Result<usize, rusqlite::Error> {
+    conn.call(move |conn| conn.execute("DELETE FROM service_heartbeats WHERE service = ?1", params![service]))
+        .await
+}
+
+/// Handle one heartbeat message
+async fn handle(conn: &Connection, config: &HeartbeatConfig, msg: async_nats::Message) {
+    let Some(name) = msg.subject.strip_prefix(format!("{}.", config.subject_prefix).as_str()).map(str::to_string) else {
+        return;
+    };
+    if let Err(err) = services::validate_service_name(&name) {
+        tracing::warn!("Ignoring heartbeat for invalid service name {:?}: {}", name, err);
+        return;
+    }
+    let heartbeat: Heartbeat = if msg.payload.is_empty() {
+        Heartbeat::default()
+    } else {
+        match serde_json::from_slice(&msg.payload) {
+            Ok(heartbeat) => heartbeat,
+            Err(err) => {
+                tracing::warn!("Ignoring bad heartbeat from {}: {}", name, err);
+                return;
+            },
+        }
+    };
+    if let Err(err) = heartbeat.validate() {
+        tracing::warn!("Ignoring invalid heartbeat from {}: {}", name, err);
+        return;
+    }
+
+    let (apply_config, apply_name) = (config.clone(), name.clone());
+    let result = conn
+        .call(move |conn| apply(conn, &apply_config, &apply_name, &heartbeat, unix_now()))
+        .await;
+    match result {
+        Ok(Outcome::Recorded) => tracing::trace!("Heartbeat from {}", name),
+        Ok(Outcome::Registered) => {
+            tracing::info!("Registered service {} from its heartbeat", name);
+            audit::record(conn, AuditEvent::new("service.register").anonymous(SOURCE).target(&name)).await;
+        },
+        Ok(Outcome::Unknown) => tracing::debug!("Heartbeat from unregistered service {}", name),
+        Ok(Outcome::Untracked) => tracing::debug!("Heartbeat from {} which doesn't have heartbeats turned on", name),
+        Err(err) => tracing::error!("Heartbeat db err for {}: {:?}", name, err),
+    }
+}
+
+/// Listen for heartbeats and start the task that marks silent services
+pub fn spawn(client: async_nats::Client, conn: Connection, config: HeartbeatConfig) {
+    if !config.enabled {
+        tracing::info!("Service heartbeats are disabled");
+        return;
+    }
+
+    let (listen_conn, listen_config) = (conn.clone(), config.clone());
+    tokio::spawn(async move {
+        // Service names can't contain dots so they are always one token
+        let subject = format!("{}.*", listen_config.subject_prefix);
+        let mut subscriber = match client.subscribe(subject.clone()).await {
+            Ok(subscriber) => subscriber,
+            Err(err) => {
+                tracing::error!("Could not subscribe to {}: {:?}", subject, err);
+                return;
+            },
+        };
+        tracing::info!("Listening for heartbeats on {}", subject);
+        while let Some(msg) = subscriber.next().await {
+            handle(&listen_conn, &listen_config, msg).await;
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.reap_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let reap_config = config.clone();
+            match conn.call(move |conn| reap(conn, &reap_config, unix_now())).await {
+                Ok(0) => (),
+                Ok(count) => tracing::info!("Marked {} services with missed heartbeats", count),
+                Err(err) => tracing::error!("Heartbeat reap db err: {:?}", err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::MIGRATIONS;
+
+    #[test]
+    fn missed_heartbeats_degrade_then_down() {
+        let config = HeartbeatConfig::default();
+        assert_eq!(missed_status(&config, 0, 30, 59), None);
+        assert_eq!(missed_status(&config, 0, 30, 60).map(|(status, _)| status), Some(ServiceStatus::Degraded));
+        assert_eq!(missed_status(&config, 0, 30, 150).map(|(status, _)| status), Some(ServiceStatus::Down));
+    }
+
+    #[test]
+    fn intervals_are_clamped() {
+        let config = HeartbeatConfig::default();
+        assert_eq!(interval(&config, None), 30);
+        assert_eq!(interval(&config, Some(0)), 1);
+        assert_eq!(interval(&config, Some(u32::MAX)), MAX_INTERVAL_SECS);
+        // A default of 0 from HEARTBEAT_INTERVAL_SECS is clamped too
+        let config = HeartbeatConfig { default_interval: Duration::ZERO, ..config };
+        assert_eq!(interval(&config, None), 1);
+    }
+
+    #[tokio::test]
+    async fn heartbeats_register_and_go_stale() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        let outcomes = conn
+            .call(|conn| {
+                let config = HeartbeatConfig { auto_register: true, ..HeartbeatConfig::default() };
+                let heartbeat = Heartbeat { server: Some("Edge".to_string()), interval_secs: Some(10), ..Heartbeat::default() };
+                let first = apply(conn, &config, "netwatch", &heartbeat, 1000)?;
+                let second = apply(conn, &config, "netwatch", &heartbeat, 1010)?;
+                let off = HeartbeatConfig { auto_register: false, ..config.clone() };
+                let unknown = apply(conn, &off, "other", &heartbeat, 1010)?;
+                // Five intervals later it is down
+                let reaped = reap(conn, &config, 1060)?;
+                let status: ServiceStatus = conn.query_row("SELECT status FROM services WHERE name = 'netwatch'", [], |row| row.get(0))?;
+                Ok::<_, rusqlite::Error>((first, second, unknown, reaped, status))
+            })
+            .await
+            .unwrap();
+        assert_eq!(outcomes, (Outcome::Registered, Outcome::Recorded, Outcome::Unknown, 1, ServiceStatus::Down));
+    }
+
+    #[test]
+    fn services_can_not_claim_maintenance_or_unknown() {
+        let report = |status| Heartbeat { status: Some(status), ..Heartbeat::default() }.validate();
+        assert!(report(ServiceStatus::Maintenance).is_err());
+        assert!(report(ServiceStatus::Unknown).is_err());
+        assert_eq!(report(ServiceStatus::Degraded), Ok(()));
+        assert!(Heartbeat { server: Some("edge.*".to_string()), ..Heartbeat::default() }.validate().is_err());
+    }
+
+    async fn status(conn: &Connection, name: &'static str) -> ServiceStatus {
+        conn.call(move |conn| conn.query_row("SELECT status FROM services WHERE name = ?1", [name], |row| row.get(0)))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_services_with_heartbeats_turned_on_take_them() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        conn.call(|conn| conn.execute("INSERT INTO services (name, server, status) VALUES ('web', 'Main', 'up')", []))
+            .await
+            .unwrap();
+        let down = || Heartbeat { status: Some(ServiceStatus::Down), ..Heartbeat::default() };
+        let config = HeartbeatConfig { auto_register: true, ..HeartbeatConfig::default() };
+
+        // Publishing a heartbeat isn't enough to change a registered service
+        let apply_config = config.clone();
+        let outcome = conn.call(move |conn| apply(conn, &apply_config, "web", &down(), 1000)).await.unwrap();
+        assert_eq!(outcome, Outcome::Untracked);
+        assert_eq!(status(&conn, "web").await, ServiceStatus::Up);
+
+        assert!(enable(&conn, "web".into(), 30, 1000).await.unwrap());
+        assert!(!enable(&conn, "nope".into(), 30, 1000).await.unwrap());
+        let outcome = conn.call(move |conn| apply(conn, &config, "web", &down(), 1010)).await.unwrap();
+        assert_eq!(outcome, Outcome::Recorded);
+        assert_eq!(status(&conn, "web").await, ServiceStatus::Down);
+        assert_eq!(disable(&conn, "web".into()).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn missed_heartbeats_do_not_fight_other_writers() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        conn.call(|conn| {
+            conn.execute("INSERT INTO services (name, server, status) VALUES ('api', 'Main', 'up'), ('web', 'Main', 'up')", [])?;
+            // web is also probed
+            conn.execute(
+                "INSERT INTO service_checks (service, kind, target, interval_secs, timeout_secs, failure_threshold)
+                 VALUES ('web', 'tcp', 'web.local:80', 30, 5, 3)",
+                [],
+            )
+        })
+        .await
+        .unwrap();
+        enable(&conn, "api".into(), 10, 1000).await.unwrap();
+        enable(&conn, "web".into(), 10, 1000).await.unwrap();
+        let config = HeartbeatConfig::default();
+        let reap_at = |now| {
+            let config = config.clone();
+            conn.call(move |conn| reap(conn, &config, now))
+        };
+
+        // Only api is reaped, the prober decides for web
+        assert_eq!(reap_at(1060).await.unwrap(), 1);
+        assert_eq!(status(&conn, "api").await, ServiceStatus::Down);
+        assert_eq!(status(&conn, "web").await, ServiceStatus::Up);
+
+        // An admin brings api back up and missed heartbeats leave it alone
+        services::set_status(&conn, "api".into(), ServiceStatus::Up, "user:admin".into(), None).await.unwrap();
+        assert_eq!(reap_at(1070).await.unwrap(), 0);
+        assert_eq!(reap_at(1200).await.unwrap(), 0);
+        assert_eq!(status(&conn, "api").await, ServiceStatus::Up);
+
+        // Until heartbeats come back and stop again
+        let apply_config = config.clone();
+        let beat = Heartbeat { interval_secs: Some(10), ..Heartbeat::default() };
+        conn.call(move |conn| apply(conn, &apply_config, "api", &beat, 1300)).await.unwrap();
+        assert_eq!(reap_at(1320).await.unwrap(), 1);
+        assert_eq!(status(&conn, "api").await, ServiceStatus::Degraded);
+        let history = services::history(&conn, "api".into(), None, None, 10).await.unwrap().unwrap();
+        assert_eq!(history.len(), 3);
+    }
+}
@@ -0,0 +1,212 @@
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tokio_rusqlite::Connection;
+
+use crate::{
+    token,
+    user::{Role, User},
+};
+
+/// Invite codes start with this so they are easy to tell apart from api tokens
+const INVITE_PREFIX: &str = "inv_";
+/// Number of random characters after the prefix
+const INVITE_LENGTH: usize = 32;
+/// Characters of the code kept in the db so admins can tell invites apart
+const DISPLAY_LENGTH: usize = 12;
+/// How long an invite can be redeemed when the admin doesn't say
+pub const DEFAULT_TTL_HOURS: u32 = 7 * 24;
+/// Longest an invite can stay open
+pub const MAX_TTL_HOURS: u32 = 30 * 24;
+
+/// Where an invite is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InviteStatus {
+    Pending,
+    Used,
+    Revoked,
+    Expired,
+}
+
+/// Invite as stored in the db. Only the hash of the code is kept.
+#[derive(Debug, Clone, Serialize)]
+pub struct Invite {
+    pub id: i64,
+    /// first few characters of the code
+    pub prefix: String,
+    /// role the new user gets
+    pub role: Role,
+    pub created_by: Option<i64>,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub used_at: Option<i64>,
+    /// user made from this invite
+    pub used_by: Option<i64>,
+    pub revoked_at: Option<i64>,
+    pub status: InviteStatus,
+}
+
+impl Invite {
+    /// Map a row from `SELECT * FROM invites`. The status is worked out against `now`.
+    pub fn map(row: &rusqlite::Row<'_>, now: i64) -> Result<Self, rusqlite::Error> {
+        let expires_at = row.get("expires_at")?;
+        let used_at = row.get("used_at")?;
+        let revoked_at = row.get("revoked_at")?;
+        let status = match (used_at, revoked_at) {
+            (Some(_), _) => InviteStatus::Used,
+            (None, Some(_)) => InviteStatus::Revoked,
+            (None, None) if expires_at <= now => InviteStatus::Expired,
+            (None, None) => InviteStatus::Pending,
+        };
+        Ok(Self {
+            id: row.get("id")?,
+            prefix: row.get("prefix")?,
+            role: row.get("role")?,
+            created_by: row.get("created_by")?,
+            created_at: row.get("created_at")?,
+            expires_at,
+            used_at,
+            used_by: row.get("used_by")?,
+            revoked_at,
+            status,
+        })
+    }
+}
+
+/// Generate a new random invite code. Returns the raw code, its display prefix and its hash.
+pub fn generate() -> (String, String, String) {
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(INVITE_LENGTH)
+        .map(char::from)
+        .collect();
+    let code = format!("{}{}", INVITE_PREFIX, secret);
+    let prefix = code[..DISPLAY_LENGTH].to_string();
+    // Codes are as random as api tokens so they are hashed the same way
+    let hash = token::hash(&code);
+    (code, prefix, hash)
+}
+
+/// Role of an invite if it can still be redeemed
+pub async fn check(conn: &Connection, code: &str) -> Result<Option<Role>, rusqlite::Error> {
+    let hash = token::hash(code);
+    conn.call(move |conn| {
+        conn.query_row(
+            "SELECT role FROM invites WHERE hash = ?1 AND used_at IS NULL AND revoked_at IS NULL AND expires_at > unixepoch()",
+            params![hash],
+            |row| row.get(0),
+        )
+        .optional()
+    })
+    .await
+}
+
+/// Create the user for an invite and mark the invite as used in one transaction
+/// so a code can't be redeemed twice. The user gets the role of the invite.
+/// Returns the new user id and role, or `None` if the invite isn't valid anymore.
+pub async fn redeem(conn: &Connection, code: &str, user: User) -> Result<Option<(i64, Role)>, rusqlite::Error> {
+    let hash = token::hash(code);
+    conn.call(move |conn| {
+        let tx = conn.transaction()?;
+        let invite = tx
+            .query_row(
+                "SELECT id, role FROM invites WHERE hash = ?1 AND used_at IS NULL AND revoked_at IS NULL AND expires_at > unixepoch()",
+                params![hash],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Role>(1)?)),
+            )
+            .optional()?;
+        let Some((invite_id, role)) = invite else {
+            return Ok(None);
+        };
+        tx.execute(
+            "INSERT INTO users (name, hash, role) VALUES (?1, ?2, ?3)",
+            params![user.name, user.hash, role],
+        )?;
+        let user_id = tx.last_insert_rowid();
+        tx.execute(
+            "UPDATE invites SET used_at = unixepoch(), used_by = ?1 WHERE id = ?2",
+            params![user_id, invite_id],
+        )?;
+        tx.commit()?;
+        Ok(Some((user_id, role)))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::unix_now, migrations::MIGRATIONS};
+
+    /// Store a new operator invite and return its code
+    async fn add_invite(conn: &Connection, expires_at: i64, revoked: bool) -> String {
+        let (code, prefix, hash) = generate();
+        conn.call(move |conn| {
+            conn.execute(
+                "INSERT INTO invites (prefix, hash, role, expires_at, revoked_at) VALUES (?1, ?2, ?3, ?4, CASE WHEN ?5 THEN unixepoch() END)",
+                params![prefix, hash, Role::Operator, expires_at, revoked],
+            )
+        })
+        .await
+        .unwrap();
+        code
+    }
+
+    fn user(name: &str) -> User {
+        User::new(name, "a long enough password", 0, Role::Viewer).unwrap()
+    }
+
+    async fn user_count(conn: &Connection) -> i64 {
+        conn.call(|conn| conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn invites_are_redeemed_once() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        let code = add_invite(&conn, unix_now() + 60, false).await;
+
+        assert_eq!(check(&conn, &code).await.unwrap(), Some(Role::Operator));
+        let (id, role) = redeem(&conn, &code, user("alice")).await.unwrap().unwrap();
+        assert_eq!(role, Role::Operator);
+        let used_by: Option<i64> = conn
+            .call(|conn| conn.query_row("SELECT used_by FROM invites", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(used_by, Some(id));
+
+        assert_eq!(check(&conn, &code).await.unwrap(), None);
+        assert_eq!(redeem(&conn, &code, user("bob")).await.unwrap(), None);
+        assert_eq!(user_count(&conn).await, 1);
+    }
+
+    #[tokio::test]
+    async fn expired_and_revoked_invites_are_rejected() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        let expired = add_invite(&conn, unix_now() - 1, false).await;
+        let revoked = add_invite(&conn, unix_now() + 60, true).await;
+
+        for code in [expired, revoked, "inv_not_a_real_code".to_string()] {
+            assert_eq!(check(&conn, &code).await.unwrap(), None);
+            assert_eq!(redeem(&conn, &code, user("alice")).await.unwrap(), None);
+        }
+        assert_eq!(user_count(&conn).await, 0);
+    }
+
+    #[tokio::test]
+    async fn a_taken_username_leaves_the_invite_open() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        conn.call(|conn| conn.execute("INSERT INTO users (name, hash, role) VALUES ('alice', 'x', 'viewer')", []))
+            .await
+            .unwrap();
+        let code = add_invite(&conn, unix_now() + 60, false).await;
+
+        // The user insert fails inside the transaction so the invite isn't used up
+        assert!(redeem(&conn, &code, user("alice")).await.is_err());
+        assert_eq!(check(&conn, &code).await.unwrap(), Some(Role::Operator));
+        assert!(redeem(&conn, &code, user("bob")).await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use rusqlite::params;
+use tokio_rusqlite::Connection;
+
+/// Limits for failed login attempts. Failures are counted per username and per IP.
+/// Each failure delays the response a bit longer and too many failures lock the key out.
+#[derive(Debug, Clone)]
+pub struct LockoutPolicy {
+    /// Failures for one username before it is locked
+    pub max_failures: i64,
+    /// Failures from one IP before it is locked
+    pub max_failures_per_ip: i64,
+    /// How long a lockout lasts. Failures older than this are forgotten.
+    pub lockout: Duration,
+    /// Delay after the first failure. Doubles with every failure after that.
+    pub base_delay: Duration,
+    /// Upper limit for the delay
+    pub max_delay: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            max_failures_per_ip: 20,
+            lockout: Duration::from_secs(15 * 60),
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(8),
+        }
+    }
+}
+
+impl LockoutPolicy {
+    /// Delay to wait before answering a failed attempt
+    pub fn delay(&self, failures: i64) -> Duration {
+        let exponent = u32::try_from(failures.saturating_sub(1).clamp(0, 16)).unwrap_or(16);
+        self.base_delay.saturating_mul(2u32.pow(exponent)).min(self.max_delay)
+    }
+}
+
+/// Counter key for a username. Usernames don't have to exist so we don't leak which ones do.
+pub fn user_key(name: &str) -> String {
+    format!("user:{}", name.to_lowercase())
+}
+
+/// Counter key for a source IP
+pub fn ip_key(ip: &std::net::IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Returns the unix timestamp until which any of the keys is locked, if one is locked.
+pub async fn locked_until(conn: &Connection, keys: Vec<String>) -> Result<Option<i64>, rusqlite::Error> {
+    conn.call(move |conn| {
+        let mut stmt = conn.prepare(
+            "SELECT MAX(locked_until) FROM login_failures WHERE key = ?1 AND locked_until > unixepoch()",
+        )?;
+        let mut until = None;
+        for key in keys {
+            let locked: Option<i64> = stmt.query_row(params![key], |row| row.get(0))?;
+            until = until.max(locked);
+        }
+        Ok::<_, rusqlite::Error>(until)
+    })
+    .await
+}
+
+/// Count a failed attempt for a key. Returns the number of recent failures and
+/// whether this failure locked the key.
+pub async fn record_failure(conn: &Connection, key: String, max_failures: i64, lockout: Duration) -> Result<(i64, bool), rusqlite::Error> {
+    let lockout = i64::try_from(lockout.as_secs()).unwrap_or(i64::MAX);
+    conn.call(move |conn| {
+        // Failures older than the lockout window start the count over
+        let failures: i64 = conn.query_row(
+            "INSERT INTO login_failures(key, failures, last_failure) VALUES (?1, 1, unixepoch())
+             ON CONFLICT(key) DO UPDATE SET
+                failures = CASE WHEN last_failure <= unixepoch() - ?2 THEN 1 ELSE failures + 1 END,
+                last_failure = excluded.last_failure
+             RETURNING failures",
+            params![key, lockout],
+            |row| row.get(0),
+        )?;
+        let locked = failures >= max_failures;
+        if locked {
+            conn.execute(
+                "UPDATE login_failures SET locked_until = unixepoch() + ?2 WHERE key = ?1",
+                params![key, lockout],
+            )?;
+        }
+        Ok::<_, rusqlite::Error>((failures, locked))
+    })
+    .await
+}
+
+/// Forget all failures for a key. Used after a successful login and to unlock accounts.
+pub async fn clear(conn: &Connection, key: String) -> Result<usize, rusqlite::Error> {
+    conn.call(move |conn| conn.execute("DELETE FROM login_failures WHERE key = ?1", params![key]))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let policy = LockoutPolicy::default();
+        assert_eq!(policy.delay(1), Duration::from_millis(250));
+        assert_eq!(policy.delay(3), Duration::from_secs(1));
+        assert_eq!(policy.delay(100), policy.max_delay);
+    }
+}
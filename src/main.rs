@@ -4,10 +4,9 @@
 #![allow(missing_docs)]
 
 use axum::Router;
-use axum_login::{RusqliteStore, AuthLayer, axum_sessions::SessionLayer};
+use axum_login::{RusqliteStore, AuthLayer, axum_sessions::{SameSite, SessionLayer}};
 use rand::Rng;
-use rusqlite::params;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 use std::env;
 use tracing::log::warn;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -15,24 +14,41 @@ use tokio_rusqlite::Connection;
 use futures::StreamExt;
 
 // SETUP Constants
+/// Default name of the session cookie. Can be changed with SESSION_COOKIE_NAME.
 const SESSION_COOKIE_NAME: &str = "cyberdeck_session";
 const FRONTEND: &str = "./ui/dist";
 const SERVER_PORT: &str = "8080";
 const SERVER_HOST: &str = "0.0.0.0";
-const ADMIN_NAME: &str = "admin";
-const ADMIN_PASS: &str = "averyhardpass";
-const API_TOKEN: &str = "easytoken";
 
 pub mod user;
 pub mod fixer;
 pub mod services;
 pub mod auth;
 pub mod routes;
+pub mod oidc;
+pub mod invite;
+pub mod csrf;
 pub mod migrations;
+pub mod config;
+pub mod lockout;
+pub mod totp;
+pub mod token;
+pub mod audit;
+pub mod password;
+pub mod setup;
+pub mod session_store;
+pub mod passkey;
+pub mod prober;
+pub mod heartbeat;
+pub mod dependency;
+pub mod db_enum;
 use migrations::MIGRATIONS;
 use crate::{
-    auth::SqliteSessionStore,
-    user::{User, UserMapper}, 
+    config::Config,
+    services,
+    session_store::{AnySessionStore, MemorySessionStore, NatsSessionStore, SqliteSessionStore, StoreKind, UserSessions},
+    setup::Setup,
+    user::{Role, User, UserMapper}, 
     fixer::process_msg,
 };
 
@@ -55,21 +71,9 @@ async fn main() {
         Ok(secret) => secret.as_bytes().to_owned(),
         Err(_) => rand::thread_rng().gen::<[u8; 64]>().to_vec()
     };
-    let admin_name = env::var("ADMIN_NAME").ok()
-        .unwrap_or_else(|| {
-            warn!("Admin username set to default. DO NOT USE FOR PRODUCTION!");
-            ADMIN_NAME.to_string()
-        });
-    let admin_pass = env::var("ADMIN_NAME").ok()
-        .unwrap_or_else(|| {
-            warn!("Admin password set to default. DO NOT USE FOR PRODUCTION!");
-            ADMIN_PASS.to_string()
-        });
-    let api_token = env::var("API_TOKEN").ok()
-        .unwrap_or_else(|| {
-            warn!("API token set to default. DO NOT USE FOR PRODUCTION!");
-            API_TOKEN.to_string()
-        });
+    let config = Config::from_env();
+    // Every password hashed from here on uses the configured Argon2 settings
+    user::set_argon2_params(config.argon2.clone());
 
     let addr: SocketAddr = format!("{}:{}", host, port)
         .parse()
@@ -77,6 +81,8 @@ async fn main() {
 
     // Setup connection to fixer
     let fixer = async_nats::connect("localhost:4222").await.expect("Could not connect to fixer");
+    // the NATS session store and heartbeats share the connection
+    let nats = fixer.clone();
     // Spawn new task to handle msgs from fixer
     tokio::spawn(async move {
         let mut subscriber = fixer.clone().subscribe("cyberdeck".into()).await.expect("Could not subscribe to fixer");
@@ -89,52 +95,93 @@ async fn main() {
 
     // Setup DB connection pool and run migrations
     let mut async_conn = Connection::open("./my_db.db3").await.unwrap();
+    // Foreign keys are off by default in sqlite. We need them so deleting a user cleans up after them
+    async_conn.call(|conn| conn.pragma_update(None, "foreign_keys", "ON")).await.expect("Could not enable foreign keys");
     MIGRATIONS.to_latest(&mut async_conn).await.expect("DB migrations failed");
 
-    // Add default admin user and token to db
-    let admin = User::new(&admin_name, &admin_pass, 1).expect("Could not create admin user.");
-
-    // Add preset entries to the DB
-    async_conn.clone().call(move |conn| { 
-        // Set admin user based on username and password provided as env vars
-        conn.execute(
-            "INSERT INTO users (id, name, hash) VALUES (?1, ?2, ?3) ON CONFLICT(id) DO UPDATE SET name=excluded.name, hash=excluded.hash",
-            params![admin.id, admin.name, admin.hash],
-        )?;
-        // Set cyberdeck service in db
-        conn.execute(
-            "INSERT INTO services (name, server, status) VALUES (?1, ?2, ?3) ON CONFLICT(name) DO UPDATE SET server=excluded.server, status=excluded.status",
-            params!["Cyberdeck", "Main", 1],
-        )?;
-        // Set fixer in db
-        conn.execute(
-            "INSERT INTO services (name, server, status) VALUES (?1, ?2, ?3) ON CONFLICT(name) DO UPDATE SET server=excluded.server, status=excluded.status",
-            params!["Fixer", "Main", 1],
-        )?;
-        // Set API token that can be set based on env var
-        conn.execute(
-            "INSERT INTO tokens (id) VALUES (?1) ON CONFLICT(id) DO UPDATE SET id=excluded.id",
-            params![api_token],
-        )
-           
-    }).await.expect("Could not set default admin user or token.");
+    // Add the preset services on the first run. Once there are services they are left alone
+    // so renaming or deleting the presets through the api sticks.
+    async_conn.call(services::seed_defaults).await.expect("Could not set default services.");
+
+    // Set API token that can be set based on env var. It belongs to the first admin and has every scope.
+    // Without an admin yet setup adds it when it creates one.
+    if let Some(api_token) = config.api_token.clone() {
+        let seeded = async_conn.call(move |conn| token::seed_env_token(conn, &api_token)).await.expect("Could not set API token.");
+        if !seeded {
+            tracing::info!("API_TOKEN will be added when setup creates the first admin.");
+        }
+    }
+
+    // Relying party for passkey logins. Fail early if the origin settings don't make sense.
+    let webauthn = Arc::new(passkey::webauthn(&config.passkey).expect("Invalid WEBAUTHN_RP_ID or WEBAUTHN_RP_ORIGIN."));
+
+    // No users means this is the first run. Print a setup code instead of making a default admin.
+    let setup = Arc::new(Setup::init(&async_conn).await.expect("Could not check for first run setup."));
 
     // setup up sessions and store to keep track of session information
-    let session_layer = SessionLayer::new(SqliteSessionStore::new(async_conn.clone()), &secret)
-        .with_cookie_name(SESSION_COOKIE_NAME);
-    let user_store = RusqliteStore::<User, UserMapper>::new(async_conn.clone());
+    let (ttl, sliding) = (config.session.ttl, config.session.sliding);
+    let session_store = match config.session.store {
+        StoreKind::Sqlite => AnySessionStore::Sqlite(SqliteSessionStore::new(async_conn.clone()).with_ttl(ttl).with_sliding_expiry(sliding)),
+        StoreKind::Memory => AnySessionStore::Memory(MemorySessionStore::new().with_ttl(ttl).with_sliding_expiry(sliding)),
+        StoreKind::Nats => {
+            // NATS forgets keys that weren't written for this long so it has to cover remember me sessions too
+            let max_age = ttl.max(config.session.remember_ttl);
+            let store = NatsSessionStore::connect(nats.clone(), &config.session.nats_bucket, max_age)
+                .await
+                .expect("Could not open NATS session bucket");
+            AnySessionStore::Nats(store.with_ttl(ttl).with_sliding_expiry(sliding))
+        },
+    };
+    tracing::info!("Using {:?} session store", config.session.store);
+    // New sessions don't get an expiry so the cookie is gone when the browser closes.
+    // The store still expires them after the ttl. Remember me sets a longer expiry at login.
+    let session_layer = SessionLayer::new(session_store.clone(), &secret)
+        .with_cookie_name(&config.session.cookie_name)
+        .with_cookie_path(&config.session.cookie_path)
+        .with_secure(config.session.cookie_secure)
+        .with_same_site_policy(config.session.cookie_same_site)
+        .with_session_ttl(None);
+    let session_layer = match &config.session.cookie_domain {
+        Some(domain) => session_layer.with_cookie_domain(domain),
+        None => session_layer,
+    };
+    if config.session.cookie_same_site == SameSite::None && !config.session.cookie_secure {
+        warn!("SESSION_COOKIE_SAME_SITE=none without SESSION_COOKIE_SECURE=true. Browsers will reject the session cookie.");
+    }
+
+    // Spawn new task to periodically remove expired sessions
+    let cleanup_interval = config.session.cleanup_interval;
+    let cleanup_store = session_store.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(cleanup_interval);
+        loop {
+            interval.tick().await;
+            match cleanup_store.cleanup().await {
+                Ok(0) => (),
+                Ok(count) => tracing::info!("Removed {} expired sessions", count),
+                Err(err) => tracing::error!("Session cleanup err: {:?}", err),
+            }
+        }
+    });
+    // Background health checks that keep service status up to date
+    prober::spawn(async_conn.clone(), config.prober.clone());
+    // Services can also report in themselves over the fixer's NATS bus
+    heartbeat::spawn(nats.clone(), async_conn.clone(), config.heartbeat.clone());
+
+    let user_store = RusqliteStore::<User, UserMapper, Role>::new(async_conn.clone());
     let auth_layer = AuthLayer::new(user_store, &secret);
 
     // combine the frontend and backend routers to create the full app
     // routes are setup in ./routes/mod.rs
     let app = Router::new()
         .merge(routes::frontend())
-        .merge(routes::backend(session_layer, auth_layer, async_conn.clone()));
+        .merge(routes::backend(session_layer, auth_layer, async_conn.clone(), config, setup, session_store, webauthn));
 
     tracing::info!("listening on http://{}", addr);
 
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        // connect info is used to track failed logins by IP
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
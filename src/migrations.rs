@@ -17,6 +17,104 @@ lazy_static! {
             // services
             M::up("CREATE TABLE services(name TEXT PRIMARY KEY, server TEXT, status INTEGER);")
             .down("DROP TABLE services;"),
+            // user roles used for authorization. The existing admin (id 1) keeps full access
+            M::up("ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'viewer'; UPDATE users SET role = 'admin' WHERE id = 1;")
+            .down("ALTER TABLE users DROP COLUMN role;"),
+            // usernames must be unique. Existing duplicates are renamed to `name_id` before adding the index
+            M::up("UPDATE users SET name = name || '_' || id WHERE id NOT IN (SELECT MIN(id) FROM users GROUP BY name); CREATE UNIQUE INDEX users_name ON users(name);")
+            .down("DROP INDEX users_name;"),
+            // disabled users can't log in
+            M::up("ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;")
+            .down("ALTER TABLE users DROP COLUMN disabled;"),
+            // sessions keep track of the user they belong to so they can be destroyed together
+            M::up("ALTER TABLE sessions ADD COLUMN user_id INTEGER; CREATE INDEX sessions_user_id ON sessions(user_id);")
+            .down("DROP INDEX sessions_user_id; ALTER TABLE sessions DROP COLUMN user_id;"),
+            // failed login counters keyed by username or IP. Implementation in lockout.rs
+            M::up("CREATE TABLE login_failures(key TEXT PRIMARY KEY, failures INTEGER NOT NULL, last_failure INTEGER NOT NULL, locked_until INTEGER);")
+            .down("DROP TABLE login_failures;"),
+            // TOTP secrets and hashed recovery codes. Implementation in totp.rs
+            M::up("CREATE TABLE totp(user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE, secret TEXT NOT NULL, enabled INTEGER NOT NULL DEFAULT 0, last_step INTEGER);
+                   CREATE TABLE recovery_codes(user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE, hash TEXT NOT NULL, used_at INTEGER, PRIMARY KEY(user_id, hash));")
+            .down("DROP TABLE recovery_codes; DROP TABLE totp;"),
+            // hashed api tokens replace the plain text tokens table. Implementation in token.rs
+            // Old tokens can't be hashed in sql so they have to be minted again.
+            M::up("CREATE TABLE api_tokens(id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, prefix TEXT NOT NULL, hash TEXT NOT NULL UNIQUE,
+                   user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE, scopes TEXT NOT NULL DEFAULT '',
+                   created_at INTEGER NOT NULL DEFAULT (unixepoch()), expires_at INTEGER, last_used_at INTEGER);
+                   DROP TABLE tokens;")
+            .down("CREATE TABLE tokens(id TEXT PRIMARY KEY); DROP TABLE api_tokens;"),
+            // session expiry so old sessions can be cleaned up. Existing sessions get one more day.
+            M::up("ALTER TABLE sessions ADD COLUMN expires_at INTEGER; UPDATE sessions SET expires_at = unixepoch() + 86400; CREATE INDEX sessions_expires_at ON sessions(expires_at);")
+            .down("DROP INDEX sessions_expires_at; ALTER TABLE sessions DROP COLUMN expires_at;"),
+            // session details shown when users list their sessions
+            M::up("ALTER TABLE sessions ADD COLUMN created_at INTEGER; ALTER TABLE sessions ADD COLUMN last_seen INTEGER;
+                   ALTER TABLE sessions ADD COLUMN ip TEXT; ALTER TABLE sessions ADD COLUMN user_agent TEXT;")
+            .down("ALTER TABLE sessions DROP COLUMN user_agent; ALTER TABLE sessions DROP COLUMN ip;
+                   ALTER TABLE sessions DROP COLUMN last_seen; ALTER TABLE sessions DROP COLUMN created_at;"),
+            // audit trail of security relevant actions. Implementation in audit.rs
+            M::up("CREATE TABLE audit(id INTEGER PRIMARY KEY AUTOINCREMENT, ts INTEGER NOT NULL DEFAULT (unixepoch()), action TEXT NOT NULL,
+                   actor_kind TEXT NOT NULL, actor_id INTEGER, actor_name TEXT, target TEXT, ip TEXT, details TEXT);
+                   CREATE INDEX audit_ts ON audit(ts); CREATE INDEX audit_action ON audit(action); CREATE INDEX audit_actor_name ON audit(actor_name);")
+            .down("DROP TABLE audit;"),
+            // identities from the single sign on provider linked to users. Implementation in oidc.rs
+            M::up("CREATE TABLE oidc_identities(issuer TEXT NOT NULL, subject TEXT NOT NULL, user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                   created_at INTEGER NOT NULL DEFAULT (unixepoch()), PRIMARY KEY(issuer, subject));
+                   CREATE INDEX oidc_identities_user_id ON oidc_identities(user_id);")
+            .down("DROP TABLE oidc_identities;"),
+            // single use invites that let someone pick their own username and password. Implementation in invite.rs
+            M::up("CREATE TABLE invites(id INTEGER PRIMARY KEY AUTOINCREMENT, prefix TEXT NOT NULL, hash TEXT NOT NULL UNIQUE, role TEXT NOT NULL,
+                   created_by INTEGER REFERENCES users(id) ON DELETE SET NULL, created_at INTEGER NOT NULL DEFAULT (unixepoch()), expires_at INTEGER NOT NULL,
+                   used_at INTEGER, used_by INTEGER REFERENCES users(id) ON DELETE SET NULL, revoked_at INTEGER);")
+            .down("DROP TABLE invites;"),
+            // non interactive accounts for automation that only use API tokens
+            M::up("ALTER TABLE users ADD COLUMN service_account INTEGER NOT NULL DEFAULT 0;")
+            .down("ALTER TABLE users DROP COLUMN service_account;"),
+            // passkeys registered by users. Implementation in passkey.rs
+            M::up("CREATE TABLE passkeys(id INTEGER PRIMARY KEY AUTOINCREMENT, user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                   credential_id TEXT NOT NULL UNIQUE, name TEXT NOT NULL, passkey TEXT NOT NULL,
+                   created_at INTEGER NOT NULL DEFAULT (unixepoch()), last_used_at INTEGER);
+                   CREATE INDEX passkeys_user_id ON passkeys(user_id);")
+            .down("DROP TABLE passkeys;"),
+            // services get a named status instead of a number and a history of every status change.
+            // sqlite can't change a column type so the table is rebuilt. 1 was up and 0 was down.
+            M::up("CREATE TABLE services_new(name TEXT PRIMARY KEY, server TEXT, status TEXT NOT NULL DEFAULT 'unknown');
+                   INSERT INTO services_new (name, server, status)
+                       SELECT name, server, CASE status WHEN 1 THEN 'up' WHEN 0 THEN 'down' ELSE 'unknown' END FROM services;
+                   DROP TABLE services;
+                   ALTER TABLE services_new RENAME TO services;
+                   CREATE TABLE service_status_history(id INTEGER PRIMARY KEY AUTOINCREMENT,
+                       service TEXT NOT NULL REFERENCES services(name) ON UPDATE CASCADE ON DELETE CASCADE,
+                       ts INTEGER NOT NULL DEFAULT (unixepoch()), from_status TEXT, to_status TEXT NOT NULL, source TEXT NOT NULL, reason TEXT);
+                   CREATE INDEX service_status_history_service ON service_status_history(service, ts);")
+            .down("DROP TABLE service_status_history;
+                   CREATE TABLE services_old(name TEXT PRIMARY KEY, server TEXT, status INTEGER);
+                   INSERT INTO services_old (name, server, status)
+                       SELECT name, server, CASE status WHEN 'up' THEN 1 WHEN 'down' THEN 0 ELSE NULL END FROM services;
+                   DROP TABLE services;
+                   ALTER TABLE services_old RENAME TO services;"),
+            // health checks the prober runs against services. Implementation in prober.rs
+            M::up("CREATE TABLE service_checks(service TEXT PRIMARY KEY REFERENCES services(name) ON UPDATE CASCADE ON DELETE CASCADE,
+                   kind TEXT NOT NULL, target TEXT NOT NULL, expect_status INTEGER, body_contains TEXT, tls_expiry_days INTEGER,
+                   interval_secs INTEGER NOT NULL, timeout_secs INTEGER NOT NULL, failure_threshold INTEGER NOT NULL, enabled INTEGER NOT NULL DEFAULT 1,
+                   consecutive_failures INTEGER NOT NULL DEFAULT 0, last_checked_at INTEGER, last_error TEXT, tls_expires_at INTEGER);")
+            .down("DROP TABLE service_checks;"),
+            // last heartbeat seen from each service. Implementation in heartbeat.rs
+            M::up("CREATE TABLE service_heartbeats(service TEXT PRIMARY KEY REFERENCES services(name) ON UPDATE CASCADE ON DELETE CASCADE,
+                   last_seen_at INTEGER NOT NULL, interval_secs INTEGER NOT NULL);")
+            .down("DROP TABLE service_heartbeats;"),
+            // edges of the service graph, `service` depends on `depends_on`. Implementation in dependency.rs
+            M::up("CREATE TABLE service_dependencies(service TEXT NOT NULL REFERENCES services(name) ON UPDATE CASCADE ON DELETE CASCADE,
+                   depends_on TEXT NOT NULL REFERENCES services(name) ON UPDATE CASCADE ON DELETE CASCADE, kind TEXT NOT NULL,
+                   created_at INTEGER NOT NULL DEFAULT (unixepoch()), PRIMARY KEY(service, depends_on), CHECK(service != depends_on));
+                   CREATE INDEX service_dependencies_depends_on ON service_dependencies(depends_on);")
+            .down("DROP TABLE service_dependencies;"),
+            // status heartbeats last gave a service. Missed heartbeats don't overrule a status someone else set since.
+            M::up("ALTER TABLE service_heartbeats ADD COLUMN status TEXT;")
+            .down("ALTER TABLE service_heartbeats DROP COLUMN status;"),
+            // marks the token added from API_TOKEN so changing the variable only revokes that one.
+            // It used to be found by its name, which anyone could give their own token.
+            M::up("ALTER TABLE api_tokens ADD COLUMN from_env INTEGER NOT NULL DEFAULT 0;")
+            .down("ALTER TABLE api_tokens DROP COLUMN from_env;"),
         ]);
 }
 
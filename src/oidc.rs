@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Context, Result};
+use openidconnect::{
+    core::{CoreAuthenticationFlow, CoreClient, CoreIdTokenClaims, CoreProviderMetadata},
+    reqwest::async_http_client,
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, Scope, TokenResponse,
+};
+use rusqlite::{params, OptionalExtension};
+use tokio::sync::OnceCell;
+use tokio_rusqlite::Connection;
+
+use crate::{config::env_or, user::Role};
+
+/// Settings for single sign on with an OpenID Connect provider
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// Issuer url of the provider. Discovery is done from `<issuer>/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    /// Not needed for public clients since PKCE is always used
+    pub client_secret: Option<String>,
+    /// Where the provider sends the user back to. Has to point at `/auth/oidc/callback`
+    pub redirect_url: String,
+    /// Create a user the first time someone logs in with an unknown identity
+    pub auto_provision: bool,
+    /// Role given to auto provisioned users
+    pub default_role: Role,
+}
+
+impl OidcConfig {
+    /// Read the settings from environmental variables.
+    /// SSO is off unless OIDC_ISSUER_URL and OIDC_CLIENT_ID are both set.
+    pub fn from_env() -> Option<Self> {
+        let issuer = std::env::var("OIDC_ISSUER_URL").ok()?;
+        let client_id = std::env::var("OIDC_CLIENT_ID").ok()?;
+        Some(Self {
+            issuer,
+            client_id,
+            client_secret: std::env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_url: env_or("OIDC_REDIRECT_URL", "http://localhost:8080/auth/oidc/callback".to_string()),
+            auto_provision: env_or("OIDC_AUTO_PROVISION", false),
+            default_role: env_or("OIDC_DEFAULT_ROLE", Role::Viewer),
+        })
+    }
+}
+
+/// What has to be remembered between sending the user to the provider and the callback
+#[derive(Debug, Clone)]
+pub struct AuthRequest {
+    pub url: String,
+    pub state: String,
+    pub nonce: String,
+    pub pkce_verifier: String,
+}
+
+/// Identity taken from a verified ID token
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub issuer: String,
+    pub subject: String,
+    /// Name to use if a user gets provisioned. preferred_username, then email, then the subject.
+    pub username: String,
+}
+
+/// OpenID Connect client. Discovery runs on first use so the backend
+/// still starts when the provider is down.
+pub struct Oidc {
+    pub config: OidcConfig,
+    client: OnceCell<CoreClient>,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Self {
+        Self { config, client: OnceCell::new() }
+    }
+
+    async fn client(&self) -> Result<&CoreClient> {
+        self.client
+            .get_or_try_init(|| async {
+                let issuer = IssuerUrl::new(self.config.issuer.clone()).context("Invalid OIDC issuer url")?;
+                let metadata = CoreProviderMetadata::discover_async(issuer, async_http_client)
+                    .await
+                    .context("OIDC discovery failed")?;
+                let redirect = RedirectUrl::new(self.config.redirect_url.clone()).context("Invalid OIDC redirect url")?;
+                Ok(CoreClient::from_provider_metadata(
+                    metadata,
+                    ClientId::new(self.config.client_id.clone()),
+                    self.config.client_secret.clone().map(ClientSecret::new),
+                )
+                .set_redirect_uri(redirect))
+            })
+            .await
+    }
+
+    /// Build the url to send the user to along with the state, nonce and PKCE verifier for the callback
+    pub async fn authorize(&self) -> Result<AuthRequest> {
+        let client = self.client().await?;
+        let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+        let (url, state, nonce) = client
+            .authorize_url(CoreAuthenticationFlow::AuthorizationCode, CsrfToken::new_random, Nonce::new_random)
+            .add_scope(Scope::new("email".to_string()))
+            .add_scope(Scope::new("profile".to_string()))
+            .set_pkce_challenge(challenge)
+            .url();
+        Ok(AuthRequest {
+            url: url.to_string(),
+            state: state.secret().clone(),
+            nonce: nonce.secret().clone(),
+            pkce_verifier: verifier.secret().clone(),
+        })
+    }
+
+    /// Trade the code from the callback for tokens and verify the ID token
+    pub async fn exchange(&self, code: String, nonce: String, pkce_verifier: String) -> Result<Identity> {
+        let client = self.client().await?;
+        let response = client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier))
+            .request_async(async_http_client)
+            .await
+            .map_err(|err| anyhow!("OIDC code exchange failed: {:?}", err))?;
+        let id_token = response.id_token().ok_or_else(|| anyhow!("OIDC provider did not return an ID token"))?;
+        let claims = id_token
+            .claims(&client.id_token_verifier(), &Nonce::new(nonce))
+            .map_err(|err| anyhow!("OIDC ID token invalid: {:?}", err))?;
+        Ok(identity(claims))
+    }
+}
+
+fn identity(claims: &CoreIdTokenClaims) -> Identity {
+    let subject = claims.subject().to_string();
+    let username = claims
+        .preferred_username()
+        .map(|name| name.to_string())
+        .or_else(|| claims.email().map(|email| email.to_string()))
+        .unwrap_or_else(|| subject.clone());
+    Identity {
+        issuer: claims.issuer().to_string(),
+        subject,
+        username,
+    }
+}
+
+/// Get the id of the user linked to an identity
+pub async fn find_user(conn: &Connection, issuer: String, subject: String) -> Result<Option<i64>, rusqlite::Error> {
+    conn.call(move |conn| {
+        conn.query_row(
+            "SELECT user_id FROM oidc_identities WHERE issuer = ?1 AND subject = ?2",
+            params![issuer, subject],
+            |row| row.get(0),
+        )
+        .optional()
+    })
+    .await
+}
+
+/// Link an identity to a user. Fails if the identity already belongs to someone.
+pub async fn link(conn: &Connection, issuer: String, subject: String, user_id: i64) -> Result<usize, rusqlite::Error> {
+    conn.call(move |conn| {
+        conn.execute(
+            "INSERT INTO oidc_identities(issuer, subject, user_id) VALUES (?1, ?2, ?3)",
+            params![issuer, subject, user_id],
+        )
+    })
+    .await
+}
+
+/// Remove every identity linked to a user
+pub async fn unlink(conn: &Connection, user_id: i64) -> Result<usize, rusqlite::Error> {
+    conn.call(move |conn| conn.execute("DELETE FROM oidc_identities WHERE user_id = ?1", params![user_id]))
+        .await
+}
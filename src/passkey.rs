@@ -0,0 +1,204 @@
+use anyhow::{Context, Result};
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+use tokio_rusqlite::Connection;
+use webauthn_rs::prelude::{AuthenticationResult, CredentialID, Passkey, Url, Uuid, Webauthn, WebauthnBuilder};
+
+use crate::config::env_or;
+
+/// Settings for the relying party passkeys are registered with.
+/// Passkeys only work on the origin they were registered on so these have to match the public url.
+#[derive(Debug, Clone)]
+pub struct PasskeyConfig {
+    /// Domain of the site, like `cyberdeck.example.com`. Has to be the origin's host or a parent of it.
+    pub rp_id: String,
+    /// Full origin the browser sees, like `https://cyberdeck.example.com`
+    pub rp_origin: String,
+    /// Name shown by the browser and authenticator
+    pub rp_name: String,
+}
+
+impl Default for PasskeyConfig {
+    fn default() -> Self {
+        Self {
+            rp_id: "localhost".to_string(),
+            rp_origin: "http://localhost:8080".to_string(),
+            rp_name: "Cyberdeck".to_string(),
+        }
+    }
+}
+
+impl PasskeyConfig {
+    /// Read the settings from environmental variables
+    pub fn from_env() -> Self {
+        let config = Self::default();
+        Self {
+            rp_id: env_or("WEBAUTHN_RP_ID", config.rp_id),
+            rp_origin: env_or("WEBAUTHN_RP_ORIGIN", config.rp_origin),
+            rp_name: env_or("WEBAUTHN_RP_NAME", config.rp_name),
+        }
+    }
+}
+
+/// Build the WebAuthn relying party from the config
+pub fn webauthn(config: &PasskeyConfig) -> Result<Webauthn> {
+    let origin = Url::parse(&config.rp_origin).context("Invalid WEBAUTHN_RP_ORIGIN")?;
+    WebauthnBuilder::new(&config.rp_id, &origin)
+        .context("WEBAUTHN_RP_ID does not match WEBAUTHN_RP_ORIGIN")?
+        .rp_name(&config.rp_name)
+        .build()
+        .context("Could not set up WebAuthn")
+}
+
+/// Stable WebAuthn user handle for a user id
+pub fn user_handle(user_id: i64) -> Uuid {
+    Uuid::from_u128(user_id as u128)
+}
+
+/// Credential ids are stored hex encoded so they can be looked up
+fn credential_key(id: &CredentialID) -> String {
+    hex::encode(&id.0)
+}
+
+/// A registered passkey as shown to its owner. The credential itself is never sent back.
+#[derive(Debug, Clone, Serialize)]
+pub struct PasskeyInfo {
+    pub id: i64,
+    pub name: String,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+/// Every passkey of a user, ready to start an authentication or exclude from a registration
+pub async fn load(conn: &Connection, user_id: i64) -> Result<Vec<Passkey>> {
+    let stored = conn
+        .call(move |conn| {
+            let mut stmt = conn.prepare("SELECT passkey FROM passkeys WHERE user_id = ?1 ORDER BY id")?;
+            let passkeys = stmt
+                .query_map(params![user_id], |row| row.get::<_, String>(0))?
+                .collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
+            Ok::<_, rusqlite::Error>(passkeys)
+        })
+        .await?;
+    stored
+        .iter()
+        .map(|passkey| serde_json::from_str(passkey).context("Stored passkey is corrupt"))
+        .collect()
+}
+
+/// List the passkeys of a user
+pub async fn list(conn: &Connection, user_id: i64) -> Result<Vec<PasskeyInfo>, rusqlite::Error> {
+    conn.call(move |conn| {
+        let mut stmt = conn.prepare("SELECT id, name, created_at, last_used_at FROM passkeys WHERE user_id = ?1 ORDER BY id")?;
+        let passkeys = stmt
+            .query_map(params![user_id], |row| {
+                Ok(PasskeyInfo {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    created_at: row.get(2)?,
+                    last_used_at: row.get(3)?,
+                })
+            })?
+            .collect::<std::result::Result<Vec<PasskeyInfo>, rusqlite::Error>>()?;
+        Ok::<_, rusqlite::Error>(passkeys)
+    })
+    .await
+}
+
+/// Store a newly registered passkey. The credential id and public key are kept in the
+/// serialized passkey, the id is also stored on its own so it can be looked up.
+pub async fn add(conn: &Connection, user_id: i64, name: String, passkey: &Passkey) -> Result<i64, rusqlite::Error> {
+    let credential_id = credential_key(passkey.cred_id());
+    let passkey = serde_json::to_string(passkey).map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+    conn.call(move |conn| {
+        conn.execute(
+            "INSERT INTO passkeys (user_id, credential_id, name, passkey) VALUES (?1, ?2, ?3, ?4)",
+            params![user_id, credential_id, name, passkey],
+        )?;
+        Ok::<_, rusqlite::Error>(conn.last_insert_rowid())
+    })
+    .await
+}
+
+/// Record a successful authentication. Keeps the signature counter up to date so a cloned
+/// authenticator can be spotted and returns the id of the passkey that was used.
+pub async fn used(conn: &Connection, user_id: i64, result: &AuthenticationResult) -> Result<Option<i64>> {
+    let credential_id = credential_key(result.cred_id());
+    let stored = conn
+        .call(move |conn| {
+            conn.query_row(
+                "SELECT id, passkey FROM passkeys WHERE user_id = ?1 AND credential_id = ?2",
+                params![user_id, credential_id],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()
+        })
+        .await?;
+    let Some((id, passkey)) = stored else {
+        return Ok(None);
+    };
+
+    let mut passkey: Passkey = serde_json::from_str(&passkey).context("Stored passkey is corrupt")?;
+    passkey.update_credential(result);
+    let passkey = serde_json::to_string(&passkey)?;
+    conn.call(move |conn| {
+        conn.execute(
+            "UPDATE passkeys SET passkey = ?1, last_used_at = unixepoch() WHERE id = ?2",
+            params![passkey, id],
+        )
+    })
+    .await?;
+    Ok(Some(id))
+}
+
+/// Remove a passkey of a user. Returns how many were deleted.
+pub async fn delete(conn: &Connection, user_id: i64, id: i64) -> Result<usize, rusqlite::Error> {
+    conn.call(move |conn| conn.execute("DELETE FROM passkeys WHERE id = ?1 AND user_id = ?2", params![id, user_id]))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::MIGRATIONS;
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+
+    /// Register a passkey and log in with it using a software authenticator,
+    /// the same calls the routes make.
+    #[tokio::test]
+    async fn register_and_authenticate_with_soft_passkey() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        conn.call(|conn| conn.execute("INSERT INTO users (name, hash, role) VALUES ('alice', 'x', 'viewer')", []))
+            .await
+            .unwrap();
+        let user_id = 1;
+
+        let config = PasskeyConfig::default();
+        let webauthn = webauthn(&config).unwrap();
+        let origin = Url::parse(&config.rp_origin).unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+
+        // Registration
+        let (challenge, state) = webauthn
+            .start_passkey_registration(user_handle(user_id), "alice", "alice", None)
+            .unwrap();
+        let credential = authenticator.do_registration(origin.clone(), challenge).unwrap();
+        let passkey = webauthn.finish_passkey_registration(&credential, &state).unwrap();
+        let id = add(&conn, user_id, "laptop".to_string(), &passkey).await.unwrap();
+        assert_eq!(list(&conn, user_id).await.unwrap()[0].name, "laptop");
+
+        // Authentication with what was stored
+        let passkeys = load(&conn, user_id).await.unwrap();
+        let (challenge, state) = webauthn.start_passkey_authentication(&passkeys).unwrap();
+        let credential = authenticator.do_authentication(origin, challenge).unwrap();
+        let result = webauthn.finish_passkey_authentication(&credential, &state).unwrap();
+        assert_eq!(used(&conn, user_id, &result).await.unwrap(), Some(id));
+        assert!(list(&conn, user_id).await.unwrap()[0].last_used_at.is_some());
+
+        // Someone else can't claim the passkey
+        assert_eq!(used(&conn, user_id + 1, &result).await.unwrap(), None);
+        assert_eq!(delete(&conn, user_id, id).await.unwrap(), 1);
+        assert!(load(&conn, user_id).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+use std::{collections::HashSet, fmt, path::Path, sync::Arc};
+
+use serde::Serialize;
+
+use crate::config::env_or;
+
+/// Rules a new password has to follow. Checked everywhere a password is set or changed.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// Upper limit so nobody can make us hash megabytes of password
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// Reject passwords that contain the username
+    pub reject_username: bool,
+    /// Lowercased common or breached passwords that are never allowed
+    pub blocklist: Arc<HashSet<String>>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 10,
+            max_length: 256,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            reject_username: true,
+            blocklist: Arc::default(),
+        }
+    }
+}
+
+/// One way a password broke the policy. Serialized as `{"code": ..., "message": ...}`
+/// so the frontend can point at the exact problem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordError {
+    TooShort(usize),
+    TooLong(usize),
+    MissingLowercase,
+    MissingUppercase,
+    MissingDigit,
+    MissingSymbol,
+    ContainsUsername,
+    Common,
+}
+
+impl PasswordError {
+    pub const fn code(&self) -> &'static str {
+        match self {
+            Self::TooShort(_) => "too_short",
+            Self::TooLong(_) => "too_long",
+            Self::MissingLowercase => "missing_lowercase",
+            Self::MissingUppercase => "missing_uppercase",
+            Self::MissingDigit => "missing_digit",
+            Self::MissingSymbol => "missing_symbol",
+            Self::ContainsUsername => "contains_username",
+            Self::Common => "common",
+        }
+    }
+}
+
+impl fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort(min) => write!(f, "Password Must Be At Least {} Characters", min),
+            Self::TooLong(max) => write!(f, "Password Must Be At Most {} Characters", max),
+            Self::MissingLowercase => f.write_str("Password Must Contain A Lowercase Letter"),
+            Self::MissingUppercase => f.write_str("Password Must Contain An Uppercase Letter"),
+            Self::MissingDigit => f.write_str("Password Must Contain A Number"),
+            Self::MissingSymbol => f.write_str("Password Must Contain A Symbol"),
+            Self::ContainsUsername => f.write_str("Password Can Not Contain The Username"),
+            Self::Common => f.write_str("Password Is Too Common"),
+        }
+    }
+}
+
+impl Serialize for PasswordError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut error = serializer.serialize_struct("PasswordError", 2)?;
+        error.serialize_field("code", self.code())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.end()
+    }
+}
+
+impl PasswordPolicy {
+    /// Build the policy from environmental variables.
+    /// PASSWORD_BLOCKLIST points at a file with one password per line.
+    pub fn from_env() -> Self {
+        let policy = Self::default();
+        let blocklist = std::env::var("PASSWORD_BLOCKLIST")
+            .map(|path| load_blocklist(Path::new(&path)))
+            .unwrap_or_default();
+        Self {
+            min_length: env_or("PASSWORD_MIN_LENGTH", policy.min_length),
+            max_length: env_or("PASSWORD_MAX_LENGTH", policy.max_length),
+            require_lowercase: env_or("PASSWORD_REQUIRE_LOWERCASE", policy.require_lowercase),
+            require_uppercase: env_or("PASSWORD_REQUIRE_UPPERCASE", policy.require_uppercase),
+            require_digit: env_or("PASSWORD_REQUIRE_DIGIT", policy.require_digit),
+            require_symbol: env_or("PASSWORD_REQUIRE_SYMBOL", policy.require_symbol),
+            reject_username: env_or("PASSWORD_REJECT_USERNAME", policy.reject_username),
+            blocklist: Arc::new(blocklist),
+        }
+    }
+
+    /// Check a password for a user. Returns every rule it breaks, not just the first.
+    pub fn validate(&self, username: &str, password: &str) -> Result<(), Vec<PasswordError>> {
+        let mut errors = Vec::new();
+        let length = password.chars().count();
+        if length < self.min_length {
+            errors.push(PasswordError::TooShort(self.min_length));
+        }
+        if length > self.max_length {
+            errors.push(PasswordError::TooLong(self.max_length));
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            errors.push(PasswordError::MissingLowercase);
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            errors.push(PasswordError::MissingUppercase);
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            errors.push(PasswordError::MissingDigit);
+        }
+        if self.require_symbol && !password.chars().any(|c| !c.is_alphanumeric() && !c.is_whitespace()) {
+            errors.push(PasswordError::MissingSymbol);
+        }
+        let lower = password.to_lowercase();
+        let username = username.trim().to_lowercase();
+        if self.reject_username && !username.is_empty() && lower.contains(&username) {
+            errors.push(PasswordError::ContainsUsername);
+        }
+        if self.blocklist.contains(&lower) {
+            errors.push(PasswordError::Common);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Read a password list. A missing file only logs a warning so the server still starts.
+fn load_blocklist(path: &Path) -> HashSet<String> {
+    match std::fs::read_to_string(path) {
+        Ok(list) => {
+            let blocklist: HashSet<String> = list
+                .lines()
+                .map(|line| line.trim().to_lowercase())
+                .filter(|line| !line.is_empty())
+                .collect();
+            tracing::info!("Loaded {} blocked passwords from {}", blocklist.len(), path.display());
+            blocklist
+        },
+        Err(err) => {
+            tracing::warn!("Could not read password blocklist {}: {}", path.display(), err);
+            HashSet::new()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_broken_rule() {
+        let policy = PasswordPolicy {
+            require_uppercase: true,
+            require_digit: true,
+            blocklist: Arc::new(HashSet::from(["password".to_string()])),
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(policy.validate("alice", "Correct Horse 9"), Ok(()));
+        assert_eq!(
+            policy.validate("alice", "alice"),
+            Err(vec![PasswordError::TooShort(10), PasswordError::MissingUppercase, PasswordError::MissingDigit, PasswordError::ContainsUsername])
+        );
+        assert_eq!(policy.validate("bob", "PASSWORD"), Err(vec![PasswordError::TooShort(10), PasswordError::MissingDigit, PasswordError::Common]));
+    }
+}
@@ -0,0 +1,484 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::future::join_all;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpStream, time::MissedTickBehavior};
+use tokio_rusqlite::Connection;
+
+use crate::{
+    auth::unix_now,
+    config::{env_interval_or, env_or},
+    db_enum::db_enum,
+    services::{self, ServiceStatus},
+};
+
+/// Source recorded in the status history for changes made by the prober
+const SOURCE: &str = "prober";
+/// Most of a response body searched for `body_contains`. Health pages are small and
+/// a huge or endless body shouldn't be buffered.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Settings for the background prober
+#[derive(Debug, Clone)]
+pub struct ProberConfig {
+    pub enabled: bool,
+    /// How often the prober looks for checks that are due. Each check still runs on its own interval.
+    pub tick: Duration,
+}
+
+impl Default for ProberConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            tick: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ProberConfig {
+    /// Read the settings from environmental variables
+    pub fn from_env() -> Self {
+        let config = Self::default();
+        Self {
+            enabled: env_or("PROBER_ENABLED", config.enabled),
+            tick: env_interval_or("PROBER_TICK_SECS", config.tick),
+        }
+    }
+}
+
+/// How a service is checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckKind {
+    /// GET a url and check the status code and optionally the body
+    Http,
+    /// Open a TCP connection to `host:port`
+    Tcp,
+}
+
+db_enum!(CheckKind, "check kind", Http => "http", Tcp => "tcp");
+
+/// Health check for a service as set through the api
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckConfig {
+    pub kind: CheckKind,
+    /// Url for http checks, `host:port` for tcp checks
+    pub target: String,
+    /// Status code an http check expects. Defaults to 200.
+    pub expect_status: Option<u16>,
+    /// Text the body of an http check has to contain
+    pub body_contains: Option<String>,
+    /// Mark the service degraded when its https certificate expires within this many days
+    pub tls_expiry_days: Option<u32>,
+    #[serde(default = "default_interval")]
+    pub interval_secs: u32,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u32,
+    /// Failed checks in a row before the service is marked down
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+const fn default_interval() -> u32 {
+    60
+}
+
+const fn default_timeout() -> u32 {
+    10
+}
+
+const fn default_failure_threshold() -> u32 {
+    3
+}
+
+const fn default_enabled() -> bool {
+    true
+}
+
+impl CheckConfig {
+    /// Check the settings make sense before they are saved
+    pub fn validate(&self) -> Result<(), String> {
+        match self.kind {
+            CheckKind::Http => {
+                let url = reqwest::Url::parse(&self.target).map_err(|_| "Target Must Be A Valid Url".to_string())?;
+                if !matches!(url.scheme(), "http" | "https") {
+                    return Err("Target Must Be An http Or https Url".to_string());
+                }
+                if self.tls_expiry_days.is_some() && url.scheme() != "https" {
+                    return Err("TLS Expiry Can Only Be Checked On https Urls".to_string());
+                }
+                if self.expect_status.map_or(false, |status| !(100..=599).contains(&status)) {
+                    return Err("Expected Status Must Be Between 100 And 599".to_string());
+                }
+            },
+            CheckKind::Tcp => {
+                let port = self.target.rsplit_once(':').and_then(|(host, port)| (!host.is_empty()).then_some(port));
+                if port.and_then(|port| port.parse::<u16>().ok()).is_none() {
+                    return Err("Target Must Be host:port".to_string());
+                }
+                if self.expect_status.is_some() || self.body_contains.is_some() || self.tls_expiry_days.is_some() {
+                    return Err("Status, Body And TLS Options Only Work On http Checks".to_string());
+                }
+            },
+        }
+        if !(5..=86_400).contains(&self.interval_secs) {
+            return Err("Interval Must Be Between 5 Seconds And A Day".to_string());
+        }
+        if !(1..=60).contains(&self.timeout_secs) || self.timeout_secs > self.interval_secs {
+            return Err("Timeout Must Be Between 1 And 60 Seconds And Not Longer Than The Interval".to_string());
+        }
+        if !(1..=100).contains(&self.failure_threshold) {
+            return Err("Failure Threshold Must Be Between 1 And 100".to_string());
+        }
+        if self.tls_expiry_days.map_or(false, |days| !(1..=365).contains(&days)) {
+            return Err("TLS Expiry Days Must Be Between 1 And 365".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A saved check along with what the prober last saw
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceCheck {
+    pub service: String,
+    #[serde(flatten)]
+    pub config: CheckConfig,
+    pub consecutive_failures: u32,
+    pub last_checked_at: Option<i64>,
+    pub last_error: Option<String>,
+    /// When the https certificate expires, if the last check saw one
+    pub tls_expires_at: Option<i64>,
+}
+
+impl ServiceCheck {
+    fn map(row: &rusqlite::Row<'_>) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            service: row.get("service")?,
+            config: CheckConfig {
+                kind: row.get("kind")?,
+                target: row.get("target")?,
+                expect_status: row.get("expect_status")?,
+                body_contains: row.get("body_contains")?,
+                tls_expiry_days: row.get("tls_expiry_days")?,
+                interval_secs: row.get("interval_secs")?,
+                timeout_secs: row.get("timeout_secs")?,
+                failure_threshold: row.get("failure_threshold")?,
+                enabled: row.get("enabled")?,
+            },
+            consecutive_failures: row.get("consecutive_failures")?,
+            last_checked_at: row.get("last_checked_at")?,
+            last_error: row.get("last_error")?,
+            tls_expires_at: row.get("tls_expires_at")?,
+        })
+    }
+}
+
+/// What one run of a check found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Probe {
+    pub result: Result<(), String>,
+    pub tls_expires_at: Option<i64>,
+}
+
+/// Decide the new status after a probe. Returns the status to set, if any, with the reason
+/// and the new count of failures in a row. Failures only mark the service down once the
+/// threshold is reached so a single blip doesn't flap the status.
+pub fn next_status(config: &CheckConfig, probe: &Probe, failures: u32, now: i64) -> (Option<(ServiceStatus, String)>, u32) {
+    match &probe.result {
+        Ok(()) => {
+            let expiring = config
+                .tls_expiry_days
+                .zip(probe.tls_expires_at)
+                .filter(|(days, expires_at)| *expires_at - now < i64::from(*days) * 24 * 60 * 60);
+            let status = match expiring {
+                Some((_, expires_at)) => (ServiceStatus::Degraded, format!("TLS certificate expires in {} days", (expires_at - now).max(0) / (24 * 60 * 60))),
+                None => (ServiceStatus::Up, "check passed".to_string()),
+            };
+            (Some(status), 0)
+        },
+        Err(err) => {
+            let failures = failures.saturating_add(1);
+            let status = (failures >= config.failure_threshold).then(|| (ServiceStatus::Down, format!("{} failed checks in a row: {}", failures, err)));
+            (status, failures)
+        },
+    }
+}
+
+/// Run a check once
+pub async fn probe(client: &reqwest::Client, config: &CheckConfig) -> Probe {
+    let timeout = Duration::from_secs(u64::from(config.timeout_secs));
+    match config.kind {
+        CheckKind::Http => probe_http(client, config, timeout).await,
+        CheckKind::Tcp => {
+            let result = match tokio::time::timeout(timeout, TcpStream::connect(&config.target)).await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(err)) => Err(format!("Could not connect: {}", err)),
+                Err(_) => Err(format!("Timed out after {}s", config.timeout_secs)),
+            };
+            Probe { result, tls_expires_at: None }
+        },
+    }
+}
+
+async fn probe_http(client: &reqwest::Client, config: &CheckConfig, timeout: Duration) -> Probe {
+    let response = match client.get(&config.target).timeout(timeout).send().await {
+        Ok(response) => response,
+        Err(err) => return Probe { result: Err(format!("Request failed: {}", err)), tls_expires_at: None },
+    };
+    // The certificate is looked at even if the rest of the check fails
+    let tls_expires_at = response
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()
+        .and_then(|info| info.peer_certificate())
+        .and_then(certificate_expiry);
+
+    let expected = config.expect_status.unwrap_or(200);
+    let status = response.status().as_u16();
+    let result = if status != expected {
+        Err(format!("Expected HTTP {} but got {}", expected, status))
+    } else if let Some(needle) = &config.body_contains {
+        match body_contains(response, needle).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(format!("The first {} bytes of the body did not contain the expected text", MAX_BODY_BYTES)),
+            Err(err) => Err(format!("Could not read body: {}", err)),
+        }
+    } else {
+        Ok(())
+    };
+    Probe { result, tls_expires_at }
+}
+
+/// Whether the first `MAX_BODY_BYTES` of a response body contain `needle`
+async fn body_contains(mut response: reqwest::Response, needle: &str) -> reqwest::Result<bool> {
+    let mut body = Vec::new();
+    while body.len() < MAX_BODY_BYTES {
+        let Some(chunk) = response.chunk().await? else {
+            break;
+        };
+        body.extend_from_slice(&chunk[..chunk.len().min(MAX_BODY_BYTES - body.len())]);
+    }
+    Ok(String::from_utf8_lossy(&body).contains(needle))
+}
+
+/// Client the prober makes http checks with. Redirects aren't followed so a check sees the
+/// status and certificate of its own target, not of wherever it sends the prober.
+fn http_client() -> reqwest::Result<reqwest::Client> {
+    // TLS info is needed to read certificate expiry
+    reqwest::Client::builder().tls_info(true).redirect(reqwest::redirect::Policy::none()).build()
+}
+
+/// Unix time a DER encoded certificate stops being valid
+fn certificate_expiry(der: &[u8]) -> Option<i64> {
+    x509_parser::parse_x509_certificate(der)
+        .ok()
+        .map(|(_, cert)| cert.validity().not_after.timestamp())
+}
+
+/// Save the check of a service, replacing any existing one. Starts over with a clean state
+/// so the new check runs on the next tick. Returns false if the service doesn't exist.
+pub async fn save(conn: &Connection, service: String, config: CheckConfig) -> Result<bool, rusqlite::Error> {
+    conn.call(move |conn| {
+        let saved = conn.execute(
+            "INSERT INTO service_checks (service, kind, target, expect_status, body_contains, tls_expiry_days, interval_secs, timeout_secs, failure_threshold, enabled)
+             SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10 WHERE EXISTS (SELECT 1 FROM services WHERE name = ?1)
+             ON CONFLICT(service) DO UPDATE SET kind=excluded.kind, target=excluded.target, expect_status=excluded.expect_status,
+                body_contains=excluded.body_contains, tls_expiry_days=excluded.tls_expiry_days, interval_secs=excluded.interval_secs,
+                timeout_secs=excluded.timeout_secs, failure_threshold=excluded.failure_threshold, enabled=excluded.enabled,
+                consecutive_failures=0, last_checked_at=NULL, last_error=NULL, tls_expires_at=NULL",
+            params![
+                service, config.kind, config.target, config.expect_status, config.body_contains, config.tls_expiry_days,
+                config.interval_secs, config.timeout_secs, config.failure_threshold, config.enabled
+            ],
+        )?;
+        Ok::<_, rusqlite::Error>(saved == 1)
+    })
+    .await
+}
+
+/// The check of a service, if it has one
+pub async fn load(conn: &Connection, service: String) -> Result<Option<ServiceCheck>, rusqlite::Error> {
+    conn.call(move |conn| {
+        conn.query_row("SELECT * FROM service_checks WHERE service = ?1", params![service], ServiceCheck::map)
+            .optional()
+    })
+    .await
+}
+
+/// Remove the check of a service. Its status is left as it was.
+pub async fn delete(conn: &Connection, service: String) -> Result<usize, rusqlite::Error> {
+    conn.call(move |conn| conn.execute("DELETE FROM service_checks WHERE service = ?1", params![service]))
+        .await
+}
+
+/// Enabled checks that haven't run within their interval
+async fn due(conn: &Connection, now: i64) -> Result<Vec<ServiceCheck>, rusqlite::Error> {
+    conn.call(move |conn| {
+        let mut stmt = conn.prepare(
+            "SELECT * FROM service_checks WHERE enabled = 1 AND (last_checked_at IS NULL OR last_checked_at + interval_secs <= ?1)",
+        )?;
+        let checks = stmt
+            .query_map(params![now], ServiceCheck::map)?
+            .collect::<std::result::Result<Vec<ServiceCheck>, rusqlite::Error>>()?;
+        Ok::<_, rusqlite::Error>(checks)
+    })
+    .await
+}
+
+/// Store the result of a probe and update the service status.
+/// Services in maintenance keep their status until someone takes them out of it.
+async fn record(conn: &Connection, check: ServiceCheck, probe: Probe) -> Result<(), rusqlite::Error> {
+    let now = unix_now();
+    let (status, failures) = next_status(&check.config, &probe, check.consecutive_failures, now);
+    conn.call(move |conn| {
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE service_checks SET consecutive_failures = ?1, last_checked_at = ?2, last_error = ?3, tls_expires_at = COALESCE(?4, tls_expires_at) WHERE service = ?5",
+            params![failures, now, probe.result.err(), probe.tls_expires_at, check.service],
+        )?;
+        let current: Option<ServiceStatus> = tx
+            .query_row("SELECT status FROM services WHERE name = ?1", params![check.service], |row| row.get(0))
+            .optional()?;
+        if let (Some((status, reason)), Some(current)) = (status, current) {
+            if current != ServiceStatus::Maintenance {
+                services::change_status(&tx, &check.service, status, SOURCE, Some(&reason))?;
+            }
+        }
+        tx.commit()
+    })
+    .await
+}
+
+/// Run every check that is due, all at the same time
+async fn run_due(conn: &Connection, client: &reqwest::Client) {
+    let checks = match due(conn, unix_now()).await {
+        Ok(checks) => checks,
+        Err(err) => {
+            tracing::error!("Prober db err: {:?}", err);
+            return;
+        },
+    };
+    join_all(checks.into_iter().map(|check| async move {
+        let probe = probe(client, &check.config).await;
+        if let Err(err) = &probe.result {
+            tracing::debug!("Check for {} failed: {}", check.service, err);
+        }
+        let service = check.service.clone();
+        if let Err(err) = record(conn, check, probe).await {
+            tracing::error!("Could not record check for {}: {:?}", service, err);
+        }
+    }))
+    .await;
+}
+
+/// Start the background prober
+pub fn spawn(conn: Connection, config: ProberConfig) {
+    if !config.enabled {
+        tracing::info!("Service prober is disabled");
+        return;
+    }
+    let client = match http_client() {
+        Ok(client) => client,
+        Err(err) => {
+            tracing::error!("Could not start service prober: {:?}", err);
+            return;
+        },
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.tick);
+        // A slow round of checks shouldn't cause a burst of rounds after it
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            run_due(&conn, &client).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn http_check() -> CheckConfig {
+        CheckConfig {
+            kind: CheckKind::Http,
+            target: "https://example.com/health".to_string(),
+            expect_status: None,
+            body_contains: None,
+            tls_expiry_days: Some(14),
+            interval_secs: default_interval(),
+            timeout_secs: default_timeout(),
+            failure_threshold: 3,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn failures_wait_for_the_threshold() {
+        let config = http_check();
+        let failed = Probe { result: Err("boom".to_string()), tls_expires_at: None };
+        assert_eq!(next_status(&config, &failed, 0, 0), (None, 1));
+        assert_eq!(next_status(&config, &failed, 1, 0), (None, 2));
+        let (status, failures) = next_status(&config, &failed, 2, 0);
+        assert_eq!(status.map(|(status, _)| status), Some(ServiceStatus::Down));
+        assert_eq!(failures, 3);
+
+        // One success is enough to come back up
+        let passed = Probe { result: Ok(()), tls_expires_at: None };
+        let (status, failures) = next_status(&config, &passed, 3, 0);
+        assert_eq!(status.map(|(status, _)| status), Some(ServiceStatus::Up));
+        assert_eq!(failures, 0);
+    }
+
+    #[test]
+    fn expiring_certificate_degrades() {
+        let config = http_check();
+        let day = 24 * 60 * 60;
+        let expiring = Probe { result: Ok(()), tls_expires_at: Some(10 * day) };
+        let (status, _) = next_status(&config, &expiring, 0, 0);
+        assert_eq!(status, Some((ServiceStatus::Degraded, "TLS certificate expires in 10 days".to_string())));
+        let fine = Probe { result: Ok(()), tls_expires_at: Some(30 * day) };
+        assert_eq!(next_status(&config, &fine, 0, 0).0.map(|(status, _)| status), Some(ServiceStatus::Up));
+    }
+
+    #[test]
+    fn configs_are_validated() {
+        assert_eq!(http_check().validate(), Ok(()));
+        assert!(CheckConfig { target: "http://example.com".to_string(), ..http_check() }.validate().is_err());
+        assert!(CheckConfig { timeout_secs: 90, ..http_check() }.validate().is_err());
+        let tcp = CheckConfig { kind: CheckKind::Tcp, target: "db.local:5432".to_string(), tls_expiry_days: None, ..http_check() };
+        assert_eq!(tcp.validate(), Ok(()));
+        assert!(CheckConfig { target: "db.local".to_string(), ..tcp }.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn http_checks_stay_on_their_target() {
+        use axum::{response::Redirect, routing::get, Router};
+
+        let app = Router::new()
+            .route("/moved", get(|| async { Redirect::temporary("/health") }))
+            .route("/health", get(|| async { "ok" }))
+            .route("/big", get(|| async { format!("{}ok", "a".repeat(MAX_BODY_BYTES)) }));
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let base = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let client = http_client().unwrap();
+        let check = |path: &str| CheckConfig {
+            target: format!("{}{}", base, path),
+            body_contains: Some("ok".to_string()),
+            tls_expiry_days: None,
+            ..http_check()
+        };
+        assert_eq!(probe(&client, &check("/health")).await.result, Ok(()));
+        // A redirect is the answer, it isn't followed
+        assert_eq!(probe(&client, &check("/moved")).await.result, Err("Expected HTTP 200 but got 307".to_string()));
+        let redirect = CheckConfig { expect_status: Some(307), body_contains: None, ..check("/moved") };
+        assert_eq!(probe(&client, &redirect).await.result, Ok(()));
+        // Text past the read limit isn't found
+        assert!(probe(&client, &check("/big")).await.result.is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use axum::{extract::{Query, State}, response::IntoResponse, Json};
+use rusqlite::params;
+use serde::Deserialize;
+use serde_json::json;
+use tokio_rusqlite::Connection;
+
+use crate::audit::AuditEntry;
+
+/// Largest page that can be requested
+const MAX_PER_PAGE: u32 = 500;
+
+/// Query the audit log, newest first. Every filter is optional.
+pub async fn get_audit(State(conn): State<Connection>, Query(filter): Query<AuditFilter>) -> impl IntoResponse {
+    let page = filter.page.unwrap_or(1).max(1);
+    let per_page = filter.per_page.unwrap_or(50).clamp(1, MAX_PER_PAGE);
+    let offset = (page - 1) * per_page;
+
+    let query = conn
+        .call(move |conn| {
+            // NULL parameters match everything. `login` also matches `login.success` and `login.failure`.
+            let filters = "(?1 IS NULL OR action = ?1 OR action LIKE ?1 || '.%')
+                AND (?2 IS NULL OR actor_name = ?2)
+                AND (?3 IS NULL OR actor_kind = ?3)
+                AND (?4 IS NULL OR target = ?4)
+                AND (?5 IS NULL OR ip = ?5)
+                AND (?6 IS NULL OR ts >= ?6)
+                AND (?7 IS NULL OR ts < ?7)";
+            let values = params![filter.action, filter.actor, filter.actor_kind, filter.target, filter.ip, filter.since, filter.until];
+
+            let total: i64 = conn.query_row(&format!("SELECT COUNT(*) FROM audit WHERE {}", filters), values, |row| row.get(0))?;
+            let mut stmt = conn.prepare(&format!("SELECT * FROM audit WHERE {} ORDER BY id DESC LIMIT ?8 OFFSET ?9", filters))?;
+            let entries = stmt
+                .query_map(
+                    params![filter.action, filter.actor, filter.actor_kind, filter.target, filter.ip, filter.since, filter.until, per_page, offset],
+                    AuditEntry::map,
+                )?
+                .collect::<std::result::Result<Vec<AuditEntry>, rusqlite::Error>>()?;
+            Ok::<_, rusqlite::Error>((total, entries))
+        })
+        .await;
+
+    match query {
+        Ok((total, entries)) => Json(json!({
+            "result": "ok",
+            "events": entries,
+            "page": page,
+            "per_page": per_page,
+            "total": total,
+        })),
+        Err(err) => {
+            tracing::error!("Audit fetch db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Getting Audit Log From DB"}))
+        },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditFilter {
+    action: Option<String>,
+    actor: Option<String>,
+    actor_kind: Option<String>,
+    target: Option<String>,
+    ip: Option<String>,
+    /// unix timestamp, inclusive
+    since: Option<i64>,
+    /// unix timestamp, exclusive
+    until: Option<i64>,
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
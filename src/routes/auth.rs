@@ -1,90 +1,569 @@
-use axum::{response::IntoResponse, Json, extract::State};
-use serde_json::json;
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, bail};
+use axum::{response::IntoResponse, Json, extract::{ConnectInfo, State}, Extension};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
 use serde::Deserialize;
-use argon2::{
-    password_hash::{PasswordHash, PasswordVerifier},
-    Argon2
-};
+use axum_login::{axum_sessions::SessionHandle, RusqliteUserMapper};
+use rusqlite::params;
 use tokio_rusqlite::Connection;
 
-use crate::{user::User, auth::AuthContext};
+use crate::{
+    audit::{self, AuditEvent},
+    user::{hash_password, needs_rehash, verify_password, Role, User, UserMapper},
+    auth::{unix_now, AuthContext},
+    config::Config,
+    csrf,
+    lockout::{self, LockoutPolicy},
+    routes::user::password_policy_error,
+    session_store::{AnySessionStore, UserSessions},
+    totp,
+};
+
+/// Session key holding the user that passed the password step and still needs a TOTP code
+const MFA_USER_KEY: &str = "mfa_user_id";
+/// Session key holding when the pending TOTP login expires
+const MFA_EXPIRES_KEY: &str = "mfa_expires";
+/// Session key holding whether the pending TOTP login asked to be remembered
+const MFA_REMEMBER_KEY: &str = "mfa_remember";
+/// Session key holding the old and upgraded password hash of the pending TOTP login.
+/// The upgrade is only stored once the code is right.
+const MFA_REHASH_KEY: &str = "mfa_rehash";
+/// Seconds a user has to enter their TOTP code after the password
+const MFA_TIMEOUT: i64 = 5 * 60;
+
+lazy_static! {
+    /// Hash checked when a username doesn't exist so failed logins take the same time either way
+    static ref DUMMY_HASH: String = hash_password("not a real password").expect("Could not make dummy hash");
+}
+
+/// route that hands out the CSRF token of the session.
+/// Every non GET request on a cookie authenticated route has to send it in the `X-CSRF-Token` header.
+pub async fn csrf_token(Extension(session): Extension<SessionHandle>) -> impl IntoResponse {
+    match csrf::session_token(&session).await {
+        Ok(token) => Json(json!({"result": "ok", "token": token})),
+        Err(err) => {
+            tracing::error!("Could not store CSRF token: {:?}", err);
+            Json(json!({"result": "error", "message": "Problem creating session"}))
+        },
+    }
+}
 
 /// route to handle log in
-pub async fn login(mut auth: AuthContext, State(conn): State<Connection>, Json(login): Json<Login>) -> impl IntoResponse {
-    tracing::info!("Login Attempt: {}", login.username);
+pub async fn login(
+    mut auth: AuthContext,
+    State(conn): State<Connection>,
+    Extension(config): Extension<Config>,
+    Extension(session): Extension<SessionHandle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(login): Json<Login>,
+) -> impl IntoResponse {
+    tracing::info!("Login Attempt: {} from {}", login.username, addr.ip());
+    let user_key = lockout::user_key(&login.username);
+    let ip_key = lockout::ip_key(&addr.ip());
+
+    // Don't check the password at all while the username or IP is locked out.
+    // Unknown usernames get locked too so this doesn't leak which users exist.
+    if is_locked(&conn, &login.username, addr, &user_key, &ip_key).await {
+        return Json(json!({"result": "error", "message": "Too Many Failed Attempts, Try Again Later"}));
+    }
+
     let name = login.username.clone();
     // get password hash from db
     let query = conn
         .call(move |conn| { 
             // Sql query
-            let mut stmt = conn.prepare("Select name, hash, id FROM users WHERE name = :name")?;
+            let mut stmt = conn.prepare("SELECT id, name, hash, role, disabled, service_account FROM users WHERE name = :name")?;
             // submit the query and get all the sessions
             let tokens = stmt
-                .query_map(&[(":name", &name)], |row| {
-                    // return the user struct
-                    Ok(User {
-                        name: row.get(0)?,
-                        hash: row.get(1)?,
-                        id: row.get(2)?,
-                    })
-                })?
+                .query_map(&[(":name", &name)], UserMapper::map)?
                 .collect::<std::result::Result<Vec<User>, rusqlite::Error>>()?;
             Ok::<_, rusqlite::Error>(tokens)
         })
-        .await.map_err(|_err|  Json(json!({"result": "error"})));
-    
-    match query {
+        .await;
+
+    let user = match query {
         // User Found
-        Ok(rows) => {
-            // Should always return just one row since username is the primary key
-            if rows.len() == 1 {
-                let user = &rows[0];
-                // Check the password against the hash
-                // This is from the argon2 docs
-                let parsed_hash = PasswordHash::new(&user.hash).unwrap();
-                if Argon2::default().verify_password(&login.password.as_bytes(), &parsed_hash).is_ok() {
-                    // Passwords match so login the user
-                    // @TODO Remove unwrap and properly check error
-                    auth.login(&user).await.unwrap();
-                    tracing::info!("current user: {:?}", &auth.current_user);
-                    if let Some(user) = &auth.current_user {
-                        Json(json!({
-                            "result": "ok",
-                            "user": user.name,                  
-                        }))
-                    } else {
-                        tracing::error!("User session not set: {}", &login.username);
-                        Json(json!({"result": "error", "message": "Problem creating session"}))
-                    }
-                } else {
-                    // Password didn't match password in database
-                    tracing::error!("Password incorrect for: {}", &login.username);
-                    Json(json!({"result": "error", "message": "Username or Password Wrong"}))
-                }
-            } else { 
-                // User not found
-                tracing::error!("User not found in DB: {}", &login.username);
-                Json(json!({"result": "error", "message": "Username or Password Wrong"})) 
+        // Should always return just one row since username is unique
+        Ok(rows) if rows.len() == 1 => {
+            let user = rows[0].clone();
+            // Check the password against the hash
+            if verify_password(&user.hash, &login.password) {
+                Some(user)
+            } else {
+                // Password didn't match password in database
+                tracing::error!("Password incorrect for: {}", &login.username);
+                None
             }
         },
+        Ok(_) => {
+            // User not found. Still hash the password so the response takes as long as for a real user.
+            tracing::error!("User not found in DB: {}", &login.username);
+            verify_password(&DUMMY_HASH, &login.password);
+            None
+        },
         Err(err) => {
             // Error looking user up in DB
             tracing::error!("Login DB Error: {:?}", err);
-            Json(json!({"result": "error", "message": "Username or Password Wrong"}))
+            return Json(json!({"result": "error", "message": "Username or Password Wrong"}));
+        },
+    };
+
+    let Some(mut user) = user else {
+        login_failed(&conn, &config.lockout, &login.username, addr, user_key, ip_key, "password").await;
+        return Json(json!({"result": "error", "message": "Username or Password Wrong"}));
+    };
+
+    if user.disabled {
+        tracing::error!("Login attempt for disabled user: {}", &login.username);
+        audit::record(&conn, AuditEvent::new("login.failure").anonymous(&user.name).ip(&addr).details(json!({"reason": "disabled"}))).await;
+        return Json(json!({"result": "error", "message": "Account Disabled"}));
+    }
+
+    // Service accounts only use API tokens, even if someone set a password on one
+    if user.service_account {
+        tracing::error!("Login attempt for service account: {}", &login.username);
+        audit::record(&conn, AuditEvent::new("login.failure").anonymous(&user.name).ip(&addr).details(json!({"reason": "service account"}))).await;
+        return Json(json!({"result": "error", "message": "Service Accounts Can Not Log In"}));
+    }
+
+    // We have the plain password right now so hash it again if it was made with weaker settings.
+    // It is only stored once the login is complete since it logs out the other sessions of the user.
+    let rehash = if needs_rehash(&user.hash) {
+        match hash_password(&login.password) {
+            Ok(hash) => Some((user.hash.clone(), hash)),
+            Err(err) => {
+                tracing::error!("Could not upgrade password hash for {}: {:?}", user.name, err);
+                None
+            },
+        }
+    } else {
+        None
+    };
+
+    // With TOTP enabled the password is only the first step. Remember who passed it
+    // in the session and wait for the code before actually logging in.
+    match totp::load(&conn, user.id).await {
+        Ok(Some(record)) if record.enabled => {
+            let mut session = session.write().await;
+            let pending = session
+                .insert(MFA_USER_KEY, user.id)
+                .and_then(|_| session.insert(MFA_EXPIRES_KEY, unix_now() + MFA_TIMEOUT))
+                .and_then(|_| session.insert(MFA_REMEMBER_KEY, login.remember))
+                .and_then(|_| match &rehash {
+                    Some(rehash) => session.insert(MFA_REHASH_KEY, rehash),
+                    // Don't keep one from an earlier attempt in this session
+                    None => {
+                        session.remove(MFA_REHASH_KEY);
+                        Ok(())
+                    },
+                });
+            if let Err(err) = pending {
+                tracing::error!("Could not store pending TOTP login: {:?}", err);
+                return Json(json!({"result": "error", "message": "Problem creating session"}));
+            }
+            tracing::info!("Waiting for TOTP code from: {}", &login.username);
+            return Json(json!({"result": "totp_required"}));
+        },
+        Ok(_) => (),
+        Err(err) => {
+            tracing::error!("TOTP DB Error: {:?}", err);
+            return Json(json!({"result": "error", "message": "Problem creating session"}));
+        },
+    }
+
+    if let Some((old, new)) = rehash {
+        store_rehash(&conn, &mut user, old, new).await;
+    }
+    if login.remember {
+        remember_session(&session, &config).await;
+    }
+    login_response(complete_login(&mut auth, &conn, &user, addr, user_key, "password").await)
+}
+
+/// route for the second login step when the user has TOTP enabled.
+/// Takes either a code from the authenticator app or a recovery code.
+pub async fn login_totp(
+    mut auth: AuthContext,
+    State(conn): State<Connection>,
+    Extension(config): Extension<Config>,
+    Extension(session): Extension<SessionHandle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(totp_login): Json<TotpLogin>,
+) -> impl IntoResponse {
+    // Get the user that passed the password step
+    let pending = {
+        let session = session.read().await;
+        session.get::<i64>(MFA_USER_KEY).zip(session.get::<i64>(MFA_EXPIRES_KEY))
+    };
+    let id = match pending {
+        Some((id, expires)) if expires > unix_now() => id,
+        _ => return Json(json!({"result": "error", "message": "Login Expired, Please Log In Again"})),
+    };
+
+    let query = conn
+        .call(move |conn| {
+            let mut stmt = conn.prepare("SELECT id, name, hash, role, disabled, service_account FROM users WHERE id = ?1")?;
+            let users = stmt
+                .query_map(params![id], UserMapper::map)?
+                .collect::<std::result::Result<Vec<User>, rusqlite::Error>>()?;
+            Ok::<_, rusqlite::Error>(users)
+        })
+        .await;
+    let mut user = match query {
+        Ok(rows) if rows.len() == 1 => rows[0].clone(),
+        Ok(_) => return Json(json!({"result": "error", "message": "Login Expired, Please Log In Again"})),
+        Err(err) => {
+            tracing::error!("Login DB Error: {:?}", err);
+            return Json(json!({"result": "error", "message": "Problem creating session"}));
+        },
+    };
+    tracing::info!("TOTP Login Attempt: {} from {}", user.name, addr.ip());
+
+    // Codes are brute forceable too so they count towards the lockout
+    let user_key = lockout::user_key(&user.name);
+    let ip_key = lockout::ip_key(&addr.ip());
+    if is_locked(&conn, &user.name, addr, &user_key, &ip_key).await {
+        return Json(json!({"result": "error", "message": "Too Many Failed Attempts, Try Again Later"}));
+    }
+
+    // The account could have changed since the password step. Check before the code so a
+    // recovery code isn't used up by a login that can't work anyway.
+    if user.disabled {
+        tracing::error!("Login attempt for disabled user: {}", user.name);
+        audit::record(&conn, AuditEvent::new("login.failure").anonymous(&user.name).ip(&addr).details(json!({"reason": "disabled"}))).await;
+        return Json(json!({"result": "error", "message": "Account Disabled"}));
+    }
+    if user.service_account {
+        tracing::error!("Login attempt for service account: {}", user.name);
+        audit::record(&conn, AuditEvent::new("login.failure").anonymous(&user.name).ip(&addr).details(json!({"reason": "service account"}))).await;
+        return Json(json!({"result": "error", "message": "Service Accounts Can Not Log In"}));
+    }
+
+    match totp::check_code(&conn, user.id, &user.name, totp_login.code).await {
+        Ok(true) => (),
+        Ok(false) => {
+            tracing::error!("TOTP code incorrect for: {}", user.name);
+            login_failed(&conn, &config.lockout, &user.name, addr, user_key, ip_key, "totp").await;
+            return Json(json!({"result": "error", "message": "Code Wrong"}));
+        },
+        Err(err) => {
+            tracing::error!("TOTP check error: {:?}", err);
+            return Json(json!({"result": "error", "message": "Problem creating session"}));
+        },
+    }
+
+    // The second step is done so the pending login isn't needed anymore
+    let (remember, rehash) = {
+        let mut session = session.write().await;
+        let remember = session.get::<bool>(MFA_REMEMBER_KEY).unwrap_or(false);
+        let rehash = session.get::<(String, String)>(MFA_REHASH_KEY);
+        session.remove(MFA_USER_KEY);
+        session.remove(MFA_EXPIRES_KEY);
+        session.remove(MFA_REMEMBER_KEY);
+        session.remove(MFA_REHASH_KEY);
+        (remember, rehash)
+    };
+    if let Some((old, new)) = rehash {
+        store_rehash(&conn, &mut user, old, new).await;
+    }
+    if remember {
+        remember_session(&session, &config).await;
+    }
+
+    login_response(complete_login(&mut auth, &conn, &user, addr, user_key, "totp").await)
+}
+
+/// Store a password hash made with the current Argon2 settings in place of `old`. Nothing is
+/// stored if the password was changed since `old` was read. The session auth id comes from the
+/// hash, so other sessions of the user are logged out like after a password change.
+async fn store_rehash(conn: &Connection, user: &mut User, old: String, new: String) {
+    let (id, hash) = (user.id, new.clone());
+    let query = conn
+        .call(move |conn| conn.execute("UPDATE users SET hash = ?1 WHERE id = ?2 AND hash = ?3", params![hash, id, old]))
+        .await;
+    match query {
+        Ok(0) => tracing::info!("Password of {} changed before its hash could be upgraded", user.name),
+        Ok(_) => {
+            tracing::info!("Upgraded password hash for: {}", user.name);
+            user.hash = new;
+        },
+        Err(err) => tracing::error!("Could not upgrade password hash for {}: {:?}", user.name, err),
+    }
+}
+
+/// Give the session its own expiry so the cookie outlives the browser.
+/// Sessions without one are only kept until the browser closes.
+pub async fn remember_session(session: &SessionHandle, config: &Config) {
+    session.write().await.expire_in(config.session.remember_ttl);
+}
+
+/// The user `complete_login` created a session for
+#[derive(Debug, Clone)]
+pub struct LoggedIn {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Create the session for a user that passed every login step.
+/// `method` is recorded in the audit log to tell how the user logged in.
+pub async fn complete_login(auth: &mut AuthContext, conn: &Connection, user: &User, addr: SocketAddr, user_key: String, method: &str) -> anyhow::Result<LoggedIn> {
+    // Login worked so forget previous failures for this user
+    if let Err(err) = lockout::clear(conn, user_key).await {
+        tracing::error!("Lockout DB Error: {:?}", err);
+    }
+
+    auth.login(user)
+        .await
+        .map_err(|err| anyhow!("Could not create session for {}: {:?}", user.name, err))?;
+    tracing::info!("current user: {:?}", &auth.current_user);
+    audit::record(conn, AuditEvent::new("login.success").user(user).ip(&addr).details(json!({"method": method}))).await;
+    match &auth.current_user {
+        Some(user) => Ok(LoggedIn { name: user.name.clone(), role: user.role }),
+        None => bail!("User session not set: {}", user.name),
+    }
+}
+
+/// Json response for the routes that log in with a json request
+pub fn login_response(login: anyhow::Result<LoggedIn>) -> Json<Value> {
+    match login {
+        Ok(user) => Json(json!({
+            "result": "ok",
+            "user": user.name,
+            "role": user.role,
+        })),
+        Err(err) => {
+            tracing::error!("Login error: {:?}", err);
+            Json(json!({"result": "error", "message": "Problem creating session"}))
+        },
+    }
+}
+
+/// Check if the username or IP is locked out and log the attempt if it is
+pub async fn is_locked(conn: &Connection, name: &str, addr: SocketAddr, user_key: &str, ip_key: &str) -> bool {
+    match lockout::locked_until(conn, vec![user_key.to_string(), ip_key.to_string()]).await {
+        Ok(None) => false,
+        Ok(Some(until)) => {
+            tracing::warn!("Login attempt for {} from {} while locked out until {}", name, addr.ip(), until);
+            audit::record(conn, AuditEvent::new("login.blocked").anonymous(name).ip(&addr).details(json!({"locked_until": until}))).await;
+            true
         },
+        Err(err) => {
+            // Fail closed if we can't tell
+            tracing::error!("Lockout DB Error: {:?}", err);
+            true
+        },
+    }
+}
+
+/// Count a failed login for the username and IP, log any lockout and
+/// slow down the response based on how many times it has failed.
+/// `step` is the login step that failed and is recorded in the audit log.
+#[allow(clippy::too_many_arguments)]
+pub async fn login_failed(conn: &Connection, policy: &LockoutPolicy, name: &str, addr: SocketAddr, user_key: String, ip_key: String, step: &str) {
+    audit::record(conn, AuditEvent::new("login.failure").anonymous(name).ip(&addr).details(json!({"step": step}))).await;
+    let mut failures = 0;
+    for (key, max_failures) in [(user_key, policy.max_failures), (ip_key, policy.max_failures_per_ip)] {
+        match lockout::record_failure(conn, key.clone(), max_failures, policy.lockout).await {
+            Ok((count, locked)) => {
+                if locked {
+                    tracing::warn!("Locked out {} after {} failed logins (user: {}, ip: {})", key, count, name, addr.ip());
+                    audit::record(conn, AuditEvent::new("login.lockout").anonymous(name).target(&key).ip(&addr).details(json!({"failures": count}))).await;
+                }
+                failures = failures.max(count);
+            },
+            Err(err) => tracing::error!("Lockout DB Error: {:?}", err),
+        }
     }
+    tokio::time::sleep(policy.delay(failures)).await;
 }
 
 /// route to handle log out
-pub async fn logout(mut auth: AuthContext) -> impl IntoResponse {
+pub async fn logout(
+    mut auth: AuthContext,
+    State(conn): State<Connection>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
     tracing::info!("Logging out user: {:?}", &auth.current_user);
+    if let Some(user) = &auth.current_user {
+        audit::record(&conn, AuditEvent::new("logout").user(user).ip(&addr)).await;
+    }
     // drop session
     auth.logout().await;
     Json(json!({"result": "ok"}))
 }
 
+/// route to change the password of the logged in user.
+/// Every other session of the user is destroyed so a leaked session stops working.
+#[allow(clippy::too_many_arguments)]
+pub async fn change_password(
+    mut auth: AuthContext,
+    State(conn): State<Connection>,
+    Extension(user): Extension<User>,
+    Extension(config): Extension<Config>,
+    Extension(session): Extension<SessionHandle>,
+    Extension(sessions): Extension<AnySessionStore>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(change): Json<ChangePassword>,
+) -> impl IntoResponse {
+    tracing::info!("Password change: {}", user.name);
+    // Check the current password against the hash the same way login does
+    if !verify_password(&user.hash, &change.current_password) {
+        tracing::error!("Current password incorrect for: {}", user.name);
+        return Json(json!({"result": "error", "message": "Current Password Wrong"}));
+    }
+    if let Err(errors) = config.password.validate(&user.name, &change.new_password) {
+        return password_policy_error(errors);
+    }
+
+    let hash = match hash_password(&change.new_password) {
+        Ok(hash) => hash,
+        Err(err) => {
+            tracing::error!("Could not hash password: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Changing Password"}));
+        }
+    };
+
+    // store the new hash
+    let id = user.id;
+    let new_hash = hash.clone();
+    if let Err(err) = conn
+        .call(move |conn| conn.execute("UPDATE users SET hash = ?1 WHERE id = ?2", params![new_hash, id]))
+        .await
+    {
+        tracing::error!("Password change db err: {:?}", err);
+        return Json(json!({"result": "error", "message": "Error Changing Password"}));
+    }
+
+    audit::record(&conn, AuditEvent::new("user.password_change").user(&user).target(user.id).ip(&addr)).await;
+
+    // The session is tied to the old hash so log in again to keep the current session valid
+    let user = User { hash, ..user };
+    if let Err(err) = auth.login(&user).await {
+        tracing::error!("Could not refresh session after password change: {:?}", err);
+    }
+
+    // Destroy every other session for this user
+    let current = session.read().await.id().to_string();
+    match sessions.destroy_user_sessions(user.id, Some(current)).await {
+        Ok(count) => {
+            tracing::info!("Destroyed {} other sessions for: {}", count, user.name);
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Session cleanup err: {:?}", err);
+            Json(json!({"result": "error", "message": "Password Changed But Other Sessions Could Not Be Closed"}))
+        },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChangePassword {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpLogin {
+    code: String,
+}
+
 #[derive(Deserialize)]
 pub struct Login {
     username: String,
     password: String,
+    /// Keep the session after the browser closes
+    #[serde(default)]
+    remember: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{password_hash::SaltString, Algorithm, Argon2, Params, PasswordHasher, Version};
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::routes::harness::TestApp;
+
+    const PASSWORD: &str = "a long enough password";
+    const RECOVERY_CODE: &str = "aaaa-bbbb-cccc";
+
+    /// Add a viewer with TOTP enabled and a single recovery code. The password is stored as `hash`.
+    async fn user_with_totp(app: &TestApp, hash: String) {
+        let secret = totp::generate("alice").unwrap().get_secret_base32();
+        let code = totp::hash_recovery_code(RECOVERY_CODE);
+        app.conn
+            .call(move |conn| {
+                conn.execute("INSERT INTO users (name, hash, role) VALUES ('alice', ?1, 'viewer')", params![hash])?;
+                let id = conn.last_insert_rowid();
+                conn.execute("INSERT INTO totp (user_id, secret, enabled) VALUES (?1, ?2, 1)", params![id, secret])?;
+                conn.execute("INSERT INTO recovery_codes (user_id, hash) VALUES (?1, ?2)", params![id, code])?;
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn stored_hash(app: &TestApp) -> String {
+        app.conn
+            .call(|conn| conn.query_row("SELECT hash FROM users WHERE name = 'alice'", [], |row| row.get(0)))
+            .await
+            .unwrap()
+    }
+
+    async fn set_disabled(app: &TestApp, disabled: bool) {
+        app.conn
+            .call(move |conn| conn.execute("UPDATE users SET disabled = ?1 WHERE name = 'alice'", params![disabled]))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn disabled_users_keep_their_recovery_codes() {
+        let mut app = TestApp::new(Config::default()).await;
+        user_with_totp(&app, hash_password(PASSWORD).unwrap()).await;
+        let response = app.post("/auth/login", json!({"username": "alice", "password": PASSWORD})).await;
+        assert_eq!(response.body["result"], "totp_required", "{}", response.body);
+
+        // Disabled between the two steps
+        set_disabled(&app, true).await;
+        let response = app.post("/auth/login/totp", json!({"code": RECOVERY_CODE})).await;
+        assert_eq!(response.body["message"], "Account Disabled");
+        let unused: i64 = app
+            .conn
+            .call(|conn| conn.query_row("SELECT COUNT(*) FROM recovery_codes WHERE used_at IS NULL", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(unused, 1);
+
+        // So the code still works once the account is back
+        set_disabled(&app, false).await;
+        let response = app.post("/auth/login/totp", json!({"code": RECOVERY_CODE})).await;
+        assert_eq!(response.body["result"], "ok", "{}", response.body);
+    }
+
+    #[tokio::test]
+    async fn weak_hashes_are_upgraded_after_the_code() {
+        let mut app = TestApp::new(Config::default()).await;
+        let weak = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(8, 1, 1, None).unwrap())
+            .hash_password(PASSWORD.as_bytes(), &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+        user_with_totp(&app, weak.clone()).await;
+
+        // Passing only the password or a wrong code keeps the old hash, so other sessions stay logged in
+        let response = app.post("/auth/login", json!({"username": "alice", "password": PASSWORD})).await;
+        assert_eq!(response.body["result"], "totp_required", "{}", response.body);
+        assert_eq!(stored_hash(&app).await, weak);
+        let response = app.post("/auth/login/totp", json!({"code": "000000"})).await;
+        assert_eq!(response.body["message"], "Code Wrong");
+        assert_eq!(stored_hash(&app).await, weak);
+
+        let response = app.post("/auth/login/totp", json!({"code": RECOVERY_CODE})).await;
+        assert_eq!(response.body["result"], "ok", "{}", response.body);
+        let upgraded = stored_hash(&app).await;
+        assert!(!needs_rehash(&upgraded));
+        assert!(verify_password(&upgraded, PASSWORD));
+    }
 }
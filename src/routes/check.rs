@@ -0,0 +1,128 @@
+use std::net::SocketAddr;
+
+use axum::{extract::{ConnectInfo, Path, State}, http::StatusCode, response::IntoResponse, Json, Extension};
+use serde_json::{json, Value};
+use tokio_rusqlite::Connection;
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::{Caller, JsonError},
+    prober::{self, CheckConfig},
+    token::Scope,
+    user::User,
+};
+
+/// Health check of a service with the last result the prober saw
+pub async fn get_check(
+    State(conn): State<Connection>,
+    Extension(_user): Extension<User>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    tracing::info!("Getting health check of service: {}", name);
+    load(&conn, name).await
+}
+
+/// Set or replace the health check of a service
+pub async fn put_check(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Json(check): Json<CheckConfig>,
+) -> impl IntoResponse {
+    tracing::info!("{} setting health check of service: {}", admin.name, name);
+    save(&conn, name, check, AuditEvent::new("service.check_update").user(&admin).ip(&addr)).await
+}
+
+/// Stop checking a service. Its status is left as it was.
+pub async fn delete_check(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    tracing::info!("{} removing health check of service: {}", admin.name, name);
+    delete(&conn, name, AuditEvent::new("service.check_delete").user(&admin).ip(&addr)).await
+}
+
+/// Health check of a service for API token callers with `services:read`
+pub async fn api_get_check(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesRead)?;
+    tracing::info!("Getting health check of {} for {} with token {}", name, caller.user_name, caller.token_id);
+    Ok(load(&conn, name).await)
+}
+
+/// Set the health check of a service for API token callers with `services:write`
+pub async fn api_put_check(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Json(check): Json<CheckConfig>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesWrite)?;
+    tracing::info!("Setting health check of {} for {} with token {}", name, caller.user_name, caller.token_id);
+    Ok(save(&conn, name, check, AuditEvent::new("service.check_update").caller(&caller).ip(&addr)).await)
+}
+
+/// Remove the health check of a service for API token callers with `services:write`
+pub async fn api_delete_check(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesWrite)?;
+    tracing::info!("Removing health check of {} for {} with token {}", name, caller.user_name, caller.token_id);
+    Ok(delete(&conn, name, AuditEvent::new("service.check_delete").caller(&caller).ip(&addr)).await)
+}
+
+/// Json response with the check of a service
+async fn load(conn: &Connection, name: String) -> Json<Value> {
+    match prober::load(conn, name).await {
+        Ok(Some(check)) => Json(json!({"result": "ok", "check": check})),
+        Ok(None) => Json(json!({"result": "error", "message": "Service Has No Health Check"})),
+        Err(err) => {
+            tracing::error!("Check fetch db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Getting Health Check From DB"}))
+        },
+    }
+}
+
+/// Validate and save a check. `event` already has the actor and is recorded on success.
+async fn save(conn: &Connection, name: String, check: CheckConfig, event: AuditEvent) -> Json<Value> {
+    if let Err(message) = check.validate() {
+        return Json(json!({"result": "error", "message": message}));
+    }
+    let details = json!(check);
+    match prober::save(conn, name.clone(), check).await {
+        Ok(false) => Json(json!({"result": "error", "message": "Service Not Found"})),
+        Ok(true) => {
+            audit::record(conn, event.target(&name).details(details)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Check save db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Saving Health Check"}))
+        },
+    }
+}
+
+/// Delete the check of a service
+async fn delete(conn: &Connection, name: String, event: AuditEvent) -> Json<Value> {
+    match prober::delete(conn, name.clone()).await {
+        Ok(0) => Json(json!({"result": "error", "message": "Service Has No Health Check"})),
+        Ok(_) => {
+            audit::record(conn, event.target(&name)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Check delete db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Removing Health Check"}))
+        },
+    }
+}
@@ -0,0 +1,172 @@
+use std::net::SocketAddr;
+
+use axum::{extract::{ConnectInfo, Path, State}, http::StatusCode, response::IntoResponse, Json, Extension};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_rusqlite::Connection;
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::{Caller, JsonError},
+    dependency::{self, DependencyKind, Outcome},
+    token::Scope,
+    user::User,
+};
+
+/// Every service as a node with the dependencies between them as edges
+pub async fn get_graph(State(conn): State<Connection>, Extension(_user): Extension<User>) -> impl IntoResponse {
+    tracing::info!("Getting service graph");
+    graph(&conn).await
+}
+
+/// Services that go down or are degraded if a service goes down
+pub async fn get_impact(
+    State(conn): State<Connection>,
+    Extension(_user): Extension<User>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    tracing::info!("Getting impact of service: {}", name);
+    impact(&conn, name).await
+}
+
+/// Make a service depend on another or change the kind of an existing dependency
+pub async fn put_dependency(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((name, depends_on)): Path<(String, String)>,
+    Json(new_dependency): Json<NewDependency>,
+) -> impl IntoResponse {
+    tracing::info!("{} making service {} depend on {}", admin.name, name, depends_on);
+    save(&conn, name, depends_on, new_dependency.kind, AuditEvent::new("service.dependency_add").user(&admin).ip(&addr)).await
+}
+
+/// Remove a dependency between two services
+pub async fn delete_dependency(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((name, depends_on)): Path<(String, String)>,
+) -> impl IntoResponse {
+    tracing::info!("{} removing dependency of service {} on {}", admin.name, name, depends_on);
+    remove(&conn, name, depends_on, AuditEvent::new("service.dependency_remove").user(&admin).ip(&addr)).await
+}
+
+/// Service graph for API token callers with `services:read`
+pub async fn api_get_graph(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesRead)?;
+    tracing::info!("Getting service graph for {} with token {}", caller.user_name, caller.token_id);
+    Ok(graph(&conn).await)
+}
+
+/// Impact of a service going down for API token callers with `services:read`
+pub async fn api_get_impact(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesRead)?;
+    tracing::info!("Getting impact of {} for {} with token {}", name, caller.user_name, caller.token_id);
+    Ok(impact(&conn, name).await)
+}
+
+/// Add or change a dependency for API token callers with `services:write`
+pub async fn api_put_dependency(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((name, depends_on)): Path<(String, String)>,
+    Json(new_dependency): Json<NewDependency>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesWrite)?;
+    tracing::info!("Making {} depend on {} for {} with token {}", name, depends_on, caller.user_name, caller.token_id);
+    Ok(save(&conn, name, depends_on, new_dependency.kind, AuditEvent::new("service.dependency_add").caller(&caller).ip(&addr)).await)
+}
+
+/// Remove a dependency for API token callers with `services:write`
+pub async fn api_delete_dependency(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((name, depends_on)): Path<(String, String)>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesWrite)?;
+    tracing::info!("Removing dependency of {} on {} for {} with token {}", name, depends_on, caller.user_name, caller.token_id);
+    Ok(remove(&conn, name, depends_on, AuditEvent::new("service.dependency_remove").caller(&caller).ip(&addr)).await)
+}
+
+/// Json response with the whole service graph
+async fn graph(conn: &Connection) -> Json<Value> {
+    match dependency::graph(conn).await {
+        Ok(graph) => Json(json!({"result": "ok", "nodes": graph.nodes, "edges": graph.edges})),
+        Err(err) => {
+            tracing::error!("Service graph db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Getting Service Graph From DB"}))
+        },
+    }
+}
+
+/// Json response with the services affected by `name` going down
+async fn impact(conn: &Connection, name: String) -> Json<Value> {
+    match dependency::impact_of(conn, name.clone()).await {
+        Ok(Some(affected)) => Json(json!({"result": "ok", "service": name, "affected": affected})),
+        Ok(None) => Json(json!({"result": "error", "message": "Service Not Found"})),
+        Err(err) => {
+            tracing::error!("Service impact db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Getting Service Impact From DB"}))
+        },
+    }
+}
+
+/// Save a dependency. `event` already has the actor and is recorded on success.
+async fn save(conn: &Connection, name: String, depends_on: String, kind: DependencyKind, event: AuditEvent) -> Json<Value> {
+    if name == depends_on {
+        return Json(json!({"result": "error", "message": "A Service Can Not Depend On Itself"}));
+    }
+
+    let details = json!({"depends_on": depends_on, "kind": kind});
+    let target = name.clone();
+    let query = conn
+        .call(move |conn| dependency::save(conn, &name, &depends_on, kind))
+        .await;
+
+    match query {
+        Ok(Outcome::Saved) => {
+            audit::record(conn, event.target(&target).details(details)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Ok(Outcome::NotFound) => Json(json!({"result": "error", "message": "Service Not Found"})),
+        Ok(Outcome::Cycle) => Json(json!({"result": "error", "message": "Dependency Would Create A Cycle"})),
+        Err(err) => {
+            tracing::error!("Dependency save db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Saving Dependency"}))
+        },
+    }
+}
+
+/// Delete a dependency
+async fn remove(conn: &Connection, name: String, depends_on: String, event: AuditEvent) -> Json<Value> {
+    let details = json!({"depends_on": depends_on});
+    let target = name.clone();
+    match dependency::remove(conn, name, depends_on).await {
+        Ok(0) => Json(json!({"result": "error", "message": "Dependency Not Found"})),
+        Ok(_) => {
+            audit::record(conn, event.target(&target).details(details)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Dependency delete db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Removing Dependency"}))
+        },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewDependency {
+    /// Defaults to hard
+    #[serde(default)]
+    kind: DependencyKind,
+}
@@ -0,0 +1,140 @@
+//! Drives the backend router in tests the way the frontend does. The session cookie is kept
+//! between requests and every change sends the session's CSRF token.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    Router,
+};
+use axum_login::{axum_sessions::SessionLayer, AuthLayer, RusqliteStore};
+use serde_json::Value;
+use tokio_rusqlite::Connection;
+use tower::ServiceExt;
+
+use crate::{
+    config::Config,
+    csrf::CSRF_HEADER,
+    migrations::MIGRATIONS,
+    passkey,
+    routes::backend,
+    session_store::{AnySessionStore, MemorySessionStore},
+    setup::Setup,
+    user::{Role, User, UserMapper},
+    SESSION_COOKIE_NAME,
+};
+
+/// Signs the cookies of test apps
+const SECRET: [u8; 64] = [7; 64];
+
+/// Backend on an in memory db with every migration applied
+pub struct TestApp {
+    pub conn: Connection,
+    pub setup: Arc<Setup>,
+    router: Router,
+    /// Where requests appear to come from
+    addr: SocketAddr,
+    cookie: Option<String>,
+    csrf: Option<String>,
+}
+
+/// Status, headers and json body of a response. The body is `Null` if it isn't json.
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Value,
+}
+
+impl TestApp {
+    pub async fn new(config: Config) -> Self {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        // Like main, so cascades work the same way
+        conn.call(|conn| conn.pragma_update(None, "foreign_keys", "ON")).await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        let setup = Arc::new(Setup::init(&conn).await.unwrap());
+        let webauthn = Arc::new(passkey::webauthn(&config.passkey).unwrap());
+        let sessions = AnySessionStore::Memory(MemorySessionStore::new());
+        let session_layer = SessionLayer::new(sessions.clone(), &SECRET)
+            .with_cookie_name(SESSION_COOKIE_NAME)
+            .with_session_ttl(None);
+        let auth_layer = AuthLayer::new(RusqliteStore::<User, UserMapper, Role>::new(conn.clone()), &SECRET);
+        let router = backend(session_layer, auth_layer, conn.clone(), config, setup.clone(), sessions, webauthn);
+        Self {
+            conn,
+            setup,
+            router,
+            addr: SocketAddr::from(([127, 0, 0, 1], 40000)),
+            cookie: None,
+            csrf: None,
+        }
+    }
+
+    pub async fn get(&mut self, uri: &str) -> TestResponse {
+        self.request(Method::GET, uri, None).await
+    }
+
+    /// Post json with the CSRF token, fetching it first if the session doesn't have one yet
+    pub async fn post(&mut self, uri: &str, body: Value) -> TestResponse {
+        self.fetch_csrf().await;
+        self.request(Method::POST, uri, Some(body)).await
+    }
+
+    /// Put json with the CSRF token like `post`
+    pub async fn put(&mut self, uri: &str, body: Value) -> TestResponse {
+        self.fetch_csrf().await;
+        self.request(Method::PUT, uri, Some(body)).await
+    }
+
+    /// Delete with the CSRF token like `post`
+    pub async fn delete(&mut self, uri: &str) -> TestResponse {
+        self.fetch_csrf().await;
+        self.request(Method::DELETE, uri, None).await
+    }
+
+    async fn fetch_csrf(&mut self) {
+        if self.csrf.is_none() {
+            self.csrf = self.get("/auth/csrf").await.body["token"].as_str().map(str::to_string);
+        }
+    }
+
+    async fn request(&mut self, method: Method, uri: &str, body: Option<Value>) -> TestResponse {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::USER_AGENT, "cyberdeck-tests")
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(cookie) = &self.cookie {
+            request = request.header(header::COOKIE, cookie);
+        }
+        if let Some(csrf) = &self.csrf {
+            request = request.header(CSRF_HEADER, csrf);
+        }
+        let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+        let mut request = request.body(body).unwrap();
+        request.extensions_mut().insert(ConnectInfo(self.addr));
+
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let cookie = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| value.split(';').next())
+            .find(|value| value.starts_with(&format!("{}=", SESSION_COOKIE_NAME)))
+            .map(str::to_string);
+        if cookie.is_some() {
+            self.cookie = cookie;
+        }
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        TestResponse {
+            status,
+            headers,
+            body: serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::net::SocketAddr;
+
+use axum::{extract::{ConnectInfo, Path, State}, http::StatusCode, response::IntoResponse, Json, Extension};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_rusqlite::Connection;
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::{unix_now, Caller, JsonError},
+    config::Config,
+    heartbeat,
+    token::Scope,
+    user::User,
+};
+
+/// Let a registered service set its status with heartbeats
+pub async fn put_heartbeat(
+    State(conn): State<Connection>,
+    Extension(config): Extension<Config>,
+    Extension(user): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Json(settings): Json<HeartbeatSettings>,
+) -> impl IntoResponse {
+    tracing::info!("{} turning on heartbeats for service: {}", user.name, name);
+    enable(&conn, &config, name, settings, AuditEvent::new("service.heartbeat_update").user(&user).ip(&addr)).await
+}
+
+/// Stop taking heartbeats from a service. Its status is left as it was.
+pub async fn delete_heartbeat(
+    State(conn): State<Connection>,
+    Extension(user): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    tracing::info!("{} turning off heartbeats for service: {}", user.name, name);
+    disable(&conn, name, AuditEvent::new("service.heartbeat_delete").user(&user).ip(&addr)).await
+}
+
+/// Turn on heartbeats for a service for API token callers with `services:write`
+pub async fn api_put_heartbeat(
+    State(conn): State<Connection>,
+    Extension(config): Extension<Config>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Json(settings): Json<HeartbeatSettings>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesWrite)?;
+    tracing::info!("Turning on heartbeats for {} for {} with token {}", name, caller.user_name, caller.token_id);
+    Ok(enable(&conn, &config, name, settings, AuditEvent::new("service.heartbeat_update").caller(&caller).ip(&addr)).await)
+}
+
+/// Turn off heartbeats for a service for API token callers with `services:write`
+pub async fn api_delete_heartbeat(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesWrite)?;
+    tracing::info!("Turning off heartbeats for {} for {} with token {}", name, caller.user_name, caller.token_id);
+    Ok(disable(&conn, name, AuditEvent::new("service.heartbeat_delete").caller(&caller).ip(&addr)).await)
+}
+
+/// Start expecting heartbeats. `event` already has the actor and is recorded on success.
+async fn enable(conn: &Connection, config: &Config, name: String, settings: HeartbeatSettings, event: AuditEvent) -> Json<Value> {
+    let interval = heartbeat::interval(&config.heartbeat, settings.interval_secs);
+    match heartbeat::enable(conn, name.clone(), interval, unix_now()).await {
+        Ok(false) => Json(json!({"result": "error", "message": "Service Not Found"})),
+        Ok(true) => {
+            audit::record(conn, event.target(&name).details(json!({"interval_secs": interval}))).await;
+            Json(json!({"result": "ok", "interval_secs": interval}))
+        },
+        Err(err) => {
+            tracing::error!("Heartbeat save db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Turning On Heartbeats"}))
+        },
+    }
+}
+
+/// Stop expecting heartbeats from a service
+async fn disable(conn: &Connection, name: String, event: AuditEvent) -> Json<Value> {
+    match heartbeat::disable(conn, name.clone()).await {
+        Ok(0) => Json(json!({"result": "error", "message": "Service Has No Heartbeats"})),
+        Ok(_) => {
+            audit::record(conn, event.target(&name)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Heartbeat delete db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Turning Off Heartbeats"}))
+        },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct HeartbeatSettings {
+    /// How often the service will send heartbeats, `HEARTBEAT_INTERVAL_SECS` if not given.
+    /// Heartbeats that say how often they are sent replace it.
+    interval_secs: Option<u32>,
+}
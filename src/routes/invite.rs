@@ -0,0 +1,205 @@
+use std::net::SocketAddr;
+
+use axum::{extract::{ConnectInfo, Path, Query, State}, response::IntoResponse, Json, Extension};
+use rusqlite::params;
+use serde::Deserialize;
+use serde_json::json;
+use tokio_rusqlite::Connection;
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::unix_now,
+    config::Config,
+    invite::{self, Invite, InviteStatus, DEFAULT_TTL_HOURS, MAX_TTL_HOURS},
+    routes::user::{is_unique_violation, password_policy_error},
+    user::{Role, User},
+};
+
+/// Create a single use invite for a role. The raw code is only returned here, the db only keeps its hash.
+pub async fn create_invite(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(new_invite): Json<NewInvite>,
+) -> impl IntoResponse {
+    let hours = new_invite.expires_in_hours.unwrap_or(DEFAULT_TTL_HOURS);
+    if hours == 0 || hours > MAX_TTL_HOURS {
+        return Json(json!({"result": "error", "message": format!("Invites Must Expire Within {} Hours", MAX_TTL_HOURS)}));
+    }
+    tracing::info!("{} creating invite for role: {}", admin.name, new_invite.role);
+
+    let (code, prefix, hash) = invite::generate();
+    let expires_at = unix_now() + i64::from(hours) * 60 * 60;
+    let (role, created_by, display_prefix) = (new_invite.role, admin.id, prefix.clone());
+    let query = conn
+        .call(move |conn| {
+            conn.execute(
+                "INSERT INTO invites (prefix, hash, role, created_by, expires_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![display_prefix, hash, role, created_by, expires_at],
+            )?;
+            Ok::<_, rusqlite::Error>(conn.last_insert_rowid())
+        })
+        .await;
+
+    match query {
+        Ok(id) => {
+            audit::record(&conn, AuditEvent::new("invite.create").user(&admin).target(id).ip(&addr).details(json!({"role": role, "expires_at": expires_at}))).await;
+            Json(json!({
+                "result": "ok",
+                "id": id,
+                "prefix": prefix,
+                "expires_at": expires_at,
+                // Shown once. It can't be recovered after this.
+                "code": code,
+                "link": format!("/?invite={}", code),
+            }))
+        },
+        Err(err) => {
+            tracing::error!("Invite insert db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Creating Invite"}))
+        },
+    }
+}
+
+/// List invites with their status. Filter with `?status=pending|used|revoked|expired`.
+pub async fn get_invites(
+    State(conn): State<Connection>,
+    Query(query): Query<InviteQuery>,
+) -> impl IntoResponse {
+    let now = unix_now();
+    let query_result = conn
+        .call(move |conn| {
+            let mut stmt = conn.prepare("SELECT * FROM invites ORDER BY id DESC")?;
+            let invites = stmt
+                .query_map([], |row| Invite::map(row, now))?
+                .collect::<std::result::Result<Vec<Invite>, rusqlite::Error>>()?;
+            Ok::<_, rusqlite::Error>(invites)
+        })
+        .await;
+
+    match query_result {
+        Ok(invites) => {
+            let invites: Vec<Invite> = invites
+                .into_iter()
+                .filter(|invite| query.status.map_or(true, |status| invite.status == status))
+                .collect();
+            Json(json!({"result": "ok", "invites": invites}))
+        },
+        Err(err) => {
+            tracing::error!("Invite fetch db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Getting Invites From DB"}))
+        },
+    }
+}
+
+/// Revoke an invite that hasn't been used yet. The row is kept so it still shows up in the list.
+pub async fn revoke_invite(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    tracing::info!("{} revoking invite: {}", admin.name, id);
+    let query = conn
+        .call(move |conn| {
+            conn.execute(
+                "UPDATE invites SET revoked_at = unixepoch() WHERE id = ?1 AND used_at IS NULL AND revoked_at IS NULL",
+                params![id],
+            )
+        })
+        .await;
+
+    match query {
+        Ok(0) => Json(json!({"result": "error", "message": "Invite Not Found Or Already Used"})),
+        Ok(_) => {
+            audit::record(&conn, AuditEvent::new("invite.revoke").user(&admin).target(id).ip(&addr)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Invite revoke db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Revoking Invite"}))
+        },
+    }
+}
+
+/// Public route to check an invite code before showing the sign up form
+pub async fn check_invite(
+    State(conn): State<Connection>,
+    Json(check): Json<CheckInvite>,
+) -> impl IntoResponse {
+    match invite::check(&conn, check.code.trim()).await {
+        Ok(Some(role)) => Json(json!({"result": "ok", "role": role})),
+        Ok(None) => Json(json!({"result": "error", "message": "Invite Is Invalid Or Expired"})),
+        Err(err) => {
+            tracing::error!("Invite check db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Checking Invite"}))
+        },
+    }
+}
+
+/// Public route for an invitee to pick their username and password.
+/// Creates the user with the role of the invite and uses up the invite.
+pub async fn redeem_invite(
+    State(conn): State<Connection>,
+    Extension(config): Extension<Config>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(redeem): Json<RedeemInvite>,
+) -> impl IntoResponse {
+    let username = redeem.username.trim();
+    tracing::info!("Redeeming invite for: {} from {}", username, addr.ip());
+    if username.is_empty() {
+        return Json(json!({"result": "error", "message": "Username Can Not Be Empty"}));
+    }
+    if let Err(errors) = config.password.validate(username, &redeem.password) {
+        return password_policy_error(errors);
+    }
+
+    // The role comes from the invite, this one is replaced when the row is inserted
+    let user = match User::new(username, &redeem.password, 0, Role::Viewer) {
+        Ok(user) => user,
+        Err(err) => {
+            tracing::error!("Could not hash password: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Creating User"}));
+        }
+    };
+
+    match invite::redeem(&conn, redeem.code.trim(), user).await {
+        Ok(Some((id, role))) => {
+            audit::record(&conn, AuditEvent::new("invite.redeem").anonymous(username).target(id).ip(&addr).details(json!({"name": username, "role": role}))).await;
+            Json(json!({"result": "ok", "id": id, "user": username, "role": role}))
+        },
+        Ok(None) => Json(json!({"result": "error", "message": "Invite Is Invalid Or Expired"})),
+        Err(err) if is_unique_violation(&err) => {
+            Json(json!({"result": "error", "message": "Username Already Exists"}))
+        },
+        Err(err) => {
+            tracing::error!("Invite redeem db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Creating User"}))
+        },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewInvite {
+    #[serde(default)]
+    role: Role,
+    /// Defaults to a week
+    expires_in_hours: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct InviteQuery {
+    status: Option<InviteStatus>,
+}
+
+#[derive(Deserialize)]
+pub struct CheckInvite {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct RedeemInvite {
+    code: String,
+    username: String,
+    password: String,
+}
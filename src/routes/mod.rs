@@ -2,22 +2,37 @@ use axum::{
     http::StatusCode,
     middleware,
     response::IntoResponse,
-    routing::{get, get_service, post},
-    Router,
+    routing::{delete, get, get_service, post, put},
+    Extension, Router,
 };
 use axum_login::{
     axum_sessions::{async_session::SessionStore, SessionLayer},
     AuthLayer, RequireAuthorizationLayer, RusqliteStore
 };
 use tokio_rusqlite::Connection;
-use std::io;
+use std::{io, sync::Arc};
 use tower_http::{services::ServeDir, trace::TraceLayer};
+use webauthn_rs::Webauthn;
 
-use crate::{FRONTEND, auth::token_auth, user::{User, UserMapper}};
+use crate::{FRONTEND, auth::{token_auth, track_session}, config::Config, csrf::csrf_protect, oidc::Oidc, session_store::AnySessionStore, setup::Setup, user::{Role, User, UserMapper}};
 
 pub mod test;
 pub mod auth;
 pub mod service;
+pub mod user;
+pub mod totp;
+pub mod token;
+pub mod session;
+pub mod audit;
+pub mod oidc;
+pub mod invite;
+pub mod setup;
+pub mod passkey;
+pub mod check;
+pub mod dependency;
+pub mod heartbeat;
+#[cfg(test)]
+mod harness;
 
 /// Frontend: The svelte build bundle, css and index.html from public folder
 /// This folder is made by runing `npm run build` in the ./ui directory
@@ -39,15 +54,33 @@ async fn handle_error(_err: io::Error) -> impl IntoResponse {
 /// Backend: server built form various routes that are either public, require auth token, or secure login session
 pub fn backend<Store: SessionStore>(
     session_layer: SessionLayer<Store>,
-    auth_layer: AuthLayer<RusqliteStore<User, UserMapper>, i64, User>,
+    auth_layer: AuthLayer<RusqliteStore<User, UserMapper, Role>, i64, User, Role>,
     state: Connection,
+    config: Config,
+    setup: Arc<Setup>,
+    sessions: AnySessionStore,
+    webauthn: Arc<Webauthn>,
 ) -> Router {
     // could add tower::ServiceBuilder here to group layers, especially if you add more layers.
     // see https://docs.rs/axum/latest/axum/middleware/index.html#ordering
-    Router::new()
+    let mut session_routes = Router::new()
         .merge(back_public_route())
         .merge(back_auth_route())
+        .merge(back_operator_route())
+        .merge(back_admin_route());
+    // single sign on routes only exist when a provider is configured
+    if let Some(oidc_config) = config.oidc.clone() {
+        session_routes = session_routes.merge(back_oidc_route(Arc::new(Oidc::new(oidc_config))));
+    }
+    Router::new()
+        // everything authenticated by the session cookie needs a CSRF token to change things
+        // and records where the session is used from. Token routes don't use the session.
+        .merge(session_routes.layer(middleware::from_fn(csrf_protect)).layer(middleware::from_fn(track_session)))
         .merge(back_token_route(state.clone()))
+        .layer(Extension(config))
+        .layer(Extension(setup))
+        .layer(Extension(sessions))
+        .layer(Extension(webauthn))
         .layer(auth_layer)
         .layer(session_layer)
         .with_state(state)
@@ -58,26 +91,103 @@ pub fn backend<Store: SessionStore>(
 pub fn back_public_route() -> Router<Connection> {
     Router::new()
         // @TODO Remove test route
+        .route("/auth/csrf", get(auth::csrf_token)) // token the frontend sends with every change
         .route("/auth/login", post(auth::login)) // sets username in session
+        .route("/auth/login/totp", post(auth::login_totp)) // second step for users with TOTP
+        .route("/auth/passkey/login/start", post(passkey::login_start)) // challenge for the user's passkeys
+        .route("/auth/passkey/login/finish", post(passkey::login_finish)) // sets username in session
         .route("/auth/logout", get(auth::logout)) // deletes username in session
+        .route("/setup", get(setup::get_setup).post(setup::run_setup)) // creates the first admin
+        .route("/auth/invite/check", post(invite::check_invite))
+        .route("/auth/invite/redeem", post(invite::redeem_invite)) // creates the user from an invite
         .route("/test", get(test::test))
 }
 
 /// Routes that require a secure session.
-/// Most the app requires the user to be logged in.
+/// Most the app requires the user to be logged in. Any role can read these.
 pub fn back_auth_route() -> Router<Connection> {
     Router::new()
         .route("/services", get(service::get_services))
+        .route("/services/:name/history", get(service::get_service_history))
+        .route("/services/:name/check", get(check::get_check))
+        .route("/services/graph", get(dependency::get_graph))
+        .route("/services/:name/impact", get(dependency::get_impact))
         .route("/servicegroups", get(service::get_services_by_server))
         .route("/secure", get(test::protected))
         .route("/secure/check", get(test::check_cookie))
-        .route_layer(RequireAuthorizationLayer::<i64, User>::login())
+        .route("/auth/password", post(auth::change_password))
+        .route("/auth/totp/enroll", post(totp::enroll))
+        .route("/auth/totp/confirm", post(totp::confirm))
+        .route("/auth/totp/disable", post(totp::disable))
+        .route("/auth/passkeys", get(passkey::get_passkeys))
+        .route("/auth/passkeys/:id", delete(passkey::delete_passkey))
+        .route("/auth/passkey/confirm/start", post(passkey::confirm_start))
+        .route("/auth/passkey/register/start", post(passkey::register_start))
+        .route("/auth/passkey/register/finish", post(passkey::register_finish))
+        .route("/tokens", get(token::get_tokens).post(token::create_token))
+        .route("/tokens/:id", delete(token::delete_token))
+        .route("/auth/sessions", get(session::get_sessions).delete(session::delete_other_sessions))
+        .route("/auth/sessions/:id", delete(session::delete_session))
+        .route_layer(RequireAuthorizationLayer::<i64, User, Role>::login_with_role(Role::Viewer..))
+}
+
+/// Routes that require a secure session from an operator or admin.
+/// Changes to services, their checks and heartbeats belong here.
+pub fn back_operator_route() -> Router<Connection> {
+    Router::new()
+        .route("/services", post(service::create_service))
+        .route("/services/:name", put(service::update_service).delete(service::delete_service))
+        .route("/services/:name/rename", post(service::rename_service))
+        .route("/services/:name/check", put(check::put_check).delete(check::delete_check))
+        .route("/services/:name/heartbeat", put(heartbeat::put_heartbeat).delete(heartbeat::delete_heartbeat))
+        .route("/services/:name/dependencies/:depends_on", put(dependency::put_dependency).delete(dependency::delete_dependency))
+        .route_layer(RequireAuthorizationLayer::<i64, User, Role>::login_with_role(Role::Operator..))
+}
+
+/// Routes that require a secure session from an admin.
+/// Anything that changes users belongs here.
+pub fn back_admin_route() -> Router<Connection> {
+    Router::new()
+        .route("/secure/admin", get(test::protected))
+        .route("/users", get(user::get_users).post(user::create_user))
+        .route("/users/:id", put(user::update_user).delete(user::delete_user))
+        .route("/users/:id/disable", post(user::disable_user))
+        .route("/users/:id/enable", post(user::enable_user))
+        .route("/users/:id/unlock", post(user::unlock_user))
+        .route("/users/:id/sessions", get(session::get_user_sessions).delete(session::delete_user_sessions))
+        .route("/users/:id/sessions/:session_id", delete(session::delete_user_session))
+        .route("/audit", get(audit::get_audit))
+        .route("/invites", get(invite::get_invites).post(invite::create_invite))
+        .route("/invites/:id", delete(invite::revoke_invite))
+        .route_layer(RequireAuthorizationLayer::<i64, User, Role>::login_with_role(Role::Admin..))
+}
+
+/// Single sign on routes. Logging in is public, linking identities to users is for admins.
+pub fn back_oidc_route(oidc: Arc<Oidc>) -> Router<Connection> {
+    let admin = Router::new()
+        .route("/users/:id/oidc", put(oidc::link_user).delete(oidc::unlink_user))
+        .route_layer(RequireAuthorizationLayer::<i64, User, Role>::login_with_role(Role::Admin..));
+    Router::new()
+        .route("/auth/oidc/login", get(oidc::login)) // redirects to the provider
+        .route("/auth/oidc/callback", get(oidc::callback)) // provider redirects back here
+        .merge(admin)
+        .layer(Extension(oidc))
 }
 
 /// Routes that require an api token.
 pub fn back_token_route<S>(state: Connection) -> Router<S> {
     Router::new()
         .route("/api", get(test::api_test))
+        .route("/api/services", get(service::api_get_services).post(service::api_create_service))
+        .route("/api/services/:name", put(service::api_update_service).delete(service::api_delete_service))
+        .route("/api/services/:name/rename", post(service::api_rename_service))
+        .route("/api/services/:name/history", get(service::api_get_service_history))
+        .route("/api/services/:name/check", get(check::api_get_check).put(check::api_put_check).delete(check::api_delete_check))
+        .route("/api/services/:name/heartbeat", put(heartbeat::api_put_heartbeat).delete(heartbeat::api_delete_heartbeat))
+        .route("/api/services/graph", get(dependency::api_get_graph))
+        .route("/api/services/:name/impact", get(dependency::api_get_impact))
+        .route("/api/services/:name/dependencies/:depends_on", put(dependency::api_put_dependency).delete(dependency::api_delete_dependency))
+        .route("/api/servicegroups", get(service::api_get_services_by_server))
         .route_layer(middleware::from_fn_with_state(
             state.clone(),
             token_auth,
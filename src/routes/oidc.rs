@@ -0,0 +1,429 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    response::{IntoResponse, Redirect, Response},
+    Extension, Json,
+};
+use axum_login::{axum_sessions::SessionHandle, RusqliteUserMapper};
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::params;
+use serde::Deserialize;
+use serde_json::json;
+use tokio_rusqlite::Connection;
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::{constant_time_eq, AuthContext},
+    lockout,
+    oidc::{self, Identity, Oidc},
+    routes::{auth::complete_login, user::{is_foreign_key_violation, is_unique_violation}},
+    user::{User, UserMapper},
+};
+
+/// Session key holding the state sent to the provider
+const OIDC_STATE_KEY: &str = "oidc_state";
+/// Session key holding the nonce the ID token has to contain
+const OIDC_NONCE_KEY: &str = "oidc_nonce";
+/// Session key holding the PKCE verifier for the code exchange
+const OIDC_PKCE_KEY: &str = "oidc_pkce";
+
+/// route that starts single sign on by sending the browser to the provider
+pub async fn login(
+    Extension(oidc): Extension<Arc<Oidc>>,
+    Extension(session): Extension<SessionHandle>,
+) -> Response {
+    let request = match oidc.authorize().await {
+        Ok(request) => request,
+        Err(err) => {
+            tracing::error!("OIDC authorize error: {:?}", err);
+            return Json(json!({"result": "error", "message": "Single Sign On Is Unavailable"})).into_response();
+        },
+    };
+
+    // Remember what the callback has to check
+    let mut session = session.write().await;
+    let stored = session
+        .insert(OIDC_STATE_KEY, &request.state)
+        .and_then(|_| session.insert(OIDC_NONCE_KEY, &request.nonce))
+        .and_then(|_| session.insert(OIDC_PKCE_KEY, &request.pkce_verifier));
+    if let Err(err) = stored {
+        tracing::error!("Could not store OIDC login: {:?}", err);
+        return Json(json!({"result": "error", "message": "Problem creating session"})).into_response();
+    }
+    Redirect::to(&request.url).into_response()
+}
+
+/// route the provider sends the browser back to. Checks the state, trades the code
+/// for an ID token and logs in the user linked to the identity.
+pub async fn callback(
+    mut auth: AuthContext,
+    State(conn): State<Connection>,
+    Extension(oidc): Extension<Arc<Oidc>>,
+    Extension(session): Extension<SessionHandle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(callback): Query<Callback>,
+) -> Response {
+    // The pending login is single use so take it out of the session right away.
+    // The lock has to be dropped before logging in since that writes to the session too.
+    let pending = {
+        let mut session = session.write().await;
+        let pending = session
+            .get::<String>(OIDC_STATE_KEY)
+            .zip(session.get::<String>(OIDC_NONCE_KEY))
+            .zip(session.get::<String>(OIDC_PKCE_KEY));
+        session.remove(OIDC_STATE_KEY);
+        session.remove(OIDC_NONCE_KEY);
+        session.remove(OIDC_PKCE_KEY);
+        pending
+    };
+    let Some(((state, nonce), pkce_verifier)) = pending else {
+        return Json(json!({"result": "error", "message": "Login Expired, Please Log In Again"})).into_response();
+    };
+
+    if let Some(error) = callback.error {
+        tracing::error!("OIDC provider returned error: {} {:?}", error, callback.error_description);
+        audit::record(&conn, AuditEvent::new("login.failure").ip(&addr).details(json!({"step": "oidc", "error": error}))).await;
+        return Json(json!({"result": "error", "message": "Single Sign On Failed"})).into_response();
+    }
+    let (Some(code), Some(returned_state)) = (callback.code, callback.state) else {
+        return Json(json!({"result": "error", "message": "Single Sign On Failed"})).into_response();
+    };
+    if !constant_time_eq(state.as_bytes(), returned_state.as_bytes()) {
+        tracing::error!("OIDC state mismatch from {}", addr.ip());
+        audit::record(&conn, AuditEvent::new("login.failure").ip(&addr).details(json!({"step": "oidc", "error": "state mismatch"}))).await;
+        return Json(json!({"result": "error", "message": "Single Sign On Failed"})).into_response();
+    }
+
+    let identity = match oidc.exchange(code, nonce, pkce_verifier).await {
+        Ok(identity) => identity,
+        Err(err) => {
+            tracing::error!("OIDC exchange error: {:?}", err);
+            audit::record(&conn, AuditEvent::new("login.failure").ip(&addr).details(json!({"step": "oidc", "error": "exchange"}))).await;
+            return Json(json!({"result": "error", "message": "Single Sign On Failed"})).into_response();
+        },
+    };
+    tracing::info!("OIDC Login: {} ({}) from {}", identity.username, identity.subject, addr.ip());
+
+    let user = match user_for_identity(&conn, &oidc, &identity).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            tracing::error!("No user linked to OIDC identity: {} {}", identity.issuer, identity.subject);
+            audit::record(&conn, AuditEvent::new("login.failure").anonymous(&identity.username).ip(&addr).details(json!({"step": "oidc", "reason": "unknown identity"}))).await;
+            return Json(json!({"result": "error", "message": "No Account For This Login"})).into_response();
+        },
+        Err(err) if is_unique_violation(&err) => {
+            // Never take over an existing local account just because the name matches
+            tracing::error!("OIDC username already taken by a local user: {}", identity.username);
+            return Json(json!({"result": "error", "message": "Username Already Exists, Ask An Admin To Link Your Account"})).into_response();
+        },
+        Err(err) => {
+            tracing::error!("OIDC DB Error: {:?}", err);
+            return Json(json!({"result": "error", "message": "Problem creating session"})).into_response();
+        },
+    };
+
+    if user.disabled {
+        tracing::error!("Login attempt for disabled user: {}", user.name);
+        audit::record(&conn, AuditEvent::new("login.failure").anonymous(&user.name).ip(&addr).details(json!({"reason": "disabled"}))).await;
+        return Json(json!({"result": "error", "message": "Account Disabled"})).into_response();
+    }
+    if user.service_account {
+        tracing::error!("OIDC login attempt for service account: {}", user.name);
+        audit::record(&conn, AuditEvent::new("login.failure").anonymous(&user.name).ip(&addr).details(json!({"step": "oidc", "reason": "service account"}))).await;
+        return Json(json!({"result": "error", "message": "Service Accounts Can Not Log In"})).into_response();
+    }
+
+    let user_key = lockout::user_key(&user.name);
+    match complete_login(&mut auth, &conn, &user, addr, user_key, "oidc").await {
+        // This was a full page navigation so send the browser back to the app
+        Ok(_) => Redirect::to("/").into_response(),
+        Err(err) => {
+            tracing::error!("OIDC login error: {:?}", err);
+            Json(json!({"result": "error", "message": "Problem creating session"})).into_response()
+        },
+    }
+}
+
+/// Find the user linked to the identity. Creates and links a new user when auto provisioning is on.
+async fn user_for_identity(conn: &Connection, oidc: &Oidc, identity: &Identity) -> Result<Option<User>, rusqlite::Error> {
+    let id = match oidc::find_user(conn, identity.issuer.clone(), identity.subject.clone()).await? {
+        Some(id) => id,
+        None if oidc.config.auto_provision => provision(conn, oidc, identity).await?,
+        None => return Ok(None),
+    };
+    conn.call(move |conn| {
+        let mut stmt = conn.prepare("SELECT id, name, hash, role, disabled, service_account FROM users WHERE id = ?1")?;
+        let users = stmt
+            .query_map(params![id], UserMapper::map)?
+            .collect::<std::result::Result<Vec<User>, rusqlite::Error>>()?;
+        Ok::<_, rusqlite::Error>(users.into_iter().next())
+    })
+    .await
+}
+
+/// Create a user for an identity and link them. The password is random and never
+/// shown to anyone so the user can only log in through the provider.
+async fn provision(conn: &Connection, oidc: &Oidc, identity: &Identity) -> Result<i64, rusqlite::Error> {
+    let password: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+    let user = User::new(&identity.username, &password, 0, oidc.config.default_role)
+        .map_err(|err| rusqlite::Error::ToSqlConversionFailure(err.into()))?;
+    let (issuer, subject) = (identity.issuer.clone(), identity.subject.clone());
+    let id = conn
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO users (name, hash, role) VALUES (?1, ?2, ?3)",
+                params![user.name, user.hash, user.role],
+            )?;
+            let id = tx.last_insert_rowid();
+            tx.execute(
+                "INSERT INTO oidc_identities(issuer, subject, user_id) VALUES (?1, ?2, ?3)",
+                params![issuer, subject, id],
+            )?;
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(id)
+        })
+        .await?;
+    tracing::info!("Provisioned user {} for OIDC identity {}", identity.username, identity.subject);
+    audit::record(conn, AuditEvent::new("user.create").anonymous(&identity.username).target(id).details(json!({"name": identity.username, "role": oidc.config.default_role, "method": "oidc"}))).await;
+    Ok(id)
+}
+
+/// Admin route to link an identity from the provider to an existing user
+pub async fn link_user(
+    State(conn): State<Connection>,
+    Extension(oidc): Extension<Arc<Oidc>>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+    Json(link): Json<Link>,
+) -> impl IntoResponse {
+    tracing::info!("{} linking OIDC subject {} to user {}", admin.name, link.subject, id);
+    // Identities are always for the configured provider
+    let issuer = oidc.config.issuer.clone();
+    match oidc::link(&conn, issuer, link.subject.clone(), id).await {
+        Ok(_) => {
+            audit::record(&conn, AuditEvent::new("user.oidc_link").user(&admin).target(id).ip(&addr).details(json!({"subject": link.subject}))).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) if is_unique_violation(&err) => Json(json!({"result": "error", "message": "Identity Already Linked"})),
+        Err(err) if is_foreign_key_violation(&err) => Json(json!({"result": "error", "message": "User Not Found"})),
+        Err(err) => {
+            tracing::error!("OIDC link db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Linking User"}))
+        },
+    }
+}
+
+/// Admin route to remove every identity linked to a user
+pub async fn unlink_user(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    tracing::info!("{} unlinking OIDC identities from user {}", admin.name, id);
+    match oidc::unlink(&conn, id).await {
+        Ok(count) => {
+            audit::record(&conn, AuditEvent::new("user.oidc_unlink").user(&admin).target(id).ip(&addr).details(json!({"count": count}))).await;
+            Json(json!({"result": "ok", "count": count}))
+        },
+        Err(err) => {
+            tracing::error!("OIDC unlink db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Unlinking User"}))
+        },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Link {
+    subject: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::{http::{header, StatusCode}, routing::{get, post}, Router};
+    use chrono::{Duration, Utc};
+    use openidconnect::{
+        core::{CoreIdToken, CoreIdTokenClaims, CoreJwsSigningAlgorithm, CoreRsaPrivateSigningKey},
+        Audience, EmptyAdditionalClaims, EndUserUsername, IssuerUrl, JsonWebKeyId, Nonce, PrivateSigningKey,
+        StandardClaims, SubjectIdentifier,
+    };
+    use reqwest::Url;
+
+    use super::*;
+    use crate::{config::Config, oidc::OidcConfig, routes::harness::TestApp, user::Role};
+
+    const CLIENT_ID: &str = "cyberdeck";
+    /// Key the mock issuer signs ID tokens with. Only used in tests.
+    const SIGNING_KEY: &str = include_str!("testdata/oidc_signing_key.pem");
+
+    /// Subject and nonce the mock issuer puts in the next ID token it hands out
+    #[derive(Default)]
+    struct NextToken {
+        subject: String,
+        nonce: String,
+    }
+
+    struct MockIssuer {
+        url: String,
+        next: Arc<Mutex<NextToken>>,
+    }
+
+    impl MockIssuer {
+        /// Start a provider on a random port with discovery, its signing key and a token endpoint
+        async fn start() -> Self {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let next = Arc::new(Mutex::new(NextToken::default()));
+            let key = Arc::new(CoreRsaPrivateSigningKey::from_pem(SIGNING_KEY, Some(JsonWebKeyId::new("test".to_string()))).unwrap());
+
+            let discovery = json!({
+                "issuer": url,
+                "authorization_endpoint": format!("{}/authorize", url),
+                "token_endpoint": format!("{}/token", url),
+                "jwks_uri": format!("{}/jwks", url),
+                "response_types_supported": ["code"],
+                "subject_types_supported": ["public"],
+                "id_token_signing_alg_values_supported": ["RS256"],
+            });
+            let jwks = json!({"keys": [key.as_verification_key()]});
+            let (issuer, token_next) = (url.clone(), next.clone());
+            let token = move || async move {
+                let (subject, nonce) = {
+                    let next = token_next.lock().unwrap();
+                    (next.subject.clone(), next.nonce.clone())
+                };
+                let claims = CoreIdTokenClaims::new(
+                    IssuerUrl::new(issuer).unwrap(),
+                    vec![Audience::new(CLIENT_ID.to_string())],
+                    Utc::now() + Duration::minutes(5),
+                    Utc::now(),
+                    StandardClaims::new(SubjectIdentifier::new(subject.clone())).set_preferred_username(Some(EndUserUsername::new(subject))),
+                    EmptyAdditionalClaims {},
+                )
+                .set_nonce(Some(Nonce::new(nonce)));
+                let id_token = CoreIdToken::new(claims, &*key, CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256, None, None).unwrap();
+                Json(json!({"access_token": "access", "token_type": "Bearer", "expires_in": 300, "id_token": id_token}))
+            };
+
+            let app = Router::new()
+                .route("/.well-known/openid-configuration", get(move || async move { Json(discovery) }))
+                .route("/jwks", get(move || async move { Json(jwks) }))
+                .route("/token", post(token));
+            tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+            Self { url, next }
+        }
+
+        fn config(&self) -> Config {
+            Config {
+                oidc: Some(OidcConfig {
+                    issuer: self.url.clone(),
+                    client_id: CLIENT_ID.to_string(),
+                    client_secret: None,
+                    redirect_url: "http://localhost:8080/auth/oidc/callback".to_string(),
+                    auto_provision: false,
+                    default_role: Role::Viewer,
+                }),
+                ..Config::default()
+            }
+        }
+
+        fn next_token(&self, subject: &str, nonce: &str) {
+            *self.next.lock().unwrap() = NextToken { subject: subject.to_string(), nonce: nonce.to_string() };
+        }
+    }
+
+    /// Start a login and return the state and nonce sent to the provider
+    async fn start_login(app: &mut TestApp) -> (String, String) {
+        let response = app.get("/auth/oidc/login").await;
+        assert!(response.status.is_redirection());
+        let location = Url::parse(response.headers[header::LOCATION].to_str().unwrap()).unwrap();
+        let param = |name: &str| location.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.to_string()).unwrap();
+        (param("state"), param("nonce"))
+    }
+
+    /// Add a user linked to `subject` at the issuer
+    async fn linked_user(app: &TestApp, issuer: &MockIssuer, name: &str, subject: &str, disabled: bool) {
+        let name = name.to_string();
+        let id = app
+            .conn
+            .call(move |conn| {
+                conn.execute("INSERT INTO users (name, hash, role, disabled) VALUES (?1, 'x', 'viewer', ?2)", params![name, disabled])?;
+                Ok::<_, rusqlite::Error>(conn.last_insert_rowid())
+            })
+            .await
+            .unwrap();
+        oidc::link(&app.conn, issuer.url.clone(), subject.to_string(), id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn linked_user_logs_in() {
+        let issuer = MockIssuer::start().await;
+        let mut app = TestApp::new(issuer.config()).await;
+        linked_user(&app, &issuer, "alice", "alice-sub", false).await;
+
+        let (state, nonce) = start_login(&mut app).await;
+        issuer.next_token("alice-sub", &nonce);
+        let response = app.get(&format!("/auth/oidc/callback?code=abc&state={}", state)).await;
+        assert_eq!(response.status, StatusCode::SEE_OTHER);
+        assert_eq!(app.get("/secure/check").await.body["user"], "alice");
+    }
+
+    #[tokio::test]
+    async fn state_mismatch_is_rejected_and_pending_login_is_single_use() {
+        let issuer = MockIssuer::start().await;
+        let mut app = TestApp::new(issuer.config()).await;
+        linked_user(&app, &issuer, "alice", "alice-sub", false).await;
+
+        let (state, nonce) = start_login(&mut app).await;
+        issuer.next_token("alice-sub", &nonce);
+        let response = app.get("/auth/oidc/callback?code=abc&state=forged").await;
+        assert_eq!(response.body["message"], "Single Sign On Failed");
+        let response = app.get(&format!("/auth/oidc/callback?code=abc&state={}", state)).await;
+        assert_eq!(response.body["message"], "Login Expired, Please Log In Again");
+        assert_eq!(app.get("/secure/check").await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn nonce_mismatch_is_rejected() {
+        let issuer = MockIssuer::start().await;
+        let mut app = TestApp::new(issuer.config()).await;
+        linked_user(&app, &issuer, "alice", "alice-sub", false).await;
+
+        let (state, _) = start_login(&mut app).await;
+        issuer.next_token("alice-sub", "replayed");
+        let response = app.get(&format!("/auth/oidc/callback?code=abc&state={}", state)).await;
+        assert_eq!(response.body["message"], "Single Sign On Failed");
+        assert_eq!(app.get("/secure/check").await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unknown_and_disabled_users_are_rejected() {
+        let issuer = MockIssuer::start().await;
+        let mut app = TestApp::new(issuer.config()).await;
+        linked_user(&app, &issuer, "mallory", "mallory-sub", true).await;
+
+        let (state, nonce) = start_login(&mut app).await;
+        issuer.next_token("nobody-sub", &nonce);
+        let response = app.get(&format!("/auth/oidc/callback?code=abc&state={}", state)).await;
+        assert_eq!(response.body["message"], "No Account For This Login");
+
+        let (state, nonce) = start_login(&mut app).await;
+        issuer.next_token("mallory-sub", &nonce);
+        let response = app.get(&format!("/auth/oidc/callback?code=abc&state={}", state)).await;
+        assert_eq!(response.body["message"], "Account Disabled");
+        assert_eq!(app.get("/secure/check").await.status, StatusCode::UNAUTHORIZED);
+    }
+}
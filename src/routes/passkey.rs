@@ -0,0 +1,570 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{extract::{ConnectInfo, Path, State}, response::IntoResponse, Json, Extension};
+use axum_login::{axum_sessions::SessionHandle, RusqliteUserMapper};
+use rusqlite::params;
+use serde::Deserialize;
+use serde_json::json;
+use tokio_rusqlite::Connection;
+use webauthn_rs::prelude::{PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential, RegisterPublicKeyCredential, Webauthn};
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::{unix_now, AuthContext},
+    config::Config,
+    lockout,
+    passkey,
+    routes::{auth::{complete_login, is_locked, login_failed, login_response, remember_session}, user::is_unique_violation},
+    totp,
+    user::{verify_password, User, UserMapper},
+};
+
+/// Session key holding the registration that is waiting for the browser
+const REGISTRATION_KEY: &str = "passkey_registration";
+/// Session key holding the user and challenge of a passkey login that is waiting for the browser
+const LOGIN_KEY: &str = "passkey_login";
+/// Session key holding the challenge an existing passkey answers to confirm the user before registering another
+const CONFIRM_KEY: &str = "passkey_confirm";
+/// Seconds the browser has to answer a passkey challenge
+const CHALLENGE_TIMEOUT: i64 = 5 * 60;
+
+/// Start confirming the logged in user with one of their passkeys so they can register another
+/// without their password. Returns the options to pass to `navigator.credentials.get()`.
+pub async fn confirm_start(
+    State(conn): State<Connection>,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    Extension(user): Extension<User>,
+    Extension(session): Extension<SessionHandle>,
+) -> impl IntoResponse {
+    let passkeys = match passkey::load(&conn, user.id).await {
+        Ok(passkeys) if !passkeys.is_empty() => passkeys,
+        Ok(_) => return Json(json!({"result": "error", "message": "No Passkey For This User"})),
+        Err(err) => {
+            tracing::error!("Passkey fetch db err: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Starting Confirmation"}));
+        },
+    };
+    let (options, authentication) = match webauthn.start_passkey_authentication(&passkeys) {
+        Ok(started) => started,
+        Err(err) => {
+            tracing::error!("Could not start passkey confirmation: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Starting Confirmation"}));
+        },
+    };
+    if let Err(err) = session.write().await.insert(CONFIRM_KEY, (authentication, unix_now() + CHALLENGE_TIMEOUT)) {
+        tracing::error!("Could not store passkey confirmation: {:?}", err);
+        return Json(json!({"result": "error", "message": "Error Starting Confirmation"}));
+    }
+    Json(json!({"result": "ok", "options": options}))
+}
+
+/// Start registering a passkey for the logged in user.
+/// Returns the options to pass to `navigator.credentials.create()`.
+/// A passkey logs in without the password or TOTP so the user has to confirm it is them first,
+/// otherwise a stolen session could be turned into a login of its own.
+pub async fn register_start(
+    State(conn): State<Connection>,
+    Extension(config): Extension<Config>,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    Extension(user): Extension<User>,
+    Extension(session): Extension<SessionHandle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(confirm): Json<ConfirmIdentity>,
+) -> impl IntoResponse {
+    tracing::info!("Passkey registration started: {}", user.name);
+    // Guessing passwords or codes here counts towards the lockout like a login does
+    let user_key = lockout::user_key(&user.name);
+    let ip_key = lockout::ip_key(&addr.ip());
+    if is_locked(&conn, &user.name, addr, &user_key, &ip_key).await {
+        return Json(json!({"result": "error", "message": "Too Many Failed Attempts, Try Again Later"}));
+    }
+    match identity_confirmed(&conn, &webauthn, &session, &user, confirm).await {
+        Ok(true) => (),
+        Ok(false) => {
+            tracing::error!("Could not confirm {} before passkey registration", user.name);
+            login_failed(&conn, &config.lockout, &user.name, addr, user_key, ip_key, "passkey_register").await;
+            return Json(json!({"result": "error", "message": "Password, Code Or Passkey Wrong"}));
+        },
+        Err(err) => {
+            tracing::error!("Could not confirm user for passkey registration: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Starting Registration"}));
+        },
+    }
+
+    // Don't let the same authenticator be registered twice
+    let exclude = match passkey::load(&conn, user.id).await {
+        Ok(passkeys) => passkeys.iter().map(|passkey| passkey.cred_id().clone()).collect(),
+        Err(err) => {
+            tracing::error!("Passkey fetch db err: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Starting Registration"}));
+        },
+    };
+    let (options, registration) = match webauthn.start_passkey_registration(passkey::user_handle(user.id), &user.name, &user.name, Some(exclude)) {
+        Ok(started) => started,
+        Err(err) => {
+            tracing::error!("Could not start passkey registration: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Starting Registration"}));
+        },
+    };
+
+    if let Err(err) = session.write().await.insert(REGISTRATION_KEY, (registration, unix_now() + CHALLENGE_TIMEOUT)) {
+        tracing::error!("Could not store passkey registration: {:?}", err);
+        return Json(json!({"result": "error", "message": "Error Starting Registration"}));
+    }
+    Json(json!({"result": "ok", "options": options}))
+}
+
+/// Finish registering a passkey with the credential the browser created
+pub async fn register_finish(
+    State(conn): State<Connection>,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    Extension(user): Extension<User>,
+    Extension(session): Extension<SessionHandle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(register): Json<RegisterPasskey>,
+) -> impl IntoResponse {
+    // A challenge can only be answered once
+    let pending = {
+        let mut session = session.write().await;
+        let pending = session.get::<(PasskeyRegistration, i64)>(REGISTRATION_KEY);
+        session.remove(REGISTRATION_KEY);
+        pending
+    };
+    let registration = match pending {
+        Some((registration, expires)) if expires > unix_now() => registration,
+        _ => return Json(json!({"result": "error", "message": "Registration Expired, Please Try Again"})),
+    };
+
+    let name = register.name.trim().to_string();
+    if name.is_empty() {
+        return Json(json!({"result": "error", "message": "Passkey Name Can Not Be Empty"}));
+    }
+    let passkey = match webauthn.finish_passkey_registration(&register.credential, &registration) {
+        Ok(passkey) => passkey,
+        Err(err) => {
+            tracing::error!("Passkey registration rejected for {}: {:?}", user.name, err);
+            return Json(json!({"result": "error", "message": "Passkey Not Accepted"}));
+        },
+    };
+
+    match passkey::add(&conn, user.id, name.clone(), &passkey).await {
+        Ok(id) => {
+            audit::record(&conn, AuditEvent::new("passkey.register").user(&user).target(id).ip(&addr).details(json!({"name": name}))).await;
+            Json(json!({"result": "ok", "id": id}))
+        },
+        Err(err) if is_unique_violation(&err) => {
+            Json(json!({"result": "error", "message": "Passkey Already Registered"}))
+        },
+        Err(err) => {
+            tracing::error!("Passkey insert db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Registering Passkey"}))
+        },
+    }
+}
+
+/// List the passkeys of the logged in user
+pub async fn get_passkeys(State(conn): State<Connection>, Extension(user): Extension<User>) -> impl IntoResponse {
+    match passkey::list(&conn, user.id).await {
+        Ok(passkeys) => Json(json!({"result": "ok", "passkeys": passkeys})),
+        Err(err) => {
+            tracing::error!("Passkey fetch db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Getting Passkeys From DB"}))
+        },
+    }
+}
+
+/// Remove one of the logged in user's passkeys
+pub async fn delete_passkey(
+    State(conn): State<Connection>,
+    Extension(user): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    tracing::info!("{} deleting passkey: {}", user.name, id);
+    match passkey::delete(&conn, user.id, id).await {
+        Ok(0) => Json(json!({"result": "error", "message": "Passkey Not Found"})),
+        Ok(_) => {
+            audit::record(&conn, AuditEvent::new("passkey.delete").user(&user).target(id).ip(&addr)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Passkey delete db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Deleting Passkey"}))
+        },
+    }
+}
+
+/// Whether the user proved it is them with their password, a TOTP or recovery code, or an existing
+/// passkey answering the challenge from `confirm_start`. Users created through single sign on
+/// don't know their password so they use one of the others.
+async fn identity_confirmed(
+    conn: &Connection,
+    webauthn: &Webauthn,
+    session: &SessionHandle,
+    user: &User,
+    confirm: ConfirmIdentity,
+) -> anyhow::Result<bool> {
+    if let Some(password) = confirm.password {
+        return Ok(verify_password(&user.hash, &password));
+    }
+    if let Some(code) = confirm.code {
+        return totp::check_code(conn, user.id, &user.name, code).await;
+    }
+    let Some(credential) = confirm.credential else {
+        return Ok(false);
+    };
+    // A challenge can only be answered once
+    let pending = {
+        let mut session = session.write().await;
+        let pending = session.get::<(PasskeyAuthentication, i64)>(CONFIRM_KEY);
+        session.remove(CONFIRM_KEY);
+        pending
+    };
+    let authentication = match pending {
+        Some((authentication, expires)) if expires > unix_now() => authentication,
+        _ => return Ok(false),
+    };
+    match webauthn.finish_passkey_authentication(&credential, &authentication) {
+        Ok(result) => Ok(passkey::used(conn, user.id, &result).await?.is_some()),
+        Err(err) => {
+            tracing::error!("Passkey rejected confirming {}: {:?}", user.name, err);
+            Ok(false)
+        },
+    }
+}
+
+/// Public route to start logging in with a passkey instead of a password.
+/// Returns the options to pass to `navigator.credentials.get()`.
+pub async fn login_start(
+    State(conn): State<Connection>,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    Extension(session): Extension<SessionHandle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(start): Json<PasskeyLoginStart>,
+) -> impl IntoResponse {
+    tracing::info!("Passkey Login Attempt: {} from {}", start.username, addr.ip());
+    let user_key = lockout::user_key(&start.username);
+    let ip_key = lockout::ip_key(&addr.ip());
+    if is_locked(&conn, &start.username, addr, &user_key, &ip_key).await {
+        return Json(json!({"result": "error", "message": "Too Many Failed Attempts, Try Again Later"}));
+    }
+
+    let name = start.username.clone();
+    let query = conn
+        .call(move |conn| {
+            let mut stmt = conn.prepare("SELECT id, name, hash, role, disabled, service_account FROM users WHERE name = ?1")?;
+            let users = stmt
+                .query_map(params![name], UserMapper::map)?
+                .collect::<std::result::Result<Vec<User>, rusqlite::Error>>()?;
+            Ok::<_, rusqlite::Error>(users)
+        })
+        .await;
+    let user = match query {
+        Ok(rows) if rows.len() == 1 => rows[0].clone(),
+        Ok(_) => return Json(json!({"result": "error", "message": "No Passkey For This User"})),
+        Err(err) => {
+            tracing::error!("Login DB Error: {:?}", err);
+            return Json(json!({"result": "error", "message": "Problem creating session"}));
+        },
+    };
+
+    let passkeys = match passkey::load(&conn, user.id).await {
+        Ok(passkeys) if !passkeys.is_empty() => passkeys,
+        // Unknown users and users without passkeys look the same
+        Ok(_) => return Json(json!({"result": "error", "message": "No Passkey For This User"})),
+        Err(err) => {
+            tracing::error!("Passkey fetch db err: {:?}", err);
+            return Json(json!({"result": "error", "message": "Problem creating session"}));
+        },
+    };
+    let (options, authentication) = match webauthn.start_passkey_authentication(&passkeys) {
+        Ok(started) => started,
+        Err(err) => {
+            tracing::error!("Could not start passkey login: {:?}", err);
+            return Json(json!({"result": "error", "message": "Problem creating session"}));
+        },
+    };
+
+    if let Err(err) = session.write().await.insert(LOGIN_KEY, (user.id, authentication, unix_now() + CHALLENGE_TIMEOUT)) {
+        tracing::error!("Could not store pending passkey login: {:?}", err);
+        return Json(json!({"result": "error", "message": "Problem creating session"}));
+    }
+    Json(json!({"result": "ok", "options": options}))
+}
+
+/// Public route to finish a passkey login with the assertion from the browser.
+/// Creates the same session a password login does.
+pub async fn login_finish(
+    mut auth: AuthContext,
+    State(conn): State<Connection>,
+    Extension(config): Extension<Config>,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    Extension(session): Extension<SessionHandle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(finish): Json<PasskeyLoginFinish>,
+) -> impl IntoResponse {
+    // A challenge can only be answered once
+    let pending = {
+        let mut session = session.write().await;
+        let pending = session.get::<(i64, PasskeyAuthentication, i64)>(LOGIN_KEY);
+        session.remove(LOGIN_KEY);
+        pending
+    };
+    let (id, authentication) = match pending {
+        Some((id, authentication, expires)) if expires > unix_now() => (id, authentication),
+        _ => return Json(json!({"result": "error", "message": "Login Expired, Please Log In Again"})),
+    };
+
+    let query = conn
+        .call(move |conn| {
+            let mut stmt = conn.prepare("SELECT id, name, hash, role, disabled, service_account FROM users WHERE id = ?1")?;
+            let users = stmt
+                .query_map(params![id], UserMapper::map)?
+                .collect::<std::result::Result<Vec<User>, rusqlite::Error>>()?;
+            Ok::<_, rusqlite::Error>(users)
+        })
+        .await;
+    let user = match query {
+        Ok(rows) if rows.len() == 1 => rows[0].clone(),
+        Ok(_) => return Json(json!({"result": "error", "message": "Login Expired, Please Log In Again"})),
+        Err(err) => {
+            tracing::error!("Login DB Error: {:?}", err);
+            return Json(json!({"result": "error", "message": "Problem creating session"}));
+        },
+    };
+
+    let user_key = lockout::user_key(&user.name);
+    let ip_key = lockout::ip_key(&addr.ip());
+    if is_locked(&conn, &user.name, addr, &user_key, &ip_key).await {
+        return Json(json!({"result": "error", "message": "Too Many Failed Attempts, Try Again Later"}));
+    }
+
+    let result = match webauthn.finish_passkey_authentication(&finish.credential, &authentication) {
+        Ok(result) => result,
+        Err(err) => {
+            tracing::error!("Passkey rejected for {}: {:?}", user.name, err);
+            login_failed(&conn, &config.lockout, &user.name, addr, user_key, ip_key, "passkey").await;
+            return Json(json!({"result": "error", "message": "Passkey Not Accepted"}));
+        },
+    };
+    match passkey::used(&conn, user.id, &result).await {
+        Ok(Some(_)) => (),
+        Ok(None) => {
+            // The passkey was deleted while the login was pending
+            tracing::error!("Passkey no longer registered for: {}", user.name);
+            return Json(json!({"result": "error", "message": "Passkey Not Accepted"}));
+        },
+        Err(err) => {
+            tracing::error!("Passkey update db err: {:?}", err);
+            return Json(json!({"result": "error", "message": "Problem creating session"}));
+        },
+    }
+
+    if user.disabled {
+        tracing::error!("Login attempt for disabled user: {}", user.name);
+        audit::record(&conn, AuditEvent::new("login.failure").anonymous(&user.name).ip(&addr).details(json!({"reason": "disabled"}))).await;
+        return Json(json!({"result": "error", "message": "Account Disabled"}));
+    }
+    if user.service_account {
+        tracing::error!("Passkey login attempt for service account: {}", user.name);
+        audit::record(&conn, AuditEvent::new("login.failure").anonymous(&user.name).ip(&addr).details(json!({"step": "passkey", "reason": "service account"}))).await;
+        return Json(json!({"result": "error", "message": "Service Accounts Can Not Log In"}));
+    }
+
+    if finish.remember {
+        remember_session(&session, &config).await;
+    }
+    // A passkey already proves possession and user verification so TOTP isn't asked for
+    login_response(complete_login(&mut auth, &conn, &user, addr, user_key, "passkey").await)
+}
+
+/// Proof that the logged in user is at the keyboard. One of them is enough.
+#[derive(Deserialize)]
+pub struct ConfirmIdentity {
+    password: Option<String>,
+    /// TOTP or recovery code
+    code: Option<String>,
+    /// Answer to the challenge from `confirm_start`
+    credential: Option<PublicKeyCredential>,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterPasskey {
+    /// Label so the user can tell their passkeys apart
+    name: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginStart {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginFinish {
+    credential: PublicKeyCredential,
+    #[serde(default)]
+    remember: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use serde_json::Value;
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+    use webauthn_rs::prelude::{CreationChallengeResponse, RequestChallengeResponse, Url};
+
+    use super::*;
+    use crate::{lockout::LockoutPolicy, routes::harness::TestApp, user::hash_password};
+
+    const PASSWORD: &str = "a long enough password";
+
+    type Authenticator = WebauthnAuthenticator<SoftPasskey>;
+
+    /// Add a viewer with a passkey on a fresh software authenticator
+    async fn user_with_passkey(app: &TestApp, config: &Config, name: &str) -> Authenticator {
+        let insert_name = name.to_string();
+        let user_id = app
+            .conn
+            .call(move |conn| {
+                conn.execute("INSERT INTO users (name, hash, role) VALUES (?1, 'x', 'viewer')", params![insert_name])?;
+                Ok::<_, rusqlite::Error>(conn.last_insert_rowid())
+            })
+            .await
+            .unwrap();
+        let webauthn = passkey::webauthn(&config.passkey).unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+        let (challenge, state) = webauthn.start_passkey_registration(passkey::user_handle(user_id), name, name, None).unwrap();
+        let credential = authenticator.do_registration(origin(config), challenge).unwrap();
+        let passkey = webauthn.finish_passkey_registration(&credential, &state).unwrap();
+        passkey::add(&app.conn, user_id, "laptop".to_string(), &passkey).await.unwrap();
+        authenticator
+    }
+
+    fn origin(config: &Config) -> Url {
+        Url::parse(&config.passkey.rp_origin).unwrap()
+    }
+
+    /// Ask for a challenge and answer it with the authenticator
+    async fn answer(app: &mut TestApp, config: &Config, authenticator: &mut Authenticator, name: &str) -> PublicKeyCredential {
+        let response = app.post("/auth/passkey/login/start", json!({"username": name})).await;
+        assert_eq!(response.body["result"], "ok", "{}", response.body);
+        let options: RequestChallengeResponse = serde_json::from_value(response.body["options"].clone()).unwrap();
+        authenticator.do_authentication(origin(config), options).unwrap()
+    }
+
+    async fn finish(app: &mut TestApp, credential: &PublicKeyCredential) -> Value {
+        app.post("/auth/passkey/login/finish", json!({"credential": credential})).await.body
+    }
+
+    #[tokio::test]
+    async fn passkey_login_creates_a_session_once() {
+        let config = Config::default();
+        let mut app = TestApp::new(config.clone()).await;
+        let mut authenticator = user_with_passkey(&app, &config, "alice").await;
+        assert_eq!(app.get("/secure/check").await.status, StatusCode::UNAUTHORIZED);
+
+        let credential = answer(&mut app, &config, &mut authenticator, "alice").await;
+        let body = finish(&mut app, &credential).await;
+        assert_eq!(body["result"], "ok", "{}", body);
+        assert_eq!(body["user"], "alice");
+        let check = app.get("/secure/check").await;
+        assert_eq!(check.status, StatusCode::OK);
+        assert_eq!(check.body["user"], "alice");
+
+        // The challenge was used up so the same answer can't log in again
+        assert_eq!(finish(&mut app, &credential).await["message"], "Login Expired, Please Log In Again");
+    }
+
+    #[tokio::test]
+    async fn finishing_needs_a_pending_login() {
+        let config = Config::default();
+        let mut app = TestApp::new(config.clone()).await;
+        let mut authenticator = user_with_passkey(&app, &config, "alice").await;
+        let credential = answer(&mut app, &config, &mut authenticator, "alice").await;
+
+        // A session that didn't start the login can't finish it
+        let mut other = TestApp::new(config.clone()).await;
+        assert_eq!(finish(&mut other, &credential).await["message"], "Login Expired, Please Log In Again");
+        let response = app.post("/auth/passkey/login/start", json!({"username": "nobody"})).await;
+        assert_eq!(response.body["message"], "No Passkey For This User");
+    }
+
+    #[tokio::test]
+    async fn wrong_answers_lock_the_user_out() {
+        let config = Config { lockout: LockoutPolicy { max_failures: 1, ..LockoutPolicy::default() }, ..Config::default() };
+        let mut app = TestApp::new(config.clone()).await;
+        let mut authenticator = user_with_passkey(&app, &config, "alice").await;
+
+        // An answer to an older challenge doesn't match the pending one
+        let stale = answer(&mut app, &config, &mut authenticator, "alice").await;
+        answer(&mut app, &config, &mut authenticator, "alice").await;
+        assert_eq!(finish(&mut app, &stale).await["message"], "Passkey Not Accepted");
+
+        let response = app.post("/auth/passkey/login/start", json!({"username": "alice"})).await;
+        assert_eq!(response.body["message"], "Too Many Failed Attempts, Try Again Later");
+        assert_eq!(app.get("/secure/check").await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn disabled_and_service_accounts_can_not_log_in() {
+        let config = Config::default();
+        let mut app = TestApp::new(config.clone()).await;
+        let mut disabled = user_with_passkey(&app, &config, "bob").await;
+        let mut service = user_with_passkey(&app, &config, "backup").await;
+        app.conn
+            .call(|conn| {
+                conn.execute("UPDATE users SET disabled = 1 WHERE name = 'bob'", [])?;
+                conn.execute("UPDATE users SET service_account = 1 WHERE name = 'backup'", [])
+            })
+            .await
+            .unwrap();
+
+        let credential = answer(&mut app, &config, &mut disabled, "bob").await;
+        assert_eq!(finish(&mut app, &credential).await["message"], "Account Disabled");
+        let credential = answer(&mut app, &config, &mut service, "backup").await;
+        assert_eq!(finish(&mut app, &credential).await["message"], "Service Accounts Can Not Log In");
+        assert_eq!(app.get("/secure/check").await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn registering_needs_the_password_or_a_passkey() {
+        let config = Config { lockout: LockoutPolicy { base_delay: Duration::ZERO, ..LockoutPolicy::default() }, ..Config::default() };
+        let mut app = TestApp::new(config.clone()).await;
+        let hash = hash_password(PASSWORD).unwrap();
+        app.conn
+            .call(move |conn| conn.execute("INSERT INTO users (name, hash, role) VALUES ('alice', ?1, 'viewer')", params![hash]))
+            .await
+            .unwrap();
+        let login = app.post("/auth/login", json!({"username": "alice", "password": PASSWORD})).await;
+        assert_eq!(login.body["result"], "ok", "{}", login.body);
+
+        // A logged in session alone can't add a passkey
+        for confirm in [json!({}), json!({"password": "not the password"}), json!({"code": "123456"})] {
+            let response = app.post("/auth/passkey/register/start", confirm).await;
+            assert_eq!(response.body["message"], "Password, Code Or Passkey Wrong");
+        }
+        assert_eq!(app.post("/auth/passkey/confirm/start", json!({})).await.body["message"], "No Passkey For This User");
+        assert!(passkey::list(&app.conn, 1).await.unwrap().is_empty());
+
+        // With the password the first one can be added
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+        let start = app.post("/auth/passkey/register/start", json!({"password": PASSWORD})).await;
+        assert_eq!(start.body["result"], "ok", "{}", start.body);
+        let options: CreationChallengeResponse = serde_json::from_value(start.body["options"].clone()).unwrap();
+        let credential = authenticator.do_registration(origin(&config), options).unwrap();
+        let finish = app.post("/auth/passkey/register/finish", json!({"name": "laptop", "credential": credential})).await;
+        assert_eq!(finish.body["result"], "ok", "{}", finish.body);
+
+        // Then that passkey confirms it is the user, once per challenge
+        let confirm = app.post("/auth/passkey/confirm/start", json!({})).await;
+        let options: RequestChallengeResponse = serde_json::from_value(confirm.body["options"].clone()).unwrap();
+        let credential = authenticator.do_authentication(origin(&config), options).unwrap();
+        let start = app.post("/auth/passkey/register/start", json!({"credential": credential})).await;
+        assert_eq!(start.body["result"], "ok", "{}", start.body);
+        let replay = app.post("/auth/passkey/register/start", json!({"credential": credential})).await;
+        assert_eq!(replay.body["message"], "Password, Code Or Passkey Wrong");
+    }
+}
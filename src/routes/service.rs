@@ -1,16 +1,320 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, net::SocketAddr};
 
-use axum::{extract::State, response::IntoResponse, Json, Extension};
-use serde_json::json;
+use axum::{extract::{ConnectInfo, Path, Query, State}, http::StatusCode, response::IntoResponse, Json, Extension};
+use rusqlite::params;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use tokio_rusqlite::Connection;
 
-use crate::{services::Service, user::User};
+use crate::{
+    audit::{self, AuditEvent},
+    auth::{Caller, JsonError},
+    routes::user::is_unique_violation,
+    services::{self, Service, ServiceStatus},
+    token::Scope,
+    user::User,
+};
+
+/// Most status changes returned at once
+const MAX_HISTORY: u32 = 1000;
 
 /// List all services by name
 pub async fn get_services(State(conn): State<Connection>, Extension(_user): Extension<User>) -> impl IntoResponse {
     tracing::info!("Getting services");
-    // get all services from db
+    services_by_name(&conn).await
+}
+
+/// List all services organized by the server they belong to.
+pub async fn get_services_by_server(State(conn): State<Connection>, Extension(_user): Extension<User>) -> impl IntoResponse {
+    tracing::info!("Getting services grouped by server");
+    services_by_server(&conn).await
+}
+
+/// List all services by name for API token callers with `services:read`
+pub async fn api_get_services(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesRead)?;
+    tracing::info!("Getting services for {} with token {}", caller.user_name, caller.token_id);
+    Ok(services_by_name(&conn).await)
+}
+
+/// List all services grouped by server for API token callers with `services:read`
+pub async fn api_get_services_by_server(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesRead)?;
+    tracing::info!("Getting services grouped by server for {} with token {}", caller.user_name, caller.token_id);
+    Ok(services_by_server(&conn).await)
+}
+
+/// Register a new service
+pub async fn create_service(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(new_service): Json<NewService>,
+) -> impl IntoResponse {
+    tracing::info!("{} creating service: {}", admin.name, new_service.name);
+    create(&conn, new_service, AuditEvent::new("service.create").user(&admin).ip(&addr)).await
+}
+
+/// Change the server or status of a service. Fields that are not provided are left unchanged.
+/// A status change is added to the history of the service with the optional `reason`.
+pub async fn update_service(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Json(update_service): Json<UpdateService>,
+) -> impl IntoResponse {
+    tracing::info!("{} updating service: {}", admin.name, name);
+    update(&conn, name, update_service, AuditEvent::new("service.update").user(&admin).ip(&addr)).await
+}
+
+/// Give a service a new name
+pub async fn rename_service(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Json(rename_service): Json<RenameService>,
+) -> impl IntoResponse {
+    tracing::info!("{} renaming service {} to {}", admin.name, name, rename_service.name);
+    rename(&conn, name, rename_service.name, AuditEvent::new("service.rename").user(&admin).ip(&addr)).await
+}
+
+/// Remove a service from the registry
+pub async fn delete_service(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    tracing::info!("{} deleting service: {}", admin.name, name);
+    delete(&conn, name, AuditEvent::new("service.delete").user(&admin).ip(&addr)).await
+}
+
+/// Status timeline of a service, newest first
+pub async fn get_service_history(
+    State(conn): State<Connection>,
+    Extension(_user): Extension<User>,
+    Path(name): Path<String>,
+    Query(filter): Query<HistoryFilter>,
+) -> impl IntoResponse {
+    tracing::info!("Getting status history of service: {}", name);
+    status_history(&conn, name, filter).await
+}
+
+/// Status timeline of a service for API token callers with `services:read`
+pub async fn api_get_service_history(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+    Query(filter): Query<HistoryFilter>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesRead)?;
+    tracing::info!("Getting status history of {} for {} with token {}", name, caller.user_name, caller.token_id);
+    Ok(status_history(&conn, name, filter).await)
+}
+
+/// Register a new service for API token callers with `services:write`
+pub async fn api_create_service(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(new_service): Json<NewService>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesWrite)?;
+    tracing::info!("Creating service {} for {} with token {}", new_service.name, caller.user_name, caller.token_id);
+    Ok(create(&conn, new_service, AuditEvent::new("service.create").caller(&caller).ip(&addr)).await)
+}
+
+/// Update a service for API token callers with `services:write`
+pub async fn api_update_service(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Json(update_service): Json<UpdateService>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesWrite)?;
+    tracing::info!("Updating service {} for {} with token {}", name, caller.user_name, caller.token_id);
+    Ok(update(&conn, name, update_service, AuditEvent::new("service.update").caller(&caller).ip(&addr)).await)
+}
+
+/// Rename a service for API token callers with `services:write`
+pub async fn api_rename_service(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    Json(rename_service): Json<RenameService>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesWrite)?;
+    tracing::info!("Renaming service {} to {} for {} with token {}", name, rename_service.name, caller.user_name, caller.token_id);
+    Ok(rename(&conn, name, rename_service.name, AuditEvent::new("service.rename").caller(&caller).ip(&addr)).await)
+}
+
+/// Delete a service for API token callers with `services:write`
+pub async fn api_delete_service(
+    State(conn): State<Connection>,
+    Extension(caller): Extension<Caller>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, (StatusCode, Json<JsonError>)> {
+    caller.require(Scope::ServicesWrite)?;
+    tracing::info!("Deleting service {} for {} with token {}", name, caller.user_name, caller.token_id);
+    Ok(delete(&conn, name, AuditEvent::new("service.delete").caller(&caller).ip(&addr)).await)
+}
+
+/// Validate and insert a service. `event` already has the actor and is recorded on success.
+/// The starting status is the first entry in the history of the service with the actor as its source.
+async fn create(conn: &Connection, new_service: NewService, event: AuditEvent) -> Json<Value> {
+    let checks = services::validate_service_name(&new_service.name)
+        .and_then(|_| services::validate_name("Server", &new_service.server));
+    if let Err(message) = checks {
+        return Json(json!({"result": "error", "message": message}));
+    }
+
+    let service = Service { name: new_service.name, server: new_service.server, status: new_service.status };
+    let details = json!({"server": service.server, "status": service.status});
+    let name = service.name.clone();
+    let source = event.actor();
     let query = conn
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO services (name, server, status) VALUES (?1, ?2, ?3)",
+                params![service.name, service.server, service.status],
+            )?;
+            services::record_transition(&tx, &service.name, None, service.status, &source, None)?;
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(())
+        })
+        .await;
+
+    match query {
+        Ok(_) => {
+            audit::record(conn, event.target(&name).details(details)).await;
+            Json(json!({"result": "ok", "name": name}))
+        },
+        Err(err) if is_unique_violation(&err) => Json(json!({"result": "error", "message": "Service Already Exists"})),
+        Err(err) => {
+            tracing::error!("Service insert db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Creating Service"}))
+        },
+    }
+}
+
+/// Validate and apply an update to a service. A status change has the actor of `event` as its source.
+async fn update(conn: &Connection, name: String, update: UpdateService, event: AuditEvent) -> Json<Value> {
+    if update.server.is_none() && update.status.is_none() {
+        return Json(json!({"result": "error", "message": "Nothing To Update"}));
+    }
+    if let Err(message) = update.server.as_deref().map_or(Ok(()), |server| services::validate_name("Server", server)) {
+        return Json(json!({"result": "error", "message": message}));
+    }
+
+    let details = json!({"server": update.server, "status": update.status, "reason": update.reason});
+    let target = name.clone();
+    let source = event.actor();
+    let query = conn
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            // NULL keeps the current server
+            let found = tx.execute("UPDATE services SET server = COALESCE(?1, server) WHERE name = ?2", params![update.server, name])?;
+            if found == 0 {
+                return Ok::<_, rusqlite::Error>(false);
+            }
+            if let Some(status) = update.status {
+                services::change_status(&tx, &name, status, &source, update.reason.as_deref())?;
+            }
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(true)
+        })
+        .await;
+
+    match query {
+        Ok(false) => Json(json!({"result": "error", "message": "Service Not Found"})),
+        Ok(true) => {
+            audit::record(conn, event.target(&target).details(details)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Service update db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Updating Service"}))
+        },
+    }
+}
+
+/// Validate a new name and rename a service
+async fn rename(conn: &Connection, name: String, new_name: String, event: AuditEvent) -> Json<Value> {
+    if let Err(message) = services::validate_service_name(&new_name) {
+        return Json(json!({"result": "error", "message": message}));
+    }
+    if new_name == name {
+        return Json(json!({"result": "error", "message": "Service Already Has That Name"}));
+    }
+
+    let details = json!({"from": name, "to": new_name});
+    let target = new_name.clone();
+    let query = conn
+        .call(move |conn| conn.execute("UPDATE services SET name = ?1 WHERE name = ?2", params![new_name, name]))
+        .await;
+
+    match query {
+        Ok(0) => Json(json!({"result": "error", "message": "Service Not Found"})),
+        Ok(_) => {
+            audit::record(conn, event.target(&target).details(details)).await;
+            Json(json!({"result": "ok", "name": target}))
+        },
+        Err(err) if is_unique_violation(&err) => Json(json!({"result": "error", "message": "Service Already Exists"})),
+        Err(err) => {
+            tracing::error!("Service rename db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Renaming Service"}))
+        },
+    }
+}
+
+/// Delete a service by name
+async fn delete(conn: &Connection, name: String, event: AuditEvent) -> Json<Value> {
+    let target = name.clone();
+    let query = conn
+        .call(move |conn| conn.execute("DELETE FROM services WHERE name = ?1", params![name]))
+        .await;
+
+    match query {
+        Ok(0) => Json(json!({"result": "error", "message": "Service Not Found"})),
+        Ok(_) => {
+            audit::record(conn, event.target(&target)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Service delete db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Deleting Service"}))
+        },
+    }
+}
+
+/// Json response with the status history of a service
+async fn status_history(conn: &Connection, name: String, filter: HistoryFilter) -> Json<Value> {
+    let limit = filter.limit.unwrap_or(100).clamp(1, MAX_HISTORY);
+    match services::history(conn, name.clone(), filter.since, filter.until, limit).await {
+        Ok(Some(transitions)) => Json(json!({"result": "ok", "service": name, "history": transitions})),
+        Ok(None) => Json(json!({"result": "error", "message": "Service Not Found"})),
+        Err(err) => {
+            tracing::error!("Service history db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Getting Service History From DB"}))
+        },
+    }
+}
+
+/// Get every service from the db
+async fn load_services(conn: &Connection) -> Result<Vec<Service>, rusqlite::Error> {
+    conn
         .call(move |conn| { 
             // Sql query
             let mut stmt = conn.prepare("Select name, server, status FROM services")?;
@@ -27,12 +331,15 @@ pub async fn get_services(State(conn): State<Connection>, Extension(_user): Exte
                 .collect::<std::result::Result<Vec<Service>, rusqlite::Error>>()?;
             Ok::<_, rusqlite::Error>(services)
         })
-        .await;
-    
-    match query {
+        .await
+}
+
+/// Json response with every service keyed by name
+async fn services_by_name(conn: &Connection) -> Json<Value> {
+    match load_services(conn).await {
         // Services found
         Ok(rows) => {
-            // Build a json response from our services and group them by server
+            // Build a json response from our services keyed by name
             let mut map = HashMap::new();
             for service in rows {
                 map.insert(service.name.clone(), service);
@@ -50,30 +357,9 @@ pub async fn get_services(State(conn): State<Connection>, Extension(_user): Exte
     }
 }
 
-/// List all services organized by the server they belong to.
-pub async fn get_services_by_server(State(conn): State<Connection>, Extension(_user): Extension<User>) -> impl IntoResponse {
-    tracing::info!("Getting services grouped by server");
-    // get all services from db
-    let query = conn
-        .call(move |conn| { 
-            // Sql query
-            let mut stmt = conn.prepare("Select name, server, status FROM services")?;
-            // submit the query and get all the services
-            let services = stmt
-                .query_map([], |row| {
-                    // return the Service struct
-                    Ok(Service {
-                        name: row.get(0)?,
-                        server: row.get(1)?,
-                        status: row.get(2)?,
-                    })
-                })?
-                .collect::<std::result::Result<Vec<Service>, rusqlite::Error>>()?;
-            Ok::<_, rusqlite::Error>(services)
-        })
-        .await;
-    
-    match query {
+/// Json response with every service grouped by server
+async fn services_by_server(conn: &Connection) -> Json<Value> {
+    match load_services(conn).await {
         // Services found
         Ok(rows) => {
             // Build a json response from our services and group them by server
@@ -93,4 +379,171 @@ pub async fn get_services_by_server(State(conn): State<Connection>, Extension(_u
             Json(json!({"result": "error", "message": "Error Getting Services From DB"}))
         },
     }
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize)]
+pub struct NewService {
+    name: String,
+    server: String,
+    /// Defaults to unknown until something reports on the service
+    #[serde(default)]
+    status: ServiceStatus,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateService {
+    server: Option<String>,
+    status: Option<ServiceStatus>,
+    /// Why the status changed. Kept in the status history.
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryFilter {
+    /// unix timestamp, inclusive
+    since: Option<i64>,
+    /// unix timestamp, exclusive
+    until: Option<i64>,
+    limit: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct RenameService {
+    name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::Config, routes::harness::TestApp, user::hash_password};
+
+    use super::*;
+
+    const PASSWORD: &str = "a long enough password";
+
+    /// App with a logged in operator
+    async fn operator_app() -> TestApp {
+        let mut app = TestApp::new(Config::default()).await;
+        let hash = hash_password(PASSWORD).unwrap();
+        app.conn
+            .call(move |conn| conn.execute("INSERT INTO users (name, hash, role) VALUES ('ops', ?1, 'operator')", params![hash]))
+            .await
+            .unwrap();
+        let response = app.post("/auth/login", json!({"username": "ops", "password": PASSWORD})).await;
+        assert_eq!(response.body["result"], "ok", "{}", response.body);
+        app
+    }
+
+    async fn history_len(app: &TestApp, name: &'static str) -> i64 {
+        app.conn
+            .call(move |conn| conn.query_row("SELECT COUNT(*) FROM service_status_history WHERE service = ?1", params![name], |row| row.get(0)))
+            .await
+            .unwrap()
+    }
+
+    async fn audit_count(app: &TestApp, action: &'static str) -> i64 {
+        app.conn
+            .call(move |conn| conn.query_row("SELECT COUNT(*) FROM audit WHERE action = ?1", params![action], |row| row.get(0)))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn services_are_validated_and_unique() {
+        let mut app = operator_app().await;
+        for (name, message) in [
+            ("", "Service Name Can Not Be Empty"),
+            ("net.watch", "Service Name Can Only Contain Letters, Numbers, - and _"),
+            ("graph", "Service Name graph Is Reserved"),
+        ] {
+            let response = app.post("/services", json!({"name": name, "server": "Main"})).await;
+            assert_eq!(response.body["message"], message);
+        }
+        let response = app.post("/services", json!({"name": "netwatch", "server": "Main 2"})).await;
+        assert_eq!(response.body["message"], "Server Name Can Only Contain Letters, Numbers, - and _");
+
+        let response = app.post("/services", json!({"name": "netwatch", "server": "Main", "status": "up"})).await;
+        assert_eq!(response.body["result"], "ok", "{}", response.body);
+        assert_eq!(history_len(&app, "netwatch").await, 1);
+        let response = app.post("/services", json!({"name": "netwatch", "server": "Other"})).await;
+        assert_eq!(response.body["message"], "Service Already Exists");
+        assert_eq!(audit_count(&app, "service.create").await, 1);
+
+        let response = app.get("/services").await;
+        assert_eq!(response.body["services"]["netwatch"]["server"], "Main");
+        assert_eq!(response.body["services"]["netwatch"]["status"], "up");
+    }
+
+    #[tokio::test]
+    async fn updates_change_the_server_and_record_the_status() {
+        let mut app = operator_app().await;
+        app.post("/services", json!({"name": "netwatch", "server": "Main"})).await;
+
+        let response = app.put("/services/netwatch", json!({})).await;
+        assert_eq!(response.body["message"], "Nothing To Update");
+        let response = app.put("/services/netwatch", json!({"server": "a.b"})).await;
+        assert_eq!(response.body["message"], "Server Name Can Only Contain Letters, Numbers, - and _");
+        let response = app.put("/services/nope", json!({"status": "up"})).await;
+        assert_eq!(response.body["message"], "Service Not Found");
+
+        let response = app.put("/services/netwatch", json!({"server": "Backup", "status": "maintenance", "reason": "move"})).await;
+        assert_eq!(response.body["result"], "ok", "{}", response.body);
+        let response = app.get("/services/netwatch/history").await;
+        assert_eq!(response.body["history"][0]["to"], "maintenance");
+        assert_eq!(response.body["history"][0]["reason"], "move");
+        assert_eq!(response.body["history"][0]["source"], "user:ops");
+        assert_eq!(app.get("/services").await.body["services"]["netwatch"]["server"], "Backup");
+    }
+
+    #[tokio::test]
+    async fn failed_history_writes_roll_back_the_change() {
+        let mut app = operator_app().await;
+        app.post("/services", json!({"name": "netwatch", "server": "Main"})).await;
+        app.conn.call(|conn| conn.execute_batch("DROP TABLE service_status_history")).await.unwrap();
+
+        // Neither the new service nor the server of the update are kept without their history
+        let response = app.post("/services", json!({"name": "sentinel", "server": "Main"})).await;
+        assert_eq!(response.body["message"], "Error Creating Service");
+        let response = app.put("/services/netwatch", json!({"server": "Backup", "status": "down"})).await;
+        assert_eq!(response.body["message"], "Error Updating Service");
+
+        let services = app.get("/services").await.body["services"].clone();
+        assert!(services.get("sentinel").is_none());
+        assert_eq!(services["netwatch"]["server"], "Main");
+        assert_eq!(services["netwatch"]["status"], "unknown");
+    }
+
+    #[tokio::test]
+    async fn renames_keep_names_unique_and_take_the_history_along() {
+        let mut app = operator_app().await;
+        app.post("/services", json!({"name": "netwatch", "server": "Main"})).await;
+        app.post("/services", json!({"name": "sentinel", "server": "Main"})).await;
+
+        let response = app.post("/services/netwatch/rename", json!({"name": "netwatch"})).await;
+        assert_eq!(response.body["message"], "Service Already Has That Name");
+        let response = app.post("/services/netwatch/rename", json!({"name": "graph"})).await;
+        assert_eq!(response.body["message"], "Service Name graph Is Reserved");
+        let response = app.post("/services/netwatch/rename", json!({"name": "sentinel"})).await;
+        assert_eq!(response.body["message"], "Service Already Exists");
+        let response = app.post("/services/nope/rename", json!({"name": "other"})).await;
+        assert_eq!(response.body["message"], "Service Not Found");
+
+        let response = app.post("/services/netwatch/rename", json!({"name": "watcher"})).await;
+        assert_eq!(response.body["name"], "watcher", "{}", response.body);
+        assert_eq!(app.get("/services/netwatch/history").await.body["message"], "Service Not Found");
+        assert_eq!(history_len(&app, "watcher").await, 1);
+        assert_eq!(audit_count(&app, "service.rename").await, 1);
+    }
+
+    #[tokio::test]
+    async fn deleted_services_are_gone() {
+        let mut app = operator_app().await;
+        app.post("/services", json!({"name": "netwatch", "server": "Main"})).await;
+
+        let response = app.delete("/services/netwatch").await;
+        assert_eq!(response.body["result"], "ok", "{}", response.body);
+        let response = app.delete("/services/netwatch").await;
+        assert_eq!(response.body["message"], "Service Not Found");
+        assert!(app.get("/services").await.body["services"].get("netwatch").is_none());
+        assert_eq!(audit_count(&app, "service.delete").await, 1);
+    }
+}
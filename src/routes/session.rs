@@ -0,0 +1,147 @@
+use std::net::SocketAddr;
+
+use axum::{extract::{ConnectInfo, Path, State}, response::IntoResponse, Json, Extension};
+use axum_login::axum_sessions::SessionHandle;
+use serde_json::json;
+use tokio_rusqlite::Connection;
+
+use crate::{
+    audit::{self, AuditEvent},
+    session_store::{AnySessionStore, UserSessions},
+    user::User,
+};
+
+/// List the live sessions of the logged in user
+pub async fn get_sessions(
+    Extension(sessions): Extension<AnySessionStore>,
+    Extension(user): Extension<User>,
+    Extension(session): Extension<SessionHandle>,
+) -> impl IntoResponse {
+    let current = session.read().await.id().to_string();
+    match sessions.list_user_sessions(user.id, Some(current)).await {
+        Ok(sessions) => Json(json!({"result": "ok", "sessions": sessions})),
+        Err(err) => {
+            tracing::error!("Session fetch err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Getting Sessions From DB"}))
+        },
+    }
+}
+
+/// Revoke one session of the logged in user. Session ids have to be url encoded.
+pub async fn delete_session(
+    State(conn): State<Connection>,
+    Extension(sessions): Extension<AnySessionStore>,
+    Extension(user): Extension<User>,
+    Extension(session): Extension<SessionHandle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    tracing::info!("{} revoking session: {}", user.name, id);
+    let event = AuditEvent::new("session.revoke").user(&user).target(user.id).ip(&addr).details(json!({"session": id}));
+    // The current session would just be stored again after this request so destroy it through the session layer
+    {
+        let mut session = session.write().await;
+        if session.id() == id {
+            session.destroy();
+            drop(session);
+            audit::record(&conn, event).await;
+            return Json(json!({"result": "ok"}));
+        }
+    }
+
+    match sessions.destroy_user_session(user.id, id).await {
+        Ok(0) => Json(json!({"result": "error", "message": "Session Not Found"})),
+        Ok(_) => {
+            audit::record(&conn, event).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Session delete err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Revoking Session"}))
+        },
+    }
+}
+
+/// Revoke every session of the logged in user except the current one
+pub async fn delete_other_sessions(
+    State(conn): State<Connection>,
+    Extension(sessions): Extension<AnySessionStore>,
+    Extension(user): Extension<User>,
+    Extension(session): Extension<SessionHandle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    tracing::info!("{} revoking all other sessions", user.name);
+    let current = session.read().await.id().to_string();
+    match sessions.destroy_user_sessions(user.id, Some(current)).await {
+        Ok(count) => {
+            audit::record(&conn, AuditEvent::new("session.revoke").user(&user).target(user.id).ip(&addr).details(json!({"count": count}))).await;
+            Json(json!({"result": "ok", "revoked": count}))
+        },
+        Err(err) => {
+            tracing::error!("Session delete err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Revoking Sessions"}))
+        },
+    }
+}
+
+/// List the live sessions of any user
+pub async fn get_user_sessions(
+    Extension(sessions): Extension<AnySessionStore>,
+    Extension(session): Extension<SessionHandle>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    let current = session.read().await.id().to_string();
+    match sessions.list_user_sessions(id, Some(current)).await {
+        Ok(sessions) => Json(json!({"result": "ok", "sessions": sessions})),
+        Err(err) => {
+            tracing::error!("Session fetch err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Getting Sessions From DB"}))
+        },
+    }
+}
+
+/// Revoke one session of any user
+pub async fn delete_user_session(
+    State(conn): State<Connection>,
+    Extension(sessions): Extension<AnySessionStore>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((id, session_id)): Path<(i64, String)>,
+) -> impl IntoResponse {
+    tracing::info!("{} revoking session {} of user {}", admin.name, session_id, id);
+    let event = AuditEvent::new("session.revoke").user(&admin).target(id).ip(&addr).details(json!({"session": session_id}));
+    match sessions.destroy_user_session(id, session_id).await {
+        Ok(0) => Json(json!({"result": "error", "message": "Session Not Found"})),
+        Ok(_) => {
+            audit::record(&conn, event).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Session delete err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Revoking Session"}))
+        },
+    }
+}
+
+/// Revoke every session of any user. An admin's own current session is kept.
+pub async fn delete_user_sessions(
+    State(conn): State<Connection>,
+    Extension(sessions): Extension<AnySessionStore>,
+    Extension(admin): Extension<User>,
+    Extension(session): Extension<SessionHandle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    tracing::info!("{} revoking all sessions of user {}", admin.name, id);
+    let current = session.read().await.id().to_string();
+    match sessions.destroy_user_sessions(id, Some(current)).await {
+        Ok(count) => {
+            audit::record(&conn, AuditEvent::new("session.revoke").user(&admin).target(id).ip(&addr).details(json!({"count": count}))).await;
+            Json(json!({"result": "ok", "revoked": count}))
+        },
+        Err(err) => {
+            tracing::error!("Session delete err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Revoking Sessions"}))
+        },
+    }
+}
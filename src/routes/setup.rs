@@ -0,0 +1,165 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{extract::{ConnectInfo, State}, response::IntoResponse, Json, Extension};
+use serde::Deserialize;
+use serde_json::json;
+use tokio_rusqlite::Connection;
+
+use crate::{
+    audit::{self, AuditEvent},
+    config::Config,
+    lockout,
+    routes::user::password_policy_error,
+    setup::{self, Setup},
+    token,
+    user::{Role, User},
+};
+
+/// Public route the frontend uses to decide if it should show the setup page
+pub async fn get_setup(Extension(setup): Extension<Arc<Setup>>) -> impl IntoResponse {
+    Json(json!({"result": "ok", "required": setup.required()}))
+}
+
+/// Public route that creates the first admin. Needs the setup code printed at startup.
+pub async fn run_setup(
+    State(conn): State<Connection>,
+    Extension(setup): Extension<Arc<Setup>>,
+    Extension(config): Extension<Config>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(run): Json<RunSetup>,
+) -> impl IntoResponse {
+    if !setup.required() {
+        return Json(json!({"result": "error", "message": "Setup Is Already Done"}));
+    }
+    tracing::info!("Setup attempt from {}", addr.ip());
+
+    // Guessing the code counts the same as guessing a password
+    let ip_key = lockout::ip_key(&addr.ip());
+    match lockout::locked_until(&conn, vec![ip_key.clone()]).await {
+        Ok(None) => (),
+        Ok(Some(_)) => return Json(json!({"result": "error", "message": "Too Many Failed Attempts, Try Again Later"})),
+        Err(err) => {
+            tracing::error!("Lockout DB Error: {:?}", err);
+            return Json(json!({"result": "error", "message": "Too Many Failed Attempts, Try Again Later"}));
+        },
+    }
+    if !setup.verify(&run.code) {
+        tracing::error!("Setup code incorrect from {}", addr.ip());
+        audit::record(&conn, AuditEvent::new("setup.failure").anonymous(&run.username).ip(&addr)).await;
+        if let Err(err) = lockout::record_failure(&conn, ip_key, config.lockout.max_failures, config.lockout.lockout).await {
+            tracing::error!("Lockout DB Error: {:?}", err);
+        }
+        return Json(json!({"result": "error", "message": "Setup Code Wrong"}));
+    }
+
+    let username = run.username.trim();
+    if username.is_empty() {
+        return Json(json!({"result": "error", "message": "Username Can Not Be Empty"}));
+    }
+    if let Err(errors) = config.password.validate(username, &run.password) {
+        return password_policy_error(errors);
+    }
+    let user = match User::new(username, &run.password, 0, Role::Admin) {
+        Ok(user) => user,
+        Err(err) => {
+            tracing::error!("Could not hash password: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Creating User"}));
+        }
+    };
+
+    match setup::create_admin(&conn, user).await {
+        Ok(Some(id)) => {
+            setup.finish();
+            tracing::info!("Setup done. Created admin: {}", username);
+            audit::record(&conn, AuditEvent::new("setup.complete").anonymous(username).target(id).ip(&addr)).await;
+            // API_TOKEN was waiting for an admin to own it
+            if let Some(api_token) = config.api_token.clone() {
+                if let Err(err) = conn.call(move |conn| token::seed_env_token(conn, &api_token)).await {
+                    tracing::error!("Could not add API_TOKEN: {:?}", err);
+                }
+            }
+            Json(json!({"result": "ok", "id": id, "user": username}))
+        },
+        Ok(None) => {
+            // Someone else finished setup first
+            setup.finish();
+            Json(json!({"result": "error", "message": "Setup Is Already Done"}))
+        },
+        Err(err) => {
+            tracing::error!("Setup db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Creating User"}))
+        },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RunSetup {
+    code: String,
+    username: String,
+    password: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+    use crate::{lockout::LockoutPolicy, routes::harness::TestApp};
+
+    const PASSWORD: &str = "a long admin password";
+
+    async fn user_count(app: &TestApp) -> i64 {
+        app.conn.call(|conn| conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_are_rejected_and_locked_out() {
+        let config = Config { lockout: LockoutPolicy { max_failures: 2, ..LockoutPolicy::default() }, ..Config::default() };
+        let mut app = TestApp::new(config).await;
+        let code = app.setup.code().unwrap();
+
+        for _ in 0..2 {
+            let response = app.post("/setup", json!({"code": "aaaa-bbbb-cccc", "username": "root", "password": PASSWORD})).await;
+            assert_eq!(response.body["message"], "Setup Code Wrong");
+        }
+        // Even the right code is refused while the IP is locked out
+        let response = app.post("/setup", json!({"code": code, "username": "root", "password": PASSWORD})).await;
+        assert_eq!(response.body["message"], "Too Many Failed Attempts, Try Again Later");
+        assert!(app.setup.required());
+        assert_eq!(user_count(&app).await, 0);
+    }
+
+    #[tokio::test]
+    async fn setup_creates_one_admin_and_adds_api_token() {
+        let api_token = token::generate().0;
+        let config = Config { api_token: Some(api_token.clone()), ..Config::default() };
+        let mut app = TestApp::new(config).await;
+        let code = app.setup.code().unwrap();
+        assert_eq!(app.get("/setup").await.body["required"], true);
+
+        let response = app.post("/setup", json!({"code": code, "username": "root", "password": PASSWORD})).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body["result"], "ok");
+        assert_eq!(app.get("/setup").await.body["required"], false);
+
+        // The code is used up
+        let response = app.post("/setup", json!({"code": code, "username": "second", "password": PASSWORD})).await;
+        assert_eq!(response.body["message"], "Setup Is Already Done");
+        assert_eq!(user_count(&app).await, 1);
+
+        // API_TOKEN belongs to the new admin without a restart
+        let hash = token::hash(&api_token);
+        let owner = app
+            .conn
+            .call(move |conn| {
+                conn.query_row(
+                    "SELECT users.name FROM api_tokens JOIN users ON users.id = api_tokens.user_id WHERE hash = ?1",
+                    [hash],
+                    |row| row.get::<_, String>(0),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(owner, "root");
+    }
+}
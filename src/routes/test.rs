@@ -1,7 +1,7 @@
 use axum::{response::IntoResponse, Json, http::Request, body::Body, Extension};
 use serde_json::json;
 
-use crate::user::User;
+use crate::{auth::Caller, user::User};
 
 pub async fn test(_req: Request<Body>) -> impl IntoResponse {
     // add which route is requesting this?
@@ -13,12 +13,12 @@ pub async fn protected(Extension(user): Extension<User>) -> impl IntoResponse {
 }
 
 pub async fn check_cookie(Extension(user): Extension<User>) -> impl IntoResponse {
-    Json(json!({ "user":  user.name }))
+    Json(json!({ "user":  user.name, "role": user.role }))
 }
 
-pub async fn api_test() -> impl IntoResponse {
-    tracing::info!("Seeking api data");
+pub async fn api_test(Extension(caller): Extension<Caller>) -> impl IntoResponse {
+    tracing::info!("Seeking api data: {}", caller.user_name);
     Json(
-        json!({"result": "ok", "message": "You've reached the backend API by using a valid token."}),
+        json!({"result": "ok", "message": "You've reached the backend API by using a valid token.", "caller": caller}),
     )
 }
\ No newline at end of file
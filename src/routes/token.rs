@@ -0,0 +1,168 @@
+use std::net::SocketAddr;
+
+use axum::{extract::{ConnectInfo, Path, Query, State}, response::IntoResponse, Json, Extension};
+use rusqlite::params;
+use serde::Deserialize;
+use serde_json::json;
+use tokio_rusqlite::Connection;
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::unix_now,
+    token::{self, ApiToken, Scope},
+    user::{Role, User},
+};
+
+/// Mint a new API token. The raw token is only returned here, the db only keeps its hash.
+/// Admins can mint tokens for other users by setting `user_id`.
+pub async fn create_token(
+    State(conn): State<Connection>,
+    Extension(user): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(new_token): Json<NewToken>,
+) -> impl IntoResponse {
+    tracing::info!("{} creating token: {}", user.name, new_token.name);
+    if new_token.name.trim().is_empty() {
+        return Json(json!({"result": "error", "message": "Token Name Can Not Be Empty"}));
+    }
+    // Keeps the token from API_TOKEN easy to tell apart in the list
+    if new_token.name.trim() == token::ENV_TOKEN_NAME {
+        return Json(json!({"result": "error", "message": format!("Token Name {} Is Reserved", token::ENV_TOKEN_NAME)}));
+    }
+
+    // Find the owner and its role. Only admins can mint for someone else.
+    let owner_id = new_token.user_id.unwrap_or(user.id);
+    if owner_id != user.id && user.role != Role::Admin {
+        return Json(json!({"result": "error", "message": "Only Admins Can Create Tokens For Other Users"}));
+    }
+    let query = conn
+        .call(move |conn| {
+            let mut stmt = conn.prepare("SELECT role FROM users WHERE id = ?1 AND disabled = 0")?;
+            let roles = stmt
+                .query_map(params![owner_id], |row| row.get(0))?
+                .collect::<std::result::Result<Vec<Role>, rusqlite::Error>>()?;
+            Ok::<_, rusqlite::Error>(roles)
+        })
+        .await;
+    let owner_role = match query {
+        Ok(roles) if roles.len() == 1 => roles[0],
+        Ok(_) => return Json(json!({"result": "error", "message": "User Not Found"})),
+        Err(err) => {
+            tracing::error!("Token owner fetch db err: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Creating Token"}));
+        },
+    };
+
+    // A token can't do more than its owner
+    if let Some(scope) = new_token.scopes.iter().find(|scope| scope.min_role() > owner_role) {
+        return Json(json!({"result": "error", "message": format!("Scope {} Requires The {} Role", scope, scope.min_role())}));
+    }
+
+    let (raw, prefix, hash) = token::generate();
+    let scopes = token::scopes_to_string(&new_token.scopes);
+    let expires_at = new_token.expires_in_days.map(|days| unix_now() + i64::from(days) * 24 * 60 * 60);
+    let name = new_token.name.trim().to_string();
+    let details = json!({"name": name, "owner": owner_id, "scopes": new_token.scopes, "expires_at": expires_at});
+    let display_prefix = prefix.clone();
+    let query = conn
+        .call(move |conn| {
+            conn.execute(
+                "INSERT INTO api_tokens (name, prefix, hash, user_id, scopes, expires_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![name, display_prefix, hash, owner_id, scopes, expires_at],
+            )?;
+            Ok::<_, rusqlite::Error>(conn.last_insert_rowid())
+        })
+        .await;
+
+    match query {
+        Ok(id) => {
+            audit::record(&conn, AuditEvent::new("token.create").user(&user).target(id).ip(&addr).details(details)).await;
+            Json(json!({
+                "result": "ok",
+                "id": id,
+                "prefix": prefix,
+                // Shown once. It can't be recovered after this.
+                "token": raw,
+            }))
+        },
+        Err(err) => {
+            tracing::error!("Token insert db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Creating Token"}))
+        },
+    }
+}
+
+/// List the tokens of the logged in user. Admins can list every token with `?all=true`.
+pub async fn get_tokens(
+    State(conn): State<Connection>,
+    Extension(user): Extension<User>,
+    Query(query): Query<TokenQuery>,
+) -> impl IntoResponse {
+    // NULL matches every owner
+    let owner = if query.all && user.role == Role::Admin { None } else { Some(user.id) };
+    let query = conn
+        .call(move |conn| {
+            let mut stmt = conn.prepare("SELECT * FROM api_tokens WHERE ?1 IS NULL OR user_id = ?1 ORDER BY id")?;
+            let tokens = stmt
+                .query_map(params![owner], ApiToken::map)?
+                .collect::<std::result::Result<Vec<ApiToken>, rusqlite::Error>>()?;
+            Ok::<_, rusqlite::Error>(tokens)
+        })
+        .await;
+
+    match query {
+        Ok(tokens) => Json(json!({"result": "ok", "tokens": tokens})),
+        Err(err) => {
+            tracing::error!("Token fetch db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Getting Tokens From DB"}))
+        },
+    }
+}
+
+/// Revoke a token. Users can revoke their own tokens and admins can revoke any token.
+pub async fn delete_token(
+    State(conn): State<Connection>,
+    Extension(user): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    tracing::info!("{} revoking token: {}", user.name, id);
+    let owner = if user.role == Role::Admin { None } else { Some(user.id) };
+    let query = conn
+        .call(move |conn| {
+            conn.execute(
+                "DELETE FROM api_tokens WHERE id = ?1 AND (?2 IS NULL OR user_id = ?2)",
+                params![id, owner],
+            )
+        })
+        .await;
+
+    match query {
+        Ok(0) => Json(json!({"result": "error", "message": "Token Not Found"})),
+        Ok(_) => {
+            audit::record(&conn, AuditEvent::new("token.revoke").user(&user).target(id).ip(&addr)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Token delete db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Revoking Token"}))
+        },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewToken {
+    name: String,
+    #[serde(default)]
+    scopes: Vec<Scope>,
+    /// Token never expires if this isn't set
+    expires_in_days: Option<u32>,
+    /// Owner of the token. Defaults to the logged in user.
+    user_id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenQuery {
+    #[serde(default)]
+    all: bool,
+}
@@ -0,0 +1,155 @@
+use std::net::SocketAddr;
+
+use axum::{extract::{ConnectInfo, State}, response::IntoResponse, Json, Extension};
+use rusqlite::params;
+use serde::Deserialize;
+use serde_json::json;
+use tokio_rusqlite::Connection;
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::unix_now,
+    totp,
+    user::{verify_password, User},
+};
+
+/// Start TOTP enrollment for the logged in user. A new secret is stored but it isn't
+/// required at login until the user confirms it with a valid code.
+pub async fn enroll(State(conn): State<Connection>, Extension(user): Extension<User>) -> impl IntoResponse {
+    tracing::info!("TOTP enrollment started: {}", user.name);
+    let totp = match totp::generate(&user.name) {
+        Ok(totp) => totp,
+        Err(err) => {
+            tracing::error!("Could not create TOTP: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Starting Enrollment"}));
+        },
+    };
+
+    // Replace any unconfirmed secret but never one that is already enabled
+    let secret = totp.get_secret_base32();
+    let id = user.id;
+    let query = conn
+        .call(move |conn| {
+            conn.execute(
+                "INSERT INTO totp (user_id, secret, enabled) VALUES (?1, ?2, 0) ON CONFLICT(user_id) DO UPDATE SET secret=excluded.secret WHERE enabled = 0",
+                params![id, secret],
+            )
+        })
+        .await;
+
+    match query {
+        Ok(0) => Json(json!({"result": "error", "message": "TOTP Already Enabled"})),
+        Ok(_) => Json(json!({
+            "result": "ok",
+            "secret": totp.get_secret_base32(),
+            // otpauth:// uri for authenticator apps and the same as a base64 png QR code
+            "uri": totp.get_url(),
+            "qr": totp.get_qr_base64().ok(),
+        })),
+        Err(err) => {
+            tracing::error!("TOTP insert db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Starting Enrollment"}))
+        },
+    }
+}
+
+/// Finish TOTP enrollment with a code from the authenticator app.
+/// Returns the recovery codes. This is the only time they are shown.
+pub async fn confirm(
+    State(conn): State<Connection>,
+    Extension(user): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(confirm): Json<TotpCode>,
+) -> impl IntoResponse {
+    let record = match totp::load(&conn, user.id).await {
+        Ok(Some(record)) if !record.enabled => record,
+        Ok(Some(_)) => return Json(json!({"result": "error", "message": "TOTP Already Enabled"})),
+        Ok(None) => return Json(json!({"result": "error", "message": "TOTP Enrollment Not Started"})),
+        Err(err) => {
+            tracing::error!("TOTP fetch db err: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Confirming TOTP"}));
+        },
+    };
+
+    let step = totp::from_secret(&record.secret, &user.name)
+        .ok()
+        .and_then(|totp| totp::verify(&totp, &confirm.code, u64::try_from(unix_now()).unwrap_or(0)))
+        .and_then(|step| i64::try_from(step).ok());
+    let Some(step) = step else {
+        tracing::error!("TOTP confirmation code incorrect for: {}", user.name);
+        return Json(json!({"result": "error", "message": "Code Wrong"}));
+    };
+
+    // Enable TOTP and replace any old recovery codes in one transaction
+    let codes = totp::recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|code| totp::hash_recovery_code(code)).collect();
+    let id = user.id;
+    let query = conn
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("UPDATE totp SET enabled = 1, last_step = ?1 WHERE user_id = ?2", params![step, id])?;
+            tx.execute("DELETE FROM recovery_codes WHERE user_id = ?1", params![id])?;
+            for hash in hashes {
+                tx.execute("INSERT INTO recovery_codes (user_id, hash) VALUES (?1, ?2)", params![id, hash])?;
+            }
+            tx.commit()
+        })
+        .await;
+
+    match query {
+        Ok(_) => {
+            tracing::info!("TOTP enabled: {}", user.name);
+            audit::record(&conn, AuditEvent::new("totp.enable").user(&user).target(user.id).ip(&addr)).await;
+            Json(json!({"result": "ok", "recovery_codes": codes}))
+        },
+        Err(err) => {
+            tracing::error!("TOTP enable db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Confirming TOTP"}))
+        },
+    }
+}
+
+/// Turn TOTP off for the logged in user. Requires the current password.
+pub async fn disable(
+    State(conn): State<Connection>,
+    Extension(user): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(disable): Json<TotpDisable>,
+) -> impl IntoResponse {
+    if !verify_password(&user.hash, &disable.password) {
+        tracing::error!("Password incorrect disabling TOTP for: {}", user.name);
+        return Json(json!({"result": "error", "message": "Password Wrong"}));
+    }
+
+    let id = user.id;
+    let query = conn
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM totp WHERE user_id = ?1", params![id])?;
+            tx.execute("DELETE FROM recovery_codes WHERE user_id = ?1", params![id])?;
+            tx.commit()
+        })
+        .await;
+
+    match query {
+        Ok(_) => {
+            tracing::info!("TOTP disabled: {}", user.name);
+            audit::record(&conn, AuditEvent::new("totp.disable").user(&user).target(user.id).ip(&addr)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("TOTP disable db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Disabling TOTP"}))
+        },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TotpCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpDisable {
+    password: String,
+}
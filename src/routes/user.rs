@@ -0,0 +1,427 @@
+use std::net::SocketAddr;
+
+use axum::{extract::{ConnectInfo, Path, Query, State}, response::IntoResponse, Json, Extension};
+use axum_login::RusqliteUserMapper;
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::{params, OptionalExtension};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_rusqlite::Connection;
+
+use crate::{
+    audit::{self, AuditEvent},
+    config::Config,
+    lockout,
+    password::PasswordError,
+    session_store::{AnySessionStore, UserSessions},
+    user::{hash_password, Role, User, UserMapper},
+};
+
+/// List all users. Password hashes are never serialized.
+/// Filter with `?service_account=true` for only service accounts or `false` for only people.
+pub async fn get_users(
+    State(conn): State<Connection>,
+    Query(filter): Query<UserQuery>,
+) -> impl IntoResponse {
+    tracing::info!("Getting users");
+    // get all users from db
+    let query = conn
+        .call(move |conn| {
+            // Sql query
+            let mut stmt = conn.prepare("SELECT id, name, hash, role, disabled, service_account FROM users WHERE ?1 IS NULL OR service_account = ?1 ORDER BY id")?;
+            // submit the query and get all the users
+            let users = stmt
+                .query_map(params![filter.service_account], UserMapper::map)?
+                .collect::<std::result::Result<Vec<User>, rusqlite::Error>>()?;
+            Ok::<_, rusqlite::Error>(users)
+        })
+        .await;
+
+    match query {
+        Ok(users) => Json(json!({
+            "result": "ok",
+            "users": users,
+        })),
+        Err(err) => {
+            tracing::error!("User fetch db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Getting Users From DB"}))
+        },
+    }
+}
+
+/// Create a new user. The password is hashed with `User::new` before it is stored.
+/// With `service_account` set no password is needed. The account gets a random one nobody
+/// knows and can only be used through API tokens an admin mints for it.
+pub async fn create_user(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    Extension(config): Extension<Config>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(new_user): Json<NewUser>,
+) -> impl IntoResponse {
+    // Trimmed once so the policy checks the same name that is stored
+    let username = new_user.username.trim().to_string();
+    tracing::info!("{} creating user: {}", admin.name, username);
+    if username.is_empty() {
+        return Json(json!({"result": "error", "message": "Username Can Not Be Empty"}));
+    }
+    let service_account = new_user.service_account;
+    let password = if service_account {
+        unusable_password()
+    } else {
+        if let Err(errors) = config.password.validate(&username, &new_user.password) {
+            return password_policy_error(errors);
+        }
+        new_user.password
+    };
+
+    let role = new_user.role;
+    // id is set by the db so we just use 0 here
+    let user = match User::new(&username, &password, 0, new_user.role) {
+        Ok(user) => user,
+        Err(err) => {
+            tracing::error!("Could not hash password: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Creating User"}));
+        }
+    };
+
+    let query = conn
+        .call(move |conn| {
+            conn.execute(
+                "INSERT INTO users (name, hash, role, service_account) VALUES (?1, ?2, ?3, ?4)",
+                params![user.name, user.hash, user.role, service_account],
+            )?;
+            Ok::<_, rusqlite::Error>(conn.last_insert_rowid())
+        })
+        .await;
+
+    match query {
+        Ok(id) => {
+            audit::record(&conn, AuditEvent::new("user.create").user(&admin).target(id).ip(&addr).details(json!({"name": username, "role": role, "service_account": service_account}))).await;
+            Json(json!({"result": "ok", "id": id}))
+        },
+        Err(err) if is_unique_violation(&err) => {
+            Json(json!({"result": "error", "message": "Username Already Exists"}))
+        },
+        Err(err) => {
+            tracing::error!("User insert db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Creating User"}))
+        },
+    }
+}
+
+/// Update the name, password or role of a user. Fields that are not provided are left unchanged.
+pub async fn update_user(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    Extension(config): Extension<Config>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+    Json(update): Json<UpdateUser>,
+) -> impl IntoResponse {
+    tracing::info!("{} updating user: {}", admin.name, id);
+    // Don't let an admin lock themselves out by accident
+    if id == admin.id && update.role.map_or(false, |role| role != Role::Admin) {
+        return Json(json!({"result": "error", "message": "Can Not Remove Your Own Admin Role"}));
+    }
+    let name = update.username.map(|name| name.trim().to_string());
+    // Never put the password in the audit log, just whether it changed
+    let details = json!({"name": name, "role": update.role, "password_changed": update.password.is_some()});
+    if name.as_deref().map_or(false, str::is_empty) {
+        return Json(json!({"result": "error", "message": "Username Can Not Be Empty"}));
+    }
+    if let Some(password) = &update.password {
+        // The username rule is checked against the name the user has after the update
+        let username = match &name {
+            Some(name) => name.clone(),
+            None => {
+                let query = conn
+                    .call(move |conn| conn.query_row("SELECT name FROM users WHERE id = ?1", params![id], |row| row.get::<_, String>(0)).optional())
+                    .await;
+                match query {
+                    Ok(Some(name)) => name,
+                    Ok(None) => return Json(json!({"result": "error", "message": "User Not Found"})),
+                    Err(err) => {
+                        tracing::error!("User fetch db err: {:?}", err);
+                        return Json(json!({"result": "error", "message": "Error Updating User"}));
+                    },
+                }
+            },
+        };
+        if let Err(errors) = config.password.validate(&username, password) {
+            return password_policy_error(errors);
+        }
+    }
+    let hash = match update.password.as_deref().map(hash_password).transpose() {
+        Ok(hash) => hash,
+        Err(err) => {
+            tracing::error!("Could not hash password: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Updating User"}));
+        }
+    };
+
+    let query = conn
+        .call(move |conn| {
+            // NULL values keep the current column value
+            conn.execute(
+                "UPDATE users SET name = COALESCE(?1, name), hash = COALESCE(?2, hash), role = COALESCE(?3, role) WHERE id = ?4",
+                params![name, hash, update.role, id],
+            )
+        })
+        .await;
+
+    match query {
+        Ok(0) => Json(json!({"result": "error", "message": "User Not Found"})),
+        Ok(_) => {
+            audit::record(&conn, AuditEvent::new("user.update").user(&admin).target(id).ip(&addr).details(details)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) if is_unique_violation(&err) => {
+            Json(json!({"result": "error", "message": "Username Already Exists"}))
+        },
+        Err(err) => {
+            tracing::error!("User update db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Updating User"}))
+        },
+    }
+}
+
+/// Disable a user so they can no longer log in or use an existing session.
+pub async fn disable_user(
+    State(conn): State<Connection>,
+    Extension(sessions): Extension<AnySessionStore>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    tracing::info!("{} disabling user: {}", admin.name, id);
+    if id == admin.id {
+        return Json(json!({"result": "error", "message": "Can Not Disable Yourself"}));
+    }
+    set_disabled(&conn, &sessions, &admin, addr, id, true).await
+}
+
+/// Re-enable a disabled user.
+pub async fn enable_user(
+    State(conn): State<Connection>,
+    Extension(sessions): Extension<AnySessionStore>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    tracing::info!("{} enabling user: {}", admin.name, id);
+    set_disabled(&conn, &sessions, &admin, addr, id, false).await
+}
+
+/// Delete a user.
+pub async fn delete_user(
+    State(conn): State<Connection>,
+    Extension(sessions): Extension<AnySessionStore>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    tracing::info!("{} deleting user: {}", admin.name, id);
+    if id == admin.id {
+        return Json(json!({"result": "error", "message": "Can Not Delete Yourself"}));
+    }
+
+    let query = conn
+        .call(move |conn| conn.execute("DELETE FROM users WHERE id = ?1", params![id]))
+        .await;
+
+    match query {
+        Ok(0) => Json(json!({"result": "error", "message": "User Not Found"})),
+        Ok(_) => {
+            audit::record(&conn, AuditEvent::new("user.delete").user(&admin).target(id).ip(&addr)).await;
+            // Sessions aren't tied to the users table so remove them by hand
+            if let Err(err) = sessions.destroy_user_sessions(id, None).await {
+                tracing::error!("Session delete err: {:?}", err);
+            }
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("User delete db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Deleting User"}))
+        },
+    }
+}
+
+/// Clear failed login attempts for a user so a locked out account can log in again.
+pub async fn unlock_user(
+    State(conn): State<Connection>,
+    Extension(admin): Extension<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    // Lockouts are keyed by username so look it up first
+    let query = conn
+        .call(move |conn| {
+            let mut stmt = conn.prepare("Select name FROM users WHERE id = ?1")?;
+            let names = stmt
+                .query_map(params![id], |row| row.get(0))?
+                .collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
+            Ok::<_, rusqlite::Error>(names)
+        })
+        .await;
+
+    let name = match query {
+        Ok(names) if names.len() == 1 => names[0].clone(),
+        Ok(_) => return Json(json!({"result": "error", "message": "User Not Found"})),
+        Err(err) => {
+            tracing::error!("User fetch db err: {:?}", err);
+            return Json(json!({"result": "error", "message": "Error Unlocking User"}));
+        },
+    };
+
+    match lockout::clear(&conn, lockout::user_key(&name)).await {
+        Ok(_) => {
+            tracing::warn!("{} unlocked user: {}", admin.name, name);
+            audit::record(&conn, AuditEvent::new("user.unlock").user(&admin).target(id).ip(&addr)).await;
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("Lockout db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Unlocking User"}))
+        },
+    }
+}
+
+/// Helper to set the disabled flag on a user
+async fn set_disabled(conn: &Connection, sessions: &AnySessionStore, admin: &User, addr: SocketAddr, id: i64, disabled: bool) -> Json<serde_json::Value> {
+    let query = conn
+        .call(move |conn| {
+            conn.execute(
+                "UPDATE users SET disabled = ?1 WHERE id = ?2",
+                params![disabled, id],
+            )
+        })
+        .await;
+
+    match query {
+        Ok(0) => Json(json!({"result": "error", "message": "User Not Found"})),
+        Ok(_) => {
+            let action = if disabled { "user.disable" } else { "user.enable" };
+            audit::record(conn, AuditEvent::new(action).user(admin).target(id).ip(&addr)).await;
+            // A disabled user is already rejected everywhere but their sessions don't need to stick around
+            if disabled {
+                if let Err(err) = sessions.destroy_user_sessions(id, None).await {
+                    tracing::error!("Session delete err: {:?}", err);
+                }
+            }
+            Json(json!({"result": "ok"}))
+        },
+        Err(err) => {
+            tracing::error!("User disable db err: {:?}", err);
+            Json(json!({"result": "error", "message": "Error Updating User"}))
+        },
+    }
+}
+
+/// Long random password for service accounts. It is never shown to anyone so the account can't log in with it.
+fn unusable_password() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect()
+}
+
+/// Error response listing every password policy rule that was broken
+pub fn password_policy_error(errors: Vec<PasswordError>) -> Json<Value> {
+    Json(json!({"result": "error", "message": "Password Does Not Meet The Policy", "errors": errors}))
+}
+
+/// Check if a db error was caused by a UNIQUE or PRIMARY KEY constraint. Other constraints like
+/// NOT NULL or CHECK share the same primary code, so only the extended code tells them apart.
+pub fn is_unique_violation(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(err, _)
+            if err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE || err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+    )
+}
+
+/// Check if a db error was caused by a FOREIGN KEY constraint, like a row pointing at a missing user
+pub fn is_foreign_key_violation(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(err, _) if err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY
+    )
+}
+
+#[derive(Deserialize)]
+pub struct UserQuery {
+    service_account: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct NewUser {
+    username: String,
+    /// Not needed for service accounts
+    #[serde(default)]
+    password: String,
+    #[serde(default)]
+    role: Role,
+    #[serde(default)]
+    service_account: bool,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateUser {
+    username: Option<String>,
+    password: Option<String>,
+    role: Option<Role>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::harness::TestApp;
+
+    #[test]
+    fn only_unique_and_key_constraints_are_unique_violations() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+             CREATE TABLE parents(id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE);
+             CREATE TABLE children(id INTEGER PRIMARY KEY, parent_id INTEGER NOT NULL REFERENCES parents(id));
+             INSERT INTO parents(id, name) VALUES (1, 'a');",
+        )
+        .unwrap();
+
+        let unique = conn.execute("INSERT INTO parents(name) VALUES ('a')", []).unwrap_err();
+        assert!(is_unique_violation(&unique));
+        let primary_key = conn.execute("INSERT INTO parents(id, name) VALUES (1, 'b')", []).unwrap_err();
+        assert!(is_unique_violation(&primary_key));
+
+        let not_null = conn.execute("INSERT INTO parents(name) VALUES (NULL)", []).unwrap_err();
+        assert!(!is_unique_violation(&not_null));
+        let foreign_key = conn.execute("INSERT INTO children(parent_id) VALUES (2)", []).unwrap_err();
+        assert!(!is_unique_violation(&foreign_key));
+        assert!(is_foreign_key_violation(&foreign_key));
+    }
+
+    #[tokio::test]
+    async fn usernames_are_trimmed_before_the_password_policy() {
+        let mut app = TestApp::new(Config::default()).await;
+        let hash = hash_password("a long admin password").unwrap();
+        app.conn
+            .call(move |conn| conn.execute("INSERT INTO users (name, hash, role) VALUES ('root', ?1, 'admin')", params![hash]))
+            .await
+            .unwrap();
+        let response = app.post("/auth/login", json!({"username": "root", "password": "a long admin password"})).await;
+        assert_eq!(response.body["result"], "ok", "{}", response.body);
+
+        let response = app.post("/users", json!({"username": " alice ", "password": "alice wonderland"})).await;
+        assert_eq!(response.body["errors"][0]["code"], "contains_username", "{}", response.body);
+        let response = app.post("/users", json!({"username": " alice ", "password": "a long enough password"})).await;
+        assert_eq!(response.body["result"], "ok", "{}", response.body);
+        let name: String = app
+            .conn
+            .call(|conn| conn.query_row("SELECT name FROM users WHERE role = 'viewer'", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(name, "alice");
+    }
+}
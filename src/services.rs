@@ -1,11 +1,260 @@
+use anyhow::Result;
+use rusqlite::{params, OptionalExtension};
 use serde::{Deserialize, Serialize};
+use tokio_rusqlite::Connection;
+
+use crate::db_enum::db_enum;
+
+/// Longest name a service or server can have
+pub const MAX_NAME_LENGTH: usize = 64;
+/// Service names that are paths of their own under `/services`
+const RESERVED_NAMES: [&str; 1] = ["graph"];
 
 /// A Service managed by Night City
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Service {
     pub name: String,
     pub server: String,
-    pub status: i64,
+    pub status: ServiceStatus,
+}
+
+/// Health of a service
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceStatus {
+    /// Nothing has reported on the service yet
+    #[default]
+    Unknown,
+    Up,
+    /// Running but not working properly, like slow or partly failing
+    Degraded,
+    Down,
+    /// Taken down on purpose
+    Maintenance,
+}
+
+db_enum!(
+    ServiceStatus,
+    "service status",
+    Unknown => "unknown",
+    Up => "up",
+    Degraded => "degraded",
+    Down => "down",
+    Maintenance => "maintenance",
+);
+
+/// One status change of a service
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusTransition {
+    pub ts: i64,
+    /// `None` for the status a service was created with
+    pub from: Option<ServiceStatus>,
+    pub to: ServiceStatus,
+    /// Who or what changed it, like `user:alice`, `token:ci` or `prober`
+    pub source: String,
+    pub reason: Option<String>,
 }
 
+/// Check a service or server name. Service names are used as the last token of NATS subjects and
+/// in urls so names are kept to ASCII letters, numbers, `-` and `_`. `what` is used in the error message.
+pub fn validate_name(what: &str, name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err(format!("{} Name Can Not Be Empty", what));
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(format!("{} Name Must Be At Most {} Characters", what, MAX_NAME_LENGTH));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_')) {
+        return Err(format!("{} Name Can Only Contain Letters, Numbers, - and _", what));
+    }
+    Ok(())
+}
+
+/// Check the name of a service. Besides the rules of `validate_name` it can't be a name
+/// taken by a route under `/services`, like the graph.
+pub fn validate_service_name(name: &str) -> Result<(), String> {
+    validate_name("Service", name)?;
+    if RESERVED_NAMES.contains(&name) {
+        return Err(format!("Service Name {} Is Reserved", name));
+    }
+    Ok(())
+}
+
+/// Add the Cyberdeck and Fixer entries to an empty registry. Nothing is added once there is any
+/// service so renaming or deleting them through the api sticks across restarts.
+/// Returns whether the entries were added.
+pub fn seed_defaults(conn: &mut rusqlite::Connection) -> Result<bool, rusqlite::Error> {
+    let tx = conn.transaction()?;
+    let existing: i64 = tx.query_row("SELECT COUNT(*) FROM services", [], |row| row.get(0))?;
+    if existing > 0 {
+        return Ok(false);
+    }
+    for (name, status) in [("Cyberdeck", ServiceStatus::Up), ("Fixer", ServiceStatus::Unknown)] {
+        tx.execute("INSERT INTO services (name, server, status) VALUES (?1, 'Main', ?2)", params![name, status])?;
+        record_transition(&tx, name, None, status, "setup", None)?;
+    }
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Add a row to the status history. Use inside the same transaction that changed the status.
+pub fn record_transition(
+    conn: &rusqlite::Connection,
+    name: &str,
+    from: Option<ServiceStatus>,
+    to: ServiceStatus,
+    source: &str,
+    reason: Option<&str>,
+) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO service_status_history (service, from_status, to_status, source, reason) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![name, from, to, source, reason],
+    )?;
+    Ok(())
+}
 
+/// Change the status of a service and record the transition. Setting the status it already
+/// has does nothing. Returns the previous status or `None` if there is no such service.
+/// Use inside a transaction so the status and its history can't disagree.
+pub fn change_status(
+    conn: &rusqlite::Connection,
+    name: &str,
+    status: ServiceStatus,
+    source: &str,
+    reason: Option<&str>,
+) -> Result<Option<ServiceStatus>, rusqlite::Error> {
+    let current: Option<ServiceStatus> = conn
+        .query_row("SELECT status FROM services WHERE name = ?1", params![name], |row| row.get(0))
+        .optional()?;
+    let Some(current) = current else {
+        return Ok(None);
+    };
+    if current != status {
+        conn.execute("UPDATE services SET status = ?1 WHERE name = ?2", params![status, name])?;
+        record_transition(conn, name, Some(current), status, source, reason)?;
+    }
+    Ok(Some(current))
+}
+
+/// `change_status` in its own transaction
+pub async fn set_status(
+    conn: &Connection,
+    name: String,
+    status: ServiceStatus,
+    source: String,
+    reason: Option<String>,
+) -> Result<Option<ServiceStatus>, rusqlite::Error> {
+    conn.call(move |conn| {
+        let tx = conn.transaction()?;
+        let previous = change_status(&tx, &name, status, &source, reason.as_deref())?;
+        tx.commit()?;
+        Ok::<_, rusqlite::Error>(previous)
+    })
+    .await
+}
+
+/// Status changes of a service between `since` (inclusive) and `until` (exclusive), newest first.
+/// `None` if there is no such service.
+pub async fn history(
+    conn: &Connection,
+    name: String,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: u32,
+) -> Result<Option<Vec<StatusTransition>>, rusqlite::Error> {
+    conn.call(move |conn| {
+        let found = conn
+            .query_row("SELECT 1 FROM services WHERE name = ?1", params![name], |_| Ok(()))
+            .optional()?;
+        if found.is_none() {
+            return Ok::<_, rusqlite::Error>(None);
+        }
+        let mut stmt = conn.prepare(
+            "SELECT ts, from_status, to_status, source, reason FROM service_status_history
+             WHERE service = ?1 AND (?2 IS NULL OR ts >= ?2) AND (?3 IS NULL OR ts < ?3)
+             ORDER BY id DESC LIMIT ?4",
+        )?;
+        let transitions = stmt
+            .query_map(params![name, since, until, limit], |row| {
+                Ok(StatusTransition {
+                    ts: row.get(0)?,
+                    from: row.get(1)?,
+                    to: row.get(2)?,
+                    source: row.get(3)?,
+                    reason: row.get(4)?,
+                })
+            })?
+            .collect::<std::result::Result<Vec<StatusTransition>, rusqlite::Error>>()?;
+        Ok(Some(transitions))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::MIGRATIONS;
+
+    #[tokio::test]
+    async fn only_real_changes_are_recorded() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        conn.call(|conn| conn.execute("INSERT INTO services (name, server) VALUES ('Fixer', 'Main')", []))
+            .await
+            .unwrap();
+
+        let set = |status, reason: Option<&str>| set_status(&conn, "Fixer".into(), status, "test".into(), reason.map(String::from));
+        assert_eq!(set(ServiceStatus::Up, None).await.unwrap(), Some(ServiceStatus::Unknown));
+        assert_eq!(set(ServiceStatus::Up, None).await.unwrap(), Some(ServiceStatus::Up));
+        assert_eq!(set(ServiceStatus::Maintenance, Some("upgrade")).await.unwrap(), Some(ServiceStatus::Up));
+        assert_eq!(set_status(&conn, "Nope".into(), ServiceStatus::Up, "test".into(), None).await.unwrap(), None);
+
+        let timeline = history(&conn, "Fixer".into(), None, None, 10).await.unwrap().unwrap();
+        assert_eq!(timeline.len(), 2);
+        assert_eq!((timeline[0].from, timeline[0].to), (Some(ServiceStatus::Up), ServiceStatus::Maintenance));
+        assert_eq!(timeline[0].reason.as_deref(), Some("upgrade"));
+        assert_eq!((timeline[1].from, timeline[1].to), (Some(ServiceStatus::Unknown), ServiceStatus::Up));
+        assert!(history(&conn, "Nope".into(), None, None, 10).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn defaults_are_only_added_to_an_empty_registry() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        let names = |conn: &Connection| {
+            conn.call(|conn| {
+                let mut stmt = conn.prepare("SELECT name FROM services ORDER BY name")?;
+                let names = stmt.query_map([], |row| row.get(0))?.collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
+                Ok::<_, rusqlite::Error>(names)
+            })
+        };
+
+        assert!(conn.call(seed_defaults).await.unwrap());
+        assert_eq!(names(&conn).await.unwrap(), vec!["Cyberdeck", "Fixer"]);
+        // A rename and a delete survive the next start
+        conn.call(|conn| {
+            conn.execute("UPDATE services SET name = 'Deck' WHERE name = 'Cyberdeck'", [])?;
+            conn.execute("DELETE FROM services WHERE name = 'Fixer'", [])
+        })
+        .await
+        .unwrap();
+        assert!(!conn.call(seed_defaults).await.unwrap());
+        assert_eq!(names(&conn).await.unwrap(), vec!["Deck"]);
+    }
+
+    #[test]
+    fn names_are_validated() {
+        assert!(validate_name("Service", "Fixer").is_ok());
+        assert!(validate_name("Service", "net-watch_2").is_ok());
+        assert!(validate_name("Service", "").is_err());
+        assert!(validate_name("Service", " Fixer").is_err());
+        // dots, wildcards and spaces would change what a NATS subject matches
+        for name in ["fixer.>", "fixer.*", "net watch", "net.watch", "café"] {
+            assert!(validate_name("Service", name).is_err(), "{}", name);
+        }
+        assert!(validate_name("Service", &"a".repeat(MAX_NAME_LENGTH + 1)).is_err());
+        assert!(validate_service_name("graph").is_err());
+        assert!(validate_service_name("graphite").is_ok());
+        assert!(validate_name("Server", "graph").is_ok());
+    }
+}
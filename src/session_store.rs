@@ -0,0 +1,862 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use async_nats::jetstream::{self, kv};
+use async_trait::async_trait;
+use axum_login::axum_sessions::async_session::{self, Session, SessionStore};
+use futures::StreamExt;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use tokio_rusqlite::Connection;
+use tracing::log::info;
+
+use crate::auth::{unix_now, SESSION_IP_KEY, SESSION_USER_AGENT_KEY, SESSION_USER_ID_KEY};
+
+/// Which backend keeps the sessions. Picked with the SESSION_STORE environmental variable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StoreKind {
+    /// Sessions are lost on restart. Good for tests and throw away deployments.
+    Memory,
+    /// The sessions table in the main db
+    #[default]
+    Sqlite,
+    /// A NATS key value bucket so several instances can share sessions
+    Nats,
+}
+
+impl FromStr for StoreKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "memory" => Ok(Self::Memory),
+            "sqlite" => Ok(Self::Sqlite),
+            "nats" => Ok(Self::Nats),
+            _ => Err(anyhow!("Unknown session store: {}", s)),
+        }
+    }
+}
+
+/// What the session routes need from a store on top of `SessionStore`.
+/// Every store has to pass the conformance tests at the bottom of this file.
+#[async_trait]
+pub trait UserSessions {
+    /// List every session of a user that hasn't expired. `current` marks the caller's own session.
+    async fn list_user_sessions(&self, user_id: i64, current: Option<String>) -> Result<Vec<SessionInfo>>;
+    /// Destroy every session belonging to a user. The session with the id `keep` is left alone
+    /// so a user can stay logged in on the device they are using.
+    async fn destroy_user_sessions(&self, user_id: i64, keep: Option<String>) -> Result<usize>;
+    /// Destroy one session of a user. Returns 0 if the session doesn't exist or belongs to someone else.
+    async fn destroy_user_session(&self, user_id: i64, id: String) -> Result<usize>;
+    /// Delete every expired session. Returns how many were removed.
+    async fn cleanup(&self) -> Result<usize>;
+}
+
+/// Live session of a user as shown when listing sessions
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created_at: Option<i64>,
+    pub last_seen: Option<i64>,
+    pub expires_at: Option<i64>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// true for the session making the request
+    pub current: bool,
+}
+
+/// Expiry settings every store handles the same way
+#[derive(Debug, Clone, Copy, Default)]
+struct Expiry {
+    /// Lifetime of sessions that don't set their own expiry and how far sliding expiry extends
+    ttl: Option<Duration>,
+    /// Extend the expiry of a session every time it is loaded
+    sliding: bool,
+}
+
+impl Expiry {
+    /// Unix timestamp a session expires at. Sessions without an expiry get the store ttl.
+    fn expires_at(self, session: &Session) -> Option<i64> {
+        session
+            .expiry()
+            .map(|expiry| expiry.timestamp())
+            .or_else(|| self.ttl.map(|ttl| unix_now().saturating_add(secs(ttl))))
+    }
+
+    /// Sliding expiry for a session that was just loaded. Only ever extends so long lived
+    /// sessions keep their expiry. Sessions with their own expiry are changed in place.
+    /// Browser sessions have no expiry of their own and only get stored when their data
+    /// changes, so for them this returns the new expiry the store has to save itself.
+    /// That is skipped for a minute after each bump so every request isn't a write.
+    fn slide(self, session: &mut Session, stored_expires_at: Option<i64>) -> Option<i64> {
+        let (true, Some(ttl)) = (self.sliding, self.ttl) else {
+            return None;
+        };
+        let ttl_secs = secs(ttl);
+        match session.expiry() {
+            Some(expiry) => {
+                if expiry.timestamp() - unix_now() < ttl_secs {
+                    session.expire_in(ttl);
+                }
+                None
+            },
+            None => stored_expires_at
+                .filter(|expires_at| expires_at - unix_now() < ttl_secs - 60)
+                .map(|_| unix_now().saturating_add(ttl_secs)),
+        }
+    }
+}
+
+fn secs(duration: Duration) -> i64 {
+    i64::try_from(duration.as_secs()).unwrap_or(i64::MAX)
+}
+
+/// Store session information in Sqlite db using rusqlite
+#[derive(Debug, Clone)]
+pub struct SqliteSessionStore {
+    conn: Connection,
+    expiry: Expiry,
+}
+
+impl SqliteSessionStore {
+    /// Create a new session store from a tokio_rusqilte connection
+    pub fn new(conn: Connection) -> Self {
+        Self { conn, expiry: Expiry::default() }
+    }
+
+    /// Sessions without their own expiry expire `ttl` after they were last stored
+    pub const fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.expiry.ttl = Some(ttl);
+        self
+    }
+
+    /// Push the expiry of a session back to at least `ttl` from now every time it is used
+    pub const fn with_sliding_expiry(mut self, sliding: bool) -> Self {
+        self.expiry.sliding = sliding;
+        self
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore{
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        // Get the session id
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        info!("loading session id: {}", id);
+        // Get session from database
+        let session = self.conn
+            .call(move |conn| {
+                // Sql query
+                let mut stmt = conn.prepare("SELECT session, expires_at FROM sessions WHERE id = :id AND (expires_at IS NULL OR expires_at > unixepoch())")?;
+                // submit the query and get all the sessions
+                let sessions = stmt
+                    .query_map(&[(":id", &id)], |row| {
+                        // sessions are stored as message pack binaries so we get the bin type
+                        let data: Vec<u8> = row.get(0)?;
+                        // use serde to convert the binary back to a valid Session
+                        Ok((rmp_serde::from_slice(&data).unwrap(), row.get(1)?))
+                    })?
+                    .collect::<std::result::Result<Vec<(Session, Option<i64>)>, rusqlite::Error>>()?;
+                Ok::<_, rusqlite::Error>(sessions)
+            })
+            .await?;
+
+        if session.len() != 0 {
+            // If more than 0 then return the session (Should only be 1 valid)
+            let (mut session, row_expires_at) = session[0].clone();
+            // The session might carry its own expiry that passed before the row did
+            if session.is_expired() {
+                tracing::debug!("Session expired: {}", session.id());
+                self.destroy_session(session).await?;
+                return Ok(None);
+            }
+            if let Some(expires_at) = self.expiry.slide(&mut session, row_expires_at) {
+                let id = session.id().to_string();
+                self.conn
+                    .call(move |conn| conn.execute("UPDATE sessions SET expires_at = ?1, last_seen = unixepoch() WHERE id = ?2", params![expires_at, id]))
+                    .await?;
+            }
+            tracing::debug!("Session loaded: {:?}", session);
+            Ok(Some(session))
+        } else {
+            // If 0 then we have no valid sessions
+            Ok(None)
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        info!("storing session by id `{}`", session.id());
+        let copy = session.clone();
+        // the user id is only set once the session is logged in
+        let user_id = session.get::<i64>(SESSION_USER_ID_KEY);
+        let expires_at = self.expiry.expires_at(&session);
+        // set by the `track_session` middleware
+        let ip = session.get::<String>(SESSION_IP_KEY);
+        let user_agent = session.get::<String>(SESSION_USER_AGENT_KEY);
+        // insert session into database
+        self.conn
+            .call(move |conn| {
+                // sessions table takes id as string and session as a BLOB
+                // created_at is only set the first time the session is stored
+                conn.execute(
+                    "INSERT INTO sessions(id, session, user_id, expires_at, ip, user_agent, created_at, last_seen)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, unixepoch(), unixepoch())
+                     ON CONFLICT(id) DO UPDATE SET session=excluded.session, user_id=excluded.user_id, expires_at=excluded.expires_at,
+                        ip=excluded.ip, user_agent=excluded.user_agent, last_seen=excluded.last_seen",
+                    params![copy.id(), rmp_serde::to_vec(&copy).unwrap(), user_id, expires_at, ip, user_agent],
+                )
+            })
+            .await
+            .map_err(|err| {
+                tracing::debug!("Session insert err: {:?}", err);
+                err
+            })?;
+
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        info!("destroying session by id `{}`", session.id());
+        // delete session with associated id
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "DELETE FROM sessions WHERE id = ?1",
+                    params![session.id()],
+                )
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        info!("clearing memory store");
+        // clear all sessions
+        self.conn
+            .call(|conn| {
+                // sessions table takes id as string and session as a BLOB
+                conn.execute("DELETE FROM sessions", [])
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserSessions for SqliteSessionStore {
+    async fn list_user_sessions(&self, user_id: i64, current: Option<String>) -> Result<Vec<SessionInfo>> {
+        let sessions = self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, created_at, last_seen, expires_at, ip, user_agent FROM sessions
+                     WHERE user_id = ?1 AND (expires_at IS NULL OR expires_at > unixepoch()) ORDER BY last_seen DESC",
+                )?;
+                let sessions = stmt
+                    .query_map(params![user_id], |row| {
+                        let id: String = row.get(0)?;
+                        Ok(SessionInfo {
+                            current: current.as_ref() == Some(&id),
+                            id,
+                            created_at: row.get(1)?,
+                            last_seen: row.get(2)?,
+                            expires_at: row.get(3)?,
+                            ip: row.get(4)?,
+                            user_agent: row.get(5)?,
+                        })
+                    })?
+                    .collect::<std::result::Result<Vec<SessionInfo>, rusqlite::Error>>()?;
+                Ok::<_, rusqlite::Error>(sessions)
+            })
+            .await?;
+        Ok(sessions)
+    }
+
+    async fn destroy_user_sessions(&self, user_id: i64, keep: Option<String>) -> Result<usize> {
+        info!("destroying sessions for user `{}`", user_id);
+        let count = self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "DELETE FROM sessions WHERE user_id = ?1 AND id IS NOT ?2",
+                    params![user_id, keep],
+                )
+            })
+            .await?;
+        Ok(count)
+    }
+
+    async fn destroy_user_session(&self, user_id: i64, id: String) -> Result<usize> {
+        info!("destroying session `{}` for user `{}`", id, user_id);
+        let count = self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "DELETE FROM sessions WHERE user_id = ?1 AND id = ?2",
+                    params![user_id, id],
+                )
+            })
+            .await?;
+        Ok(count)
+    }
+
+    async fn cleanup(&self) -> Result<usize> {
+        let count = self.conn
+            .call(|conn| conn.execute("DELETE FROM sessions WHERE expires_at <= unixepoch()", []))
+            .await?;
+        Ok(count)
+    }
+}
+
+/// A session along with the details the sqlite store keeps in columns.
+/// Used by the stores that only have a key and a value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredSession {
+    session: Session,
+    user_id: Option<i64>,
+    expires_at: Option<i64>,
+    created_at: i64,
+    last_seen: i64,
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl StoredSession {
+    /// Wrap a session that is about to be stored. `created_at` is kept from the previous version.
+    fn new(session: Session, expiry: Expiry, previous: Option<&Self>) -> Self {
+        let now = unix_now();
+        Self {
+            user_id: session.get::<i64>(SESSION_USER_ID_KEY),
+            expires_at: expiry.expires_at(&session),
+            created_at: previous.map_or(now, |previous| previous.created_at),
+            last_seen: now,
+            ip: session.get::<String>(SESSION_IP_KEY),
+            user_agent: session.get::<String>(SESSION_USER_AGENT_KEY),
+            session,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= unix_now()) || self.session.is_expired()
+    }
+
+    fn info(&self, current: Option<&str>) -> SessionInfo {
+        SessionInfo {
+            id: self.session.id().to_string(),
+            created_at: Some(self.created_at),
+            last_seen: Some(self.last_seen),
+            expires_at: self.expires_at,
+            ip: self.ip.clone(),
+            user_agent: self.user_agent.clone(),
+            current: current == Some(self.session.id()),
+        }
+    }
+}
+
+/// Newest sessions first like the sqlite store
+fn sort_sessions(mut sessions: Vec<SessionInfo>) -> Vec<SessionInfo> {
+    sessions.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+    sessions
+}
+
+/// Keep sessions in memory. Everyone is logged out when the server restarts.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySessionStore {
+    sessions: Arc<RwLock<HashMap<String, StoredSession>>>,
+    expiry: Expiry,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sessions without their own expiry expire `ttl` after they were last stored
+    pub const fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.expiry.ttl = Some(ttl);
+        self
+    }
+
+    /// Push the expiry of a session back to at least `ttl` from now every time it is used
+    pub const fn with_sliding_expiry(mut self, sliding: bool) -> Self {
+        self.expiry.sliding = sliding;
+        self
+    }
+
+    fn sessions(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<String, StoredSession>> {
+        self.sessions.write().expect("session lock poisoned")
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        let mut sessions = self.sessions();
+        let Some(stored) = sessions.get_mut(&id) else {
+            return Ok(None);
+        };
+        if stored.is_expired() {
+            tracing::debug!("Session expired: {}", id);
+            sessions.remove(&id);
+            return Ok(None);
+        }
+        let mut session = stored.session.clone();
+        if let Some(expires_at) = self.expiry.slide(&mut session, stored.expires_at) {
+            stored.expires_at = Some(expires_at);
+            stored.last_seen = unix_now();
+        }
+        Ok(Some(session))
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        let mut sessions = self.sessions();
+        let stored = StoredSession::new(session.clone(), self.expiry, sessions.get(session.id()));
+        sessions.insert(session.id().to_string(), stored);
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        self.sessions().remove(session.id());
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        self.sessions().clear();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserSessions for MemorySessionStore {
+    async fn list_user_sessions(&self, user_id: i64, current: Option<String>) -> Result<Vec<SessionInfo>> {
+        let sessions = self
+            .sessions()
+            .values()
+            .filter(|stored| stored.user_id == Some(user_id) && !stored.is_expired())
+            .map(|stored| stored.info(current.as_deref()))
+            .collect();
+        Ok(sort_sessions(sessions))
+    }
+
+    async fn destroy_user_sessions(&self, user_id: i64, keep: Option<String>) -> Result<usize> {
+        let mut sessions = self.sessions();
+        let before = sessions.len();
+        sessions.retain(|id, stored| stored.user_id != Some(user_id) || keep.as_ref() == Some(id));
+        Ok(before - sessions.len())
+    }
+
+    async fn destroy_user_session(&self, user_id: i64, id: String) -> Result<usize> {
+        let mut sessions = self.sessions();
+        match sessions.get(&id) {
+            Some(stored) if stored.user_id == Some(user_id) => {
+                sessions.remove(&id);
+                Ok(1)
+            },
+            _ => Ok(0),
+        }
+    }
+
+    async fn cleanup(&self) -> Result<usize> {
+        let mut sessions = self.sessions();
+        let before = sessions.len();
+        sessions.retain(|_, stored| !stored.is_expired());
+        Ok(before - sessions.len())
+    }
+}
+
+/// Times a NATS session write is retried when another instance changed the session in between
+const NATS_WRITE_ATTEMPTS: usize = 5;
+
+/// What a NATS bucket holds for a session id
+enum NatsEntry {
+    Missing,
+    /// Deleted keys stay as a marker until the bucket's max age passes
+    Deleted,
+    Stored(StoredSession, u64),
+}
+
+/// Keep sessions in a NATS JetStream key value bucket so several Cyberdeck
+/// instances behind a load balancer can share them. Listing the sessions of a
+/// user reads every key so this is meant for a modest number of sessions.
+#[derive(Debug, Clone)]
+pub struct NatsSessionStore {
+    kv: kv::Store,
+    expiry: Expiry,
+}
+
+impl NatsSessionStore {
+    /// Open the bucket or create it if it doesn't exist yet. NATS drops keys that haven't
+    /// been written for `max_age`, which should be at least the longest session lifetime.
+    /// An existing bucket that keeps keys for less than that is refused.
+    pub async fn connect(client: async_nats::Client, bucket: &str, max_age: Duration) -> Result<Self> {
+        let jetstream = jetstream::new(client);
+        // A bucket is the stream KV_<bucket>. Looking it up by name keeps errors like a lost
+        // connection from being taken for a missing bucket.
+        let stream_name = format!("KV_{}", bucket);
+        let mut exists = false;
+        let mut names = jetstream.stream_names();
+        while let Some(name) = names.next().await {
+            if name? == stream_name {
+                exists = true;
+                break;
+            }
+        }
+        let kv = if exists {
+            let kept = jetstream.get_stream(&stream_name).await?.info().await?.config.max_age;
+            // zero keeps keys forever
+            if !kept.is_zero() && kept < max_age {
+                bail!(
+                    "NATS bucket {} keeps sessions for {:?} but they can last {:?}. Raise its max age or use another bucket.",
+                    bucket,
+                    kept,
+                    max_age
+                );
+            }
+            jetstream.get_key_value(bucket).await?
+        } else {
+            jetstream
+                .create_key_value(kv::Config {
+                    bucket: bucket.to_string(),
+                    history: 1,
+                    max_age,
+                    ..Default::default()
+                })
+                .await?
+        };
+        Ok(Self { kv, expiry: Expiry::default() })
+    }
+
+    /// Sessions without their own expiry expire `ttl` after they were last stored
+    pub const fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.expiry.ttl = Some(ttl);
+        self
+    }
+
+    /// Push the expiry of a session back to at least `ttl` from now every time it is used
+    pub const fn with_sliding_expiry(mut self, sliding: bool) -> Self {
+        self.expiry.sliding = sliding;
+        self
+    }
+
+    /// Session ids are base64 which has characters NATS doesn't allow in keys
+    fn key(id: &str) -> String {
+        hex::encode(id)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<StoredSession>> {
+        match self.kv.get(Self::key(id)).await? {
+            Some(value) => Ok(Some(rmp_serde::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The session under `id` with the revision of its key, which is what `update` compares against
+    async fn entry(&self, id: &str) -> Result<NatsEntry> {
+        match self.kv.entry(Self::key(id)).await? {
+            Some(entry) if entry.operation == kv::Operation::Put => {
+                Ok(NatsEntry::Stored(rmp_serde::from_slice(&entry.value)?, entry.revision))
+            },
+            Some(_) => Ok(NatsEntry::Deleted),
+            None => Ok(NatsEntry::Missing),
+        }
+    }
+
+    /// Write a session if its key is still at `revision`, 0 being a key that was never written.
+    /// Returns false when another request or instance changed the session since it was read.
+    async fn update(&self, stored: &StoredSession, revision: u64) -> Result<bool> {
+        let key = Self::key(stored.session.id());
+        match self.kv.update(&key, rmp_serde::to_vec(stored)?.into(), revision).await {
+            Ok(_) => Ok(true),
+            Err(err) => match self.kv.entry(&key).await? {
+                Some(entry) if entry.revision != revision => Ok(false),
+                _ => Err(err.into()),
+            },
+        }
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        self.kv.delete(Self::key(id)).await?;
+        Ok(())
+    }
+
+    /// Every session in the bucket
+    async fn all(&self) -> Result<Vec<StoredSession>> {
+        let mut keys = self.kv.keys().await?;
+        let mut sessions = Vec::new();
+        while let Some(key) = keys.next().await {
+            let key = key?;
+            // keys can be deleted while we go through them
+            if let Some(value) = self.kv.get(&key).await? {
+                sessions.push(rmp_serde::from_slice(&value)?);
+            }
+        }
+        Ok(sessions)
+    }
+}
+
+#[async_trait]
+impl SessionStore for NatsSessionStore {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        for _ in 0..NATS_WRITE_ATTEMPTS {
+            let NatsEntry::Stored(mut stored, revision) = self.entry(&id).await? else {
+                return Ok(None);
+            };
+            if stored.is_expired() {
+                tracing::debug!("Session expired: {}", id);
+                self.delete(&id).await?;
+                return Ok(None);
+            }
+            let mut session = stored.session.clone();
+            let Some(expires_at) = self.expiry.slide(&mut session, stored.expires_at) else {
+                return Ok(Some(session));
+            };
+            stored.expires_at = Some(expires_at);
+            stored.last_seen = unix_now();
+            if self.update(&stored, revision).await? {
+                return Ok(Some(session));
+            }
+            // Changed while we slid it, load it again so the change isn't overwritten
+        }
+        // Sliding is only a bump so the session is still good if it kept changing
+        match self.get(&id).await? {
+            Some(stored) if !stored.is_expired() => Ok(Some(stored.session)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        for _ in 0..NATS_WRITE_ATTEMPTS {
+            let (previous, revision) = match self.entry(session.id()).await? {
+                NatsEntry::Stored(previous, revision) => (Some(previous), revision),
+                NatsEntry::Missing => (None, 0),
+                // Destroyed while the request ran, storing it would bring a revoked session back
+                NatsEntry::Deleted => return Ok(None),
+            };
+            let stored = StoredSession::new(session.clone(), self.expiry, previous.as_ref());
+            if self.update(&stored, revision).await? {
+                return Ok(session.into_cookie_value());
+            }
+        }
+        bail!("Session {} kept changing while it was stored", session.id())
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        self.delete(session.id()).await
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        for stored in self.all().await? {
+            self.delete(stored.session.id()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserSessions for NatsSessionStore {
+    async fn list_user_sessions(&self, user_id: i64, current: Option<String>) -> Result<Vec<SessionInfo>> {
+        let sessions = self
+            .all()
+            .await?
+            .iter()
+            .filter(|stored| stored.user_id == Some(user_id) && !stored.is_expired())
+            .map(|stored| stored.info(current.as_deref()))
+            .collect();
+        Ok(sort_sessions(sessions))
+    }
+
+    async fn destroy_user_sessions(&self, user_id: i64, keep: Option<String>) -> Result<usize> {
+        let mut count = 0;
+        for stored in self.all().await? {
+            if stored.user_id == Some(user_id) && keep.as_deref() != Some(stored.session.id()) {
+                self.delete(stored.session.id()).await?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    async fn destroy_user_session(&self, user_id: i64, id: String) -> Result<usize> {
+        match self.get(&id).await? {
+            Some(stored) if stored.user_id == Some(user_id) => {
+                self.delete(&id).await?;
+                Ok(1)
+            },
+            _ => Ok(0),
+        }
+    }
+
+    async fn cleanup(&self) -> Result<usize> {
+        let mut count = 0;
+        for stored in self.all().await? {
+            if stored.is_expired() {
+                self.delete(stored.session.id()).await?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// The session store picked in the config. Passed to the `SessionLayer` and
+/// to the routes that list and revoke sessions.
+#[derive(Debug, Clone)]
+pub enum AnySessionStore {
+    Memory(MemorySessionStore),
+    Sqlite(SqliteSessionStore),
+    Nats(NatsSessionStore),
+}
+
+#[async_trait]
+impl SessionStore for AnySessionStore {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        match self {
+            Self::Memory(store) => store.load_session(cookie_value).await,
+            Self::Sqlite(store) => store.load_session(cookie_value).await,
+            Self::Nats(store) => store.load_session(cookie_value).await,
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        match self {
+            Self::Memory(store) => store.store_session(session).await,
+            Self::Sqlite(store) => store.store_session(session).await,
+            Self::Nats(store) => store.store_session(session).await,
+        }
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        match self {
+            Self::Memory(store) => store.destroy_session(session).await,
+            Self::Sqlite(store) => store.destroy_session(session).await,
+            Self::Nats(store) => store.destroy_session(session).await,
+        }
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        match self {
+            Self::Memory(store) => store.clear_store().await,
+            Self::Sqlite(store) => store.clear_store().await,
+            Self::Nats(store) => store.clear_store().await,
+        }
+    }
+}
+
+#[async_trait]
+impl UserSessions for AnySessionStore {
+    async fn list_user_sessions(&self, user_id: i64, current: Option<String>) -> Result<Vec<SessionInfo>> {
+        match self {
+            Self::Memory(store) => store.list_user_sessions(user_id, current).await,
+            Self::Sqlite(store) => store.list_user_sessions(user_id, current).await,
+            Self::Nats(store) => store.list_user_sessions(user_id, current).await,
+        }
+    }
+
+    async fn destroy_user_sessions(&self, user_id: i64, keep: Option<String>) -> Result<usize> {
+        match self {
+            Self::Memory(store) => store.destroy_user_sessions(user_id, keep).await,
+            Self::Sqlite(store) => store.destroy_user_sessions(user_id, keep).await,
+            Self::Nats(store) => store.destroy_user_sessions(user_id, keep).await,
+        }
+    }
+
+    async fn destroy_user_session(&self, user_id: i64, id: String) -> Result<usize> {
+        match self {
+            Self::Memory(store) => store.destroy_user_session(user_id, id).await,
+            Self::Sqlite(store) => store.destroy_user_session(user_id, id).await,
+            Self::Nats(store) => store.destroy_user_session(user_id, id).await,
+        }
+    }
+
+    async fn cleanup(&self) -> Result<usize> {
+        match self {
+            Self::Memory(store) => store.cleanup().await,
+            Self::Sqlite(store) => store.cleanup().await,
+            Self::Nats(store) => store.cleanup().await,
+        }
+    }
+}
+
+// Conformance tests every store has to pass
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::MIGRATIONS;
+
+    /// Store a new session for a user and return it with its cookie value
+    async fn new_session<S: SessionStore>(store: &S, user_id: i64) -> (Session, String) {
+        let mut session = Session::new();
+        session.insert(SESSION_USER_ID_KEY, user_id).unwrap();
+        session.insert(SESSION_IP_KEY, "127.0.0.1").unwrap();
+        let cookie = store.store_session(session.clone()).await.unwrap().unwrap();
+        (session, cookie)
+    }
+
+    async fn conformance<S: SessionStore + UserSessions>(store: S) {
+        // sessions load back with their data
+        let (current, current_cookie) = new_session(&store, 7).await;
+        let loaded = store.load_session(current_cookie.clone()).await.unwrap().expect("session should load");
+        assert_eq!(loaded.id(), current.id());
+        assert_eq!(loaded.get::<i64>(SESSION_USER_ID_KEY), Some(7));
+
+        // sessions are listed per user with the current one marked
+        let (other, other_cookie) = new_session(&store, 7).await;
+        new_session(&store, 8).await;
+        let sessions = store.list_user_sessions(7, Some(current.id().to_string())).await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        let listed = sessions.iter().find(|info| info.current).expect("current session should be marked");
+        assert_eq!(listed.id, current.id());
+        assert_eq!(listed.ip.as_deref(), Some("127.0.0.1"));
+
+        // a session can only be revoked by its owner
+        assert_eq!(store.destroy_user_session(8, other.id().to_string()).await.unwrap(), 0);
+        assert_eq!(store.destroy_user_session(7, other.id().to_string()).await.unwrap(), 1);
+        assert!(store.load_session(other_cookie).await.unwrap().is_none());
+
+        // revoking every session keeps the current one and leaves other users alone
+        new_session(&store, 7).await;
+        assert_eq!(store.destroy_user_sessions(7, Some(current.id().to_string())).await.unwrap(), 1);
+        assert!(store.load_session(current_cookie.clone()).await.unwrap().is_some());
+        assert_eq!(store.list_user_sessions(8, None).await.unwrap().len(), 1);
+
+        // expired sessions don't load, aren't listed and get cleaned up
+        let mut expired = Session::new();
+        expired.insert(SESSION_USER_ID_KEY, 9).unwrap();
+        expired.expire_in(Duration::ZERO);
+        store.store_session(expired).await.unwrap();
+        assert!(store.list_user_sessions(9, None).await.unwrap().is_empty());
+        assert_eq!(store.cleanup().await.unwrap(), 1);
+
+        // destroyed sessions are gone
+        store.destroy_session(loaded).await.unwrap();
+        assert!(store.load_session(current_cookie).await.unwrap().is_none());
+        store.clear_store().await.unwrap();
+        assert!(store.list_user_sessions(8, None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_store_conformance() {
+        conformance(MemorySessionStore::new().with_ttl(Duration::from_secs(60)).with_sliding_expiry(true)).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_conformance() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        conformance(SqliteSessionStore::new(conn).with_ttl(Duration::from_secs(60)).with_sliding_expiry(true)).await;
+    }
+
+    // Needs a NATS server with JetStream on localhost:4222 (`nats-server -js`)
+    #[tokio::test]
+    #[ignore]
+    async fn nats_store_conformance() {
+        let client = async_nats::connect("localhost:4222").await.unwrap();
+        let store = NatsSessionStore::connect(client, "cyberdeck_sessions_test", Duration::from_secs(60)).await.unwrap();
+        store.clear_store().await.unwrap();
+        conformance(store.with_ttl(Duration::from_secs(60)).with_sliding_expiry(true)).await;
+    }
+}
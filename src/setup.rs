@@ -0,0 +1,123 @@
+use std::sync::Mutex;
+
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::params;
+use tokio_rusqlite::Connection;
+
+use crate::{
+    auth::constant_time_eq,
+    user::{Role, User},
+};
+
+/// First run bootstrap. While there are no users the server prints a one time code
+/// and whoever has it can create the first admin through `POST /setup`.
+#[derive(Debug, Default)]
+pub struct Setup {
+    /// `None` once setup is done or when it was never needed
+    code: Mutex<Option<String>>,
+}
+
+impl Setup {
+    /// Check the db and make a setup code if there are no users yet
+    pub async fn init(conn: &Connection) -> Result<Self, rusqlite::Error> {
+        let users: i64 = conn
+            .call(|conn| conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0)))
+            .await?;
+        if users > 0 {
+            return Ok(Self::default());
+        }
+        let code = generate_code();
+        tracing::warn!("No users exist yet. Create the first admin at /setup with the setup code: {}", code);
+        Ok(Self { code: Mutex::new(Some(code)) })
+    }
+
+    /// True until the first admin has been created
+    pub fn required(&self) -> bool {
+        self.code.lock().expect("setup lock poisoned").is_some()
+    }
+
+    /// Check a setup code
+    pub fn verify(&self, code: &str) -> bool {
+        self.code
+            .lock()
+            .expect("setup lock poisoned")
+            .as_deref()
+            .map_or(false, |expected| constant_time_eq(expected.as_bytes(), code.trim().to_lowercase().as_bytes()))
+    }
+
+    /// Throw the code away so setup can't run again
+    pub fn finish(&self) {
+        self.code.lock().expect("setup lock poisoned").take();
+    }
+
+    /// The code that was printed at startup
+    #[cfg(test)]
+    pub fn code(&self) -> Option<String> {
+        self.code.lock().expect("setup lock poisoned").clone()
+    }
+}
+
+/// Random code in the form `xxxx-xxxx-xxxx` that is easy to copy from the log
+fn generate_code() -> String {
+    let code: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect();
+    format!("{}-{}-{}", &code[..4], &code[4..8], &code[8..])
+}
+
+/// Create the first admin. Only inserts while the users table is still empty so existing
+/// accounts are never touched, even if two setups race. Returns the new id if it was created.
+pub async fn create_admin(conn: &Connection, user: User) -> Result<Option<i64>, rusqlite::Error> {
+    conn.call(move |conn| {
+        let created = conn.execute(
+            "INSERT INTO users (name, hash, role) SELECT ?1, ?2, ?3 WHERE NOT EXISTS (SELECT 1 FROM users)",
+            params![user.name, user.hash, Role::Admin],
+        )?;
+        Ok::<_, rusqlite::Error>((created == 1).then(|| conn.last_insert_rowid()))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::MIGRATIONS;
+
+    #[tokio::test]
+    async fn code_works_until_setup_finishes() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        let setup = Setup::init(&conn).await.unwrap();
+        let code = setup.code().unwrap();
+
+        assert!(setup.required());
+        assert!(!setup.verify("aaaa-bbbb-cccc"));
+        assert!(!setup.verify(""));
+        // Pasted codes often come with spaces or in upper case
+        assert!(setup.verify(&format!(" {} ", code.to_uppercase())));
+
+        setup.finish();
+        assert!(!setup.required());
+        assert!(!setup.verify(&code));
+    }
+
+    #[tokio::test]
+    async fn no_setup_once_there_are_users() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        let first = User::new("root", "long enough password", 0, Role::Admin).unwrap();
+        assert_eq!(create_admin(&conn, first).await.unwrap(), Some(1));
+
+        // A second admin is never created and existing accounts stay as they are
+        let second = User::new("intruder", "long enough password", 0, Role::Admin).unwrap();
+        assert_eq!(create_admin(&conn, second).await.unwrap(), None);
+        let names = conn
+            .call(|conn| conn.query_row("SELECT group_concat(name) FROM users", [], |row| row.get::<_, String>(0)))
+            .await
+            .unwrap();
+        assert_eq!(names, "root");
+        assert!(!Setup::init(&conn).await.unwrap().required());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::user::Role;
+
+/// Every token starts with this so they are easy to recognize in logs and config files
+const TOKEN_PREFIX: &str = "cdk_";
+/// Random characters after the prefix
+pub const TOKEN_LENGTH: usize = 40;
+/// Characters of the token kept in plain text so users can tell their tokens apart
+const DISPLAY_LENGTH: usize = 12;
+/// Shown instead of the prefix of tokens too short to give part of them away
+const SHORT_TOKEN_LABEL: &str = "short token";
+/// Name of the token added from the `API_TOKEN` environmental variable
+pub const ENV_TOKEN_NAME: &str = "API_TOKEN";
+
+/// What an API token is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "services:read")]
+    ServicesRead,
+    #[serde(rename = "services:write")]
+    ServicesWrite,
+    #[serde(rename = "jobs:read")]
+    JobsRead,
+    #[serde(rename = "jobs:write")]
+    JobsWrite,
+}
+
+impl Scope {
+    pub const ALL: [Self; 4] = [Self::ServicesRead, Self::ServicesWrite, Self::JobsRead, Self::JobsWrite];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::ServicesRead => "services:read",
+            Self::ServicesWrite => "services:write",
+            Self::JobsRead => "jobs:read",
+            Self::JobsWrite => "jobs:write",
+        }
+    }
+
+    /// The role a token owner needs to hold this scope.
+    /// A token can never do more than its owner could do with a session.
+    pub const fn min_role(self) -> Role {
+        match self {
+            Self::ServicesRead | Self::JobsRead => Role::Viewer,
+            Self::ServicesWrite | Self::JobsWrite => Role::Operator,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match Self::ALL.into_iter().find(|scope| scope.as_str() == s) {
+            Some(scope) => Ok(scope),
+            None => bail!("Unknown scope: {}", s),
+        }
+    }
+}
+
+/// Scopes are stored in the db as a space separated list
+pub fn scopes_to_string(scopes: &[Scope]) -> String {
+    scopes.iter().map(|scope| scope.as_str()).collect::<Vec<_>>().join(" ")
+}
+
+/// Parse scopes stored by `scopes_to_string`. Unknown scopes are dropped.
+pub fn scopes_from_string(scopes: &str) -> Vec<Scope> {
+    scopes.split_whitespace().filter_map(|scope| scope.parse().ok()).collect()
+}
+
+/// API token as stored in the db. Only the hash of the token is kept.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    /// first few characters of the token
+    pub prefix: String,
+    pub user_id: i64,
+    pub scopes: Vec<Scope>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+}
+
+impl ApiToken {
+    /// Map a row from `SELECT * FROM api_tokens`
+    pub fn map(row: &rusqlite::Row<'_>) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            prefix: row.get("prefix")?,
+            user_id: row.get("user_id")?,
+            scopes: scopes_from_string(&row.get::<_, String>("scopes")?),
+            created_at: row.get("created_at")?,
+            expires_at: row.get("expires_at")?,
+            last_used_at: row.get("last_used_at")?,
+        })
+    }
+}
+
+/// Generate a new random token. Returns the raw token, its display prefix and its hash.
+pub fn generate() -> (String, String, String) {
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+    let token = format!("{}{}", TOKEN_PREFIX, secret);
+    let prefix = token[..DISPLAY_LENGTH].to_string();
+    let hash = hash(&token);
+    (token, prefix, hash)
+}
+
+/// Whether a token is at least as long as the ones we generate. Only those are accepted from
+/// `API_TOKEN` and only those have part of them shown.
+pub fn is_long_enough(token: &str) -> bool {
+    token.chars().count() >= TOKEN_LENGTH
+}
+
+/// Display prefix for an existing token. Short tokens get a fixed label since
+/// their first characters could be most of the secret.
+pub fn prefix(token: &str) -> String {
+    if is_long_enough(token) {
+        token.chars().take(DISPLAY_LENGTH).collect()
+    } else {
+        SHORT_TOKEN_LABEL.to_string()
+    }
+}
+
+/// Hash a token for storage and lookup. Tokens are long and random so SHA256 is enough.
+pub fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Add the token from `API_TOKEN` for the first admin with every scope. It is marked as coming from
+/// the environment and one added from an earlier value is removed, so changing the variable revokes the old token.
+/// Returns false if there is no admin to own it yet.
+pub fn seed_env_token(conn: &mut rusqlite::Connection, token: &str) -> Result<bool, rusqlite::Error> {
+    let tx = conn.transaction()?;
+    let admin: Option<i64> = tx
+        .query_row("SELECT id FROM users WHERE role = ?1 AND disabled = 0 ORDER BY id LIMIT 1", params![Role::Admin], |row| row.get(0))
+        .optional()?;
+    let Some(admin) = admin else { return Ok(false) };
+    let hash = hash(token);
+    tx.execute("DELETE FROM api_tokens WHERE from_env = 1 AND hash != ?1", params![hash])?;
+    // A token with the same hash is the same secret, so one seeded before the flag existed gets it now
+    tx.execute(
+        "INSERT INTO api_tokens (name, prefix, hash, user_id, scopes, from_env) VALUES (?1, ?2, ?3, ?4, ?5, 1)
+         ON CONFLICT(hash) DO UPDATE SET from_env = 1",
+        params![ENV_TOKEN_NAME, prefix(token), hash, admin, scopes_to_string(&Scope::ALL)],
+    )?;
+    tx.commit()?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::MIGRATIONS;
+    use tokio_rusqlite::Connection;
+
+    #[test]
+    fn short_tokens_are_not_shown() {
+        let (token, prefix_shown, _) = generate();
+        assert_eq!(prefix(&token), prefix_shown);
+        assert_eq!(prefix("easytoken"), SHORT_TOKEN_LABEL);
+        assert!(!is_long_enough("easytoken"));
+    }
+
+    #[tokio::test]
+    async fn changing_env_token_revokes_the_old_one() {
+        let mut conn = Connection::open_in_memory().await.unwrap();
+        MIGRATIONS.to_latest(&mut conn).await.unwrap();
+        let (old, new) = (generate().0, generate().0);
+        let new_hash = hash(&new);
+        let (before_admin, hashes) = conn
+            .call(move |conn| {
+                let before_admin = seed_env_token(conn, &old)?;
+                conn.execute("INSERT INTO users (name, hash, role) VALUES ('admin', 'x', 'admin')", [])?;
+                // Someone's own token with the same name isn't touched
+                conn.execute(
+                    "INSERT INTO api_tokens (name, prefix, hash, user_id, scopes) VALUES (?1, 'cd_', 'mine', 1, '')",
+                    params![ENV_TOKEN_NAME],
+                )?;
+                seed_env_token(conn, &old)?;
+                seed_env_token(conn, &old)?;
+                seed_env_token(conn, &new)?;
+                let mut stmt = conn.prepare("SELECT hash FROM api_tokens ORDER BY id")?;
+                let hashes = stmt
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<std::result::Result<Vec<String>, rusqlite::Error>>()?;
+                Ok::<_, rusqlite::Error>((before_admin, hashes))
+            })
+            .await
+            .unwrap();
+        assert!(!before_admin);
+        assert_eq!(hashes, vec!["mine".to_string(), new_hash]);
+    }
+}
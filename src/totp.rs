@@ -0,0 +1,155 @@
+use anyhow::{anyhow, Result};
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::params;
+use sha2::{Digest, Sha256};
+use tokio_rusqlite::Connection;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::auth::{constant_time_eq, unix_now};
+
+/// Issuer shown in authenticator apps
+const ISSUER: &str = "Cyberdeck";
+/// Number of recovery codes handed out when TOTP is enabled
+const RECOVERY_CODES: usize = 10;
+
+/// Create a TOTP with a new random secret for a user
+pub fn generate(account: &str) -> Result<TOTP> {
+    // 160 bits is the secret length recommended by RFC 4226
+    let secret = rand::thread_rng().gen::<[u8; 20]>().to_vec();
+    build(secret, account)
+}
+
+/// Rebuild the TOTP for a user from the base32 secret stored in the db
+pub fn from_secret(secret: &str, account: &str) -> Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes()?;
+    build(secret, account)
+}
+
+fn build(secret: Vec<u8>, account: &str) -> Result<TOTP> {
+    // Standard authenticator app settings: SHA1, 6 digits, 30 second steps
+    // with one step of skew allowed for clock drift.
+    // `:` separates issuer and account in the provisioning uri so it can't be in the name
+    TOTP::new(Algorithm::SHA1, 6, 1, 30, secret, Some(ISSUER.into()), account.replace(':', "_"))
+        .map_err(|err| anyhow!("Could not create TOTP: {:?}", err))
+}
+
+/// Check a code at the unix time `now`. Returns the time step the code belongs to
+/// so the caller can refuse to accept the same step twice.
+pub fn verify(totp: &TOTP, code: &str, now: u64) -> Option<u64> {
+    let step = now / totp.step;
+    let skew = u64::from(totp.skew);
+    (step.saturating_sub(skew)..=step + skew)
+        .find(|step| constant_time_eq(totp.generate(step * totp.step).as_bytes(), code.trim().as_bytes()))
+}
+
+/// Generate a fresh set of single use recovery codes in the form `xxxxx-xxxxx`
+pub fn recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Hash a recovery code for storage. Codes are random so a plain SHA256 is enough.
+/// Dashes, whitespace and case are ignored so codes are easy to type.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+/// TOTP settings for a user as stored in the db
+#[derive(Debug, Clone)]
+pub struct TotpRecord {
+    /// base32 encoded secret
+    pub secret: String,
+    /// false until the user confirmed enrollment with a valid code
+    pub enabled: bool,
+    /// last time step a code was accepted for
+    pub last_step: Option<i64>,
+}
+
+/// Load the TOTP settings for a user
+pub async fn load(conn: &Connection, user_id: i64) -> Result<Option<TotpRecord>, rusqlite::Error> {
+    conn.call(move |conn| {
+        let mut stmt = conn.prepare("SELECT secret, enabled, last_step FROM totp WHERE user_id = ?1")?;
+        let records = stmt
+            .query_map(params![user_id], |row| {
+                Ok(TotpRecord {
+                    secret: row.get(0)?,
+                    enabled: row.get(1)?,
+                    last_step: row.get(2)?,
+                })
+            })?
+            .collect::<std::result::Result<Vec<TotpRecord>, rusqlite::Error>>()?;
+        Ok::<_, rusqlite::Error>(records.into_iter().next())
+    })
+    .await
+}
+
+/// Check a second factor code for a user with TOTP enabled. Accepts a TOTP code from a time
+/// step that hasn't been used yet or an unused recovery code, which is then marked as used.
+pub async fn check_code(conn: &Connection, user_id: i64, account: &str, code: String) -> Result<bool> {
+    let record = match load(conn, user_id).await? {
+        Some(record) if record.enabled => record,
+        _ => return Ok(false),
+    };
+
+    let totp = from_secret(&record.secret, account)?;
+    if let Some(step) = verify(&totp, &code, u64::try_from(unix_now())?) {
+        let step = i64::try_from(step)?;
+        // Only move forward so a code can't be replayed
+        let updated = conn
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE totp SET last_step = ?1 WHERE user_id = ?2 AND (last_step IS NULL OR last_step < ?1)",
+                    params![step, user_id],
+                )
+            })
+            .await?;
+        return Ok(updated == 1);
+    }
+
+    // Not a TOTP code so try it as a recovery code
+    let hash = hash_recovery_code(&code);
+    let used = conn
+        .call(move |conn| {
+            conn.execute(
+                "UPDATE recovery_codes SET used_at = unixepoch() WHERE user_id = ?1 AND hash = ?2 AND used_at IS NULL",
+                params![user_id, hash],
+            )
+        })
+        .await?;
+    Ok(used == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_returns_matching_step() {
+        let totp = generate("admin").unwrap();
+        let now = 1_700_000_000;
+        let code = totp.generate(now);
+        assert_eq!(verify(&totp, &code, now), Some(now / 30));
+        // the previous step is still accepted because of skew
+        assert_eq!(verify(&totp, &code, now + 30), Some(now / 30));
+        assert_eq!(verify(&totp, &code, now + 90), None);
+    }
+
+    #[test]
+    fn recovery_codes_hash_ignores_format() {
+        let code = &recovery_codes()[0];
+        assert_eq!(hash_recovery_code(code), hash_recovery_code(&code.replace('-', "").to_uppercase()));
+    }
+}
@@ -1,50 +1,82 @@
-use std::fmt;
+use std::{fmt, sync::OnceLock};
 use anyhow::{Result, bail};
-use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+use argon2::{
+    password_hash::{PasswordHash, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, PasswordHasher, Version,
+};
 use axum_login::{AuthUser, RusqliteUserMapper};
 use rand::rngs::OsRng;
 use secrecy::SecretVec;
+use serde::{Deserialize, Serialize};
 
-/// User for the app. Currently just a username and password hash.
-// @Todo In the future we might want to add roles 
-#[derive(Debug, Default, Clone)]
+use crate::db_enum::db_enum;
+
+/// User for the app. A username, password hash and the role used for authorization.
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct User {
     pub id: i64,
+    #[serde(skip_serializing)]
     pub hash: String,
     pub name: String,
+    pub role: Role,
+    /// Disabled users can't log in and existing sessions lose access to every route
+    pub disabled: bool,
+    /// Service accounts are for automation. They can't log in and only act through API tokens.
+    pub service_account: bool,
 }
 
 impl User {
     /// Create a new user from a name and password. The password is provided as a string 
     /// and then hashed for the user struct.
-    pub fn new(name: &str, password: &str, id: i64) -> Result<User> {
-        let salt = SaltString::generate(&mut OsRng);
-
-        // Argon2 with default params (Argon2id v19)
-        let argon2 = Argon2::default();
-
-        // Hash password to PHC string ($argon2id$v=19$...)
-        let argon_hash = argon2.hash_password(password.as_bytes(), &salt);
-        let hash = match argon_hash {
-            Ok(hash) => hash.to_string(),
-            Err(_) => bail!("Could not make hash"),
-        };
-
+    pub fn new(name: &str, password: &str, id: i64, role: Role) -> Result<User> {
         Ok(User {
             id,
             name: name.to_string(),
-            hash
+            hash: hash_password(password)?,
+            role,
+            disabled: false,
+            service_account: false,
         })
     }
 }
 
+/// Argon2 cost parameters for new hashes. Set once at startup by `set_argon2_params`.
+static ARGON2_PARAMS: OnceLock<Params> = OnceLock::new();
+
+/// Set the Argon2 cost parameters used for every new hash. Only the first call has any effect.
+pub fn set_argon2_params(params: Params) {
+    if ARGON2_PARAMS.set(params).is_err() {
+        tracing::warn!("Argon2 parameters were already set");
+    }
+}
+
+/// Argon2 parameters new hashes are made with
+fn argon2_params() -> Params {
+    ARGON2_PARAMS.get().cloned().unwrap_or_default()
+}
+
+/// Hash a password to a PHC string that can be stored in the users table.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    // Argon2id v19 with the configured params
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params());
+
+    // Hash password to PHC string ($argon2id$v=19$...)
+    let argon_hash = argon2.hash_password(password.as_bytes(), &salt);
+    match argon_hash {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(_) => bail!("Could not make hash"),
+    }
+}
+
 impl fmt::Display for User {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "({}, {})", self.id, self.name)
+        write!(f, "({}, {}, {})", self.id, self.name, self.role)
     }
 }
 
-impl AuthUser<i64> for User {
+impl AuthUser<i64, Role> for User {
     fn get_id(&self) -> i64 {
         self.id
     }
@@ -52,6 +84,64 @@ impl AuthUser<i64> for User {
     fn get_password_hash(&self) -> SecretVec<u8> {
         SecretVec::new(self.hash.clone().into())
     }
+
+    fn get_role(&self) -> Option<Role> {
+        // A disabled user has no role so every role protected route rejects them.
+        // Service accounts never get a session but reject them here too just in case.
+        if self.disabled || self.service_account {
+            None
+        } else {
+            Some(self.role)
+        }
+    }
+}
+
+/// Roles a user can have. They are ordered from least to most privileged so routes
+/// can require a minimum role with a range, e.g. `login_with_role(Role::Operator..)`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can only read data like the service list
+    #[default]
+    Viewer,
+    /// Can run and manage jobs
+    Operator,
+    /// Full access including user and service management
+    Admin,
+}
+
+db_enum!(Role, "role", Viewer => "viewer", Operator => "operator", Admin => "admin");
+
+/// Check a password against a stored PHC hash string.
+/// The parameters are read from the hash so older hashes still verify.
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    // This is from the argon2 docs
+    PasswordHash::new(hash)
+        .map(|parsed_hash| Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok())
+        .unwrap_or(false)
+}
+
+/// Check if a stored hash is weaker than the current policy and should be replaced
+pub fn needs_rehash(hash: &str) -> bool {
+    needs_rehash_with(hash, &argon2_params())
+}
+
+fn needs_rehash_with(hash: &str, policy: &Params) -> bool {
+    let Ok(parsed_hash) = PasswordHash::new(hash) else {
+        return true;
+    };
+    // Anything other than Argon2id v19 gets upgraded
+    if parsed_hash.algorithm != Algorithm::Argon2id.ident() || parsed_hash.version != Some(Version::V0x13.into()) {
+        return true;
+    }
+    match Params::try_from(&parsed_hash) {
+        Ok(params) => {
+            params.m_cost() < policy.m_cost()
+                || params.t_cost() < policy.t_cost()
+                || params.p_cost() < policy.p_cost()
+        },
+        Err(_) => true,
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -60,14 +150,45 @@ pub struct UserMapper;
 impl RusqliteUserMapper for UserMapper {
     type User = User;
 
+    /// Columns are read by name so the mapper doesn't depend on their order in the table,
+    /// which changes as migrations add columns
     fn map(row: &rusqlite::Row<'_>) -> Result<Self::User, rusqlite::Error> {
         Ok(User {
-            id: row.get(0)?,
-            name: row.get(1)?,
-            hash: row.get(2)?,
-            
+            id: row.get("id")?,
+            name: row.get("name")?,
+            hash: row.get("hash")?,
+            role: row.get("role")?,
+            disabled: row.get("disabled")?,
+            service_account: row.get("service_account")?,
         })
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn users_are_mapped_by_column_name() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let user = conn
+            .query_row(
+                "SELECT 1 AS service_account, 0 AS disabled, 'admin' AS role, 'x' AS hash, 'alice' AS name, 7 AS id",
+                [],
+                UserMapper::map,
+            )
+            .unwrap();
+        assert_eq!((user.id, user.name.as_str(), user.role), (7, "alice", Role::Admin));
+        assert!(user.service_account && !user.disabled);
+    }
+
+    #[test]
+    fn rehash_only_weaker_hashes() {
+        let hash = hash_password("password").unwrap();
+        assert!(!needs_rehash_with(&hash, &Params::default()));
+        let stronger = Params::new(Params::DEFAULT_M_COST * 2, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST, None).unwrap();
+        assert!(needs_rehash_with(&hash, &stronger));
+        let weaker = Params::new(Params::DEFAULT_M_COST / 2, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST, None).unwrap();
+        assert!(!needs_rehash_with(&hash, &weaker));
+    }
+}
@@ -1,4 +1,5 @@
 import {user} from './store.js';
+import {sendJson} from './fetch.js';
 
 export async function checkCookie() {
     const res = await fetch("/secure/check", {});
@@ -10,16 +11,12 @@ export async function checkCookie() {
     }
 }
 
-export async function postLogin(username, password) {
-    const res = await fetch("/auth/login", {
-        method: "POST",
-        headers: {
-            Accept: "application/json",
-            "Content-Type": "application/json",
-        },
-        body: JSON.stringify({ username: username, password: password }),
-    });
-    return await res.json();
+export async function postLogin(username, password, remember) {
+    return await sendJson("/auth/login", "POST", { username: username, password: password, remember: remember });
+}
+
+export async function postLoginTotp(code) {
+    return await sendJson("/auth/login/totp", "POST", { code: code });
 }
 
 export async function getLogout(username, password) {
@@ -31,4 +28,18 @@ export async function getLogout(username, password) {
     }else {
         user.set(null);
     }
-}
\ No newline at end of file
+}
+
+export async function postRedeemInvite(code, username, password) {
+    return await sendJson("/auth/invite/redeem", "POST", { code: code, username: username, password: password });
+}
+
+export async function getSetupRequired() {
+    const res = await fetch("/setup");
+    let setupResponse = await res.json();
+    return setupResponse.required;
+}
+
+export async function postSetup(code, username, password) {
+    return await sendJson("/setup", "POST", { code: code, username: username, password: password });
+}
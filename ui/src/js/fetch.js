@@ -1,3 +1,32 @@
+// CSRF token for the current session. Every request that changes something has to send it.
+let csrfToken = null;
+
+async function getCsrfToken() {
+    let res = await fetch('/auth/csrf');
+    let csrfResponse = await res.json();
+    csrfToken = csrfResponse.token;
+    return csrfToken;
+}
+
+// fetch for cookie authenticated requests that aren't GET.
+// Adds the CSRF token and gets a new one once if the session changed since it was fetched.
+export async function sendJson(url, method, body) {
+    const send = async (token) => fetch(url, {
+        method: method,
+        headers: {
+            Accept: "application/json",
+            "Content-Type": "application/json",
+            "X-CSRF-Token": token,
+        },
+        body: body === undefined ? undefined : JSON.stringify(body),
+    });
+    let res = await send(csrfToken ?? await getCsrfToken());
+    if (res.status == 403) {
+        res = await send(await getCsrfToken());
+    }
+    return await res.json();
+}
+
 export async function getSecure() {
     let res = await fetch('/secure');
     let secureResponse = await res.json();
@@ -14,6 +43,11 @@ export async function getServiceGroups() {
     return await res.json();
 }
 
+export async function getServiceGraph() {
+    let res = await fetch('/services/graph');
+    return await res.json();
+}
+
 export async function getApi(api_token) {
     let res = await fetch('/api', {
         headers: {
@@ -0,0 +1,83 @@
+import {sendJson} from './fetch.js';
+
+// The server sends binary fields as base64url strings but the browser api wants ArrayBuffers
+function toBuffer(base64url) {
+    const base64 = base64url.replace(/-/g, "+").replace(/_/g, "/");
+    const padded = base64 + "=".repeat((4 - base64.length % 4) % 4);
+    return Uint8Array.from(atob(padded), c => c.charCodeAt(0)).buffer;
+}
+
+function toBase64url(buffer) {
+    const base64 = btoa(String.fromCharCode(...new Uint8Array(buffer)));
+    return base64.replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+// Encode the answer to a passkey challenge the way the server expects it
+function assertionJson(credential) {
+    return {
+        id: credential.id,
+        rawId: toBase64url(credential.rawId),
+        type: credential.type,
+        extensions: credential.getClientExtensionResults(),
+        response: {
+            authenticatorData: toBase64url(credential.response.authenticatorData),
+            clientDataJSON: toBase64url(credential.response.clientDataJSON),
+            signature: toBase64url(credential.response.signature),
+            userHandle: credential.response.userHandle ? toBase64url(credential.response.userHandle) : null,
+        },
+    };
+}
+
+// Answer a challenge from the server with one of the user's passkeys
+async function answerChallenge(options) {
+    options = options.publicKey;
+    options.challenge = toBuffer(options.challenge);
+    options.allowCredentials = (options.allowCredentials ?? []).map(c => ({...c, id: toBuffer(c.id)}));
+    return assertionJson(await navigator.credentials.get({ publicKey: options }));
+}
+
+// Adding a passkey needs the current password. Without one an existing passkey confirms it is the user.
+export async function registerPasskey(name, password) {
+    let confirm = { password: password };
+    if (!password) {
+        let confirmStart = await sendJson("/auth/passkey/confirm/start", "POST");
+        if (confirmStart.result == "error") {
+            return confirmStart;
+        }
+        confirm = { credential: await answerChallenge(confirmStart.options) };
+    }
+    let start = await sendJson("/auth/passkey/register/start", "POST", confirm);
+    if (start.result == "error") {
+        return start;
+    }
+    let options = start.options.publicKey;
+    options.challenge = toBuffer(options.challenge);
+    options.user.id = toBuffer(options.user.id);
+    options.excludeCredentials = (options.excludeCredentials ?? []).map(c => ({...c, id: toBuffer(c.id)}));
+
+    const credential = await navigator.credentials.create({ publicKey: options });
+    return await sendJson("/auth/passkey/register/finish", "POST", {
+        name: name,
+        credential: {
+            id: credential.id,
+            rawId: toBase64url(credential.rawId),
+            type: credential.type,
+            extensions: credential.getClientExtensionResults(),
+            response: {
+                attestationObject: toBase64url(credential.response.attestationObject),
+                clientDataJSON: toBase64url(credential.response.clientDataJSON),
+            },
+        },
+    });
+}
+
+export async function loginPasskey(username, remember) {
+    let start = await sendJson("/auth/passkey/login/start", "POST", { username: username });
+    if (start.result == "error") {
+        return start;
+    }
+    return await sendJson("/auth/passkey/login/finish", "POST", {
+        remember: remember,
+        credential: await answerChallenge(start.options),
+    });
+}